@@ -45,7 +45,7 @@ impl ReadingBook {
 
     pub fn read_content(&mut self, name: &str) -> String {
         self.resources.clear();
-        let path = self.book.as_mut().unwrap().resolve_path(name);
+        let path = self.book.as_mut().unwrap().resolve_path(name).unwrap();
         let content = self.book.as_mut().unwrap().content(&path).unwrap();
         self.preprocess_content(content, &path)
     }
@@ -53,7 +53,8 @@ impl ReadingBook {
     pub fn create_resources(&mut self) {}
 
     pub fn preprocess_content(&mut self, content: String, base: &str) -> String {
-        let doc = XMLDocument::try_new(content.as_bytes()).unwrap();
+        // already decoded by `Book::content`
+        let doc = XMLDocument::new_from_str(&content);
         let res = relative_resources(&doc, base).unwrap();
         for url in res {
             let image_path = url;
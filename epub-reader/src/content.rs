@@ -1,53 +1,30 @@
 use std::str::FromStr;
 
-use anyhow::{anyhow, Result};
+use anyhow::Result;
+use epub::href;
 use epub::xml::{self, XMLDocument};
+use log::warn;
 use markup5ever_rcdom::NodeData;
-use url::Url;
 use xml5ever::tendril::StrTendril;
 
 pub fn relative_resources(doc: &XMLDocument, path: &str) -> Result<Vec<String>> {
-    let images = doc.find_all_tag("img");
     let mut res = Vec::new();
-    for image in images {
-        if let Some(src) = xml::parse_attribute(&image, "src") {
-            if src.starts_with("http") {
-                continue;
-            }
-            let abs_src = get_url(src.as_str(), path)?;
-            res.push(abs_src);
+    for node in doc.select("img[src], link[rel~=stylesheet][href]")? {
+        let url = xml::parse_attribute(&node, "src")
+            .or_else(|| xml::parse_attribute(&node, "href"))
+            .unwrap_or_default();
+        if href::is_remote(&url) {
+            continue;
         }
-    }
-
-    let links = doc.find_all_tag("link");
-    for link in links {
-        if let Some(ty) = xml::parse_attribute(&link, "type") {
-            if ty == "text/css" {
-                let href = xml::parse_attribute(&link, "href").unwrap();
-                if href.starts_with("http") {
-                    continue;
-                }
-                let url = get_url(href.as_str(), path)?;
-                res.push(url);
-            }
+        // one broken url should not break the whole chapter
+        match href::resolve(path, &url) {
+            Ok(href) => res.push(href.path().to_string()),
+            Err(e) => warn!("skip resource {:?} in {:?}: {}", url, path, e),
         }
     }
     Ok(res)
 }
 
-fn get_url(href: &str, path: &str) -> Result<String> {
-    let url = Url::parse(format!("http://localhost/{}", path).as_str())
-        .map_err(|e| anyhow!(format!("url parse error:{:?}", e)))?;
-    let url = url
-        .join(href)
-        .map_err(|e| anyhow!(format!("url join error:{:?}", e)))?;
-    let mut s = url.path().to_string();
-    if s.starts_with('/') {
-        s = s.strip_prefix('/').unwrap().to_string()
-    }
-    Ok(s)
-}
-
 pub fn update_image_url(doc: &XMLDocument, src: &str, dest: &str) {
     let images = doc.find_all_tag("img");
     for node in images {
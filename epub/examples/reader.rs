@@ -6,6 +6,7 @@ fn main() {
     let filename = args[1].to_string();
     let mut doc = Book::open_from_file(filename).unwrap();
     for chapter in doc.chapters() {
+        let chapter = chapter.unwrap();
         println!("chapter: {}, {}", chapter.index(), chapter.href());
     }
     let path = doc.resolve_path("feed_20/article_0/index_u6.html");
@@ -1,11 +1,46 @@
+use std::cell::OnceCell;
+use std::collections::BTreeMap;
+use std::io::{Read, Seek};
 use std::path::Path;
+use std::rc::Rc;
+
+use markup5ever_rcdom::{Handle, Node, NodeData};
 
 use crate::chapter::Chapter;
-use crate::container::Container;
+use crate::container::{Container, CONTAINER_PATH};
+use crate::encoding;
+use crate::encryption::{EncryptedData, Encryption, ObfuscationKey, ENCRYPTION_PATH};
+use crate::epubcfi::{self, CfiLocation, Epubcfi};
 use crate::error::{EpubError, Result};
-use crate::nav::Navigation;
-use crate::package::Package;
+use crate::href;
+use crate::media_type;
+use crate::nav::{NavItem, Navigation};
+use crate::package::{ManifestItem, ManifestProperty, MetaData, Package};
 use crate::reader::Reader;
+use crate::repair::{Repair, Warning, WarningKind};
+use crate::writer::{EpubWriter, Writer, ZipWriter, MIMETYPE_PATH};
+use crate::xml::{self, XMLDocument};
+
+pub struct Cover {
+    path: String,
+    media_type: String,
+    content: Vec<u8>,
+}
+
+impl Cover {
+    // the image path in the container
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn media_type(&self) -> &str {
+        &self.media_type
+    }
+
+    pub fn content(&self) -> &[u8] {
+        self.content.as_slice()
+    }
+}
 
 #[allow(dead_code)]
 pub struct Book {
@@ -13,36 +48,58 @@ pub struct Book {
     container: Container,
     package: Package,
     nav: Option<Navigation>,
-    rootdir: String,
+    ncx: Option<Navigation>,
+    modified: BTreeMap<String, Vec<u8>>,
+    // parsed for cfi package paths, the spine cannot be edited through a book
+    package_document: OnceCell<XMLDocument>,
 }
 
 impl Book {
-    fn new_book(mut reader: Reader) -> Result<Book> {
-        let container = reader.read_meta_container()?;
+    fn new_book(mut reader: Reader, repair: &mut Repair) -> Result<Book> {
+        let container = reader.read_meta_container(repair)?;
+        if let Err(e) = reader.read_encryption() {
+            repair.recover(
+                WarningKind::Encryption,
+                ENCRYPTION_PATH,
+                "encryption skipped",
+                e,
+            )?;
+        }
         let fullpath = container
             .full_path()
             .ok_or(EpubError::ReaderError("full path not exists".to_string()))?;
-        let package = reader.read_package(fullpath.as_str())?;
-        let fp = Path::new(fullpath.as_str()).parent();
-        let prefix = match fp {
-            Some(f) => f.to_string_lossy().to_string(),
-            None => "".to_string(),
+        let names = match repair.is_lenient() {
+            true => reader.file_names()?,
+            false => Vec::new(),
         };
+        reader.repair_case(fullpath.as_str(), &names, CONTAINER_PATH, repair);
+        let package = reader.read_package(fullpath.as_str(), repair)?;
+        reader.set_obfuscation_key(ObfuscationKey::new(&package));
+        for item in package.manifest() {
+            if let Ok(href) = href::resolve(package.path(), item.href()) {
+                reader.repair_case(href.path(), &names, package.path(), repair);
+            }
+        }
 
-        let nav = match package.get_manifest("nav") {
-            Some(nav) => {
-                let path = Path::new(prefix.as_str()).join(nav.href());
-                let p = path.to_str().unwrap();
-                Some(reader.read_nav(p)?)
-            }
-            None => match package.get_manifest("ncx") {
-                Some(ncx) => {
-                    let path = Path::new(prefix.as_str()).join(ncx.href());
-                    let p = path.to_str().unwrap();
-                    Some(reader.read_ncx(p)?)
+        let nav = match package.nav_item() {
+            Some(nav) => match href::resolve(package.path(), nav.href()) {
+                Ok(href) => {
+                    let nav = reader.read_nav(href.path());
+                    Book::repair_navigation(nav, href.path(), repair)?
+                }
+                Err(e) => Book::repair_navigation(Err(e), package.path(), repair)?,
+            },
+            None => None,
+        };
+        let ncx = match package.ncx_item() {
+            Some(ncx) => match href::resolve(package.path(), ncx.href()) {
+                Ok(href) => {
+                    let ncx = reader.read_ncx(href.path());
+                    Book::repair_navigation(ncx, href.path(), repair)?
                 }
-                None => None,
+                Err(e) => Book::repair_navigation(Err(e), package.path(), repair)?,
             },
+            None => None,
         };
 
         Ok(Book {
@@ -50,28 +107,149 @@ impl Book {
             container,
             package,
             nav,
-            rootdir: prefix.to_string(),
+            ncx,
+            modified: BTreeMap::new(),
+            package_document: OnceCell::new(),
         })
     }
 
+    // a broken navigation document is dropped by a lenient `repair`
+    fn repair_navigation(
+        navigation: Result<Navigation>,
+        path: &str,
+        repair: &mut Repair,
+    ) -> Result<Option<Navigation>> {
+        match navigation {
+            Ok(navigation) => {
+                if repair.is_lenient() {
+                    for warning in navigation.warnings() {
+                        repair.warn(WarningKind::Navigation, path, warning);
+                    }
+                }
+                Ok(Some(navigation))
+            }
+            Err(e) => {
+                repair.recover(WarningKind::Navigation, path, "navigation skipped", e)?;
+                Ok(None)
+            }
+        }
+    }
+
+    fn repair_book(reader: Reader) -> Result<(Book, Vec<Warning>)> {
+        let mut repair = Repair::lenient();
+        let book = Book::new_book(reader, &mut repair)?;
+        Ok((book, repair.into_warnings()))
+    }
+
     pub fn open_from_file<T: AsRef<Path>>(path: T) -> Result<Book> {
         let reader = Reader::new_from_path(path.as_ref().into())?;
-        Book::new_book(reader)
+        Book::new_book(reader, &mut Repair::strict())
     }
 
     pub fn open_from_memory(buffer: Vec<u8>) -> Result<Book> {
         let reader = Reader::new_from_memory(buffer)?;
-        Book::new_book(reader)
+        Book::new_book(reader, &mut Repair::strict())
+    }
+
+    // entries are read from `reader` on demand instead of loading the whole book
+    pub fn open_from_reader<R: Read + Seek + 'static>(reader: R) -> Result<Book> {
+        let reader = Reader::new_from_reader(reader)?;
+        Book::new_book(reader, &mut Repair::strict())
+    }
+
+    // like `open_from_file`, but bad items are skipped or repaired and reported as warnings
+    pub fn repair_from_file<T: AsRef<Path>>(path: T) -> Result<(Book, Vec<Warning>)> {
+        Book::repair_book(Reader::new_from_path(path.as_ref().into())?)
     }
 
-    pub fn dump(&mut self) -> Result<String> {
-        unimplemented!()
+    pub fn repair_from_memory(buffer: Vec<u8>) -> Result<(Book, Vec<Warning>)> {
+        Book::repair_book(Reader::new_from_memory(buffer)?)
     }
 
+    pub fn repair_from_reader<R: Read + Seek + 'static>(reader: R) -> Result<(Book, Vec<Warning>)> {
+        Book::repair_book(Reader::new_from_reader(reader)?)
+    }
+
+    pub fn dump<T: AsRef<Path>>(&mut self, path: T) -> Result<()> {
+        let mut writer = Writer::new_from_path(path.as_ref().into())?;
+        self.dump_to_writer(&mut writer)
+    }
+
+    pub fn dump_to_memory(&mut self) -> Result<Vec<u8>> {
+        let mut writer = ZipWriter::new_to_memory()?;
+        self.dump_to_writer(&mut writer)?;
+        Ok(writer.into_inner()?.into_inner())
+    }
+
+    // every file of the source container is copied as is, except edited ones
+    pub fn dump_to_writer(&mut self, writer: &mut dyn EpubWriter) -> Result<()> {
+        let names = self.reader.file_names()?;
+        for name in names.iter() {
+            if name == MIMETYPE_PATH {
+                continue;
+            }
+            if let Some(content) = self.modified.get(name) {
+                writer.write_file(name, self.obfuscate(name, content.clone())?.as_slice())?;
+            } else if name == self.package.path() {
+                writer.write_file(name, self.package.to_bytes()?.as_slice())?;
+            } else if self.obfuscation(name).is_some() {
+                let content = self.reader.read_binary(name)?;
+                writer.write_file(name, self.obfuscate(name, content)?.as_slice())?;
+            } else {
+                let content = self.reader.read_raw(name)?;
+                writer.write_file(name, content.as_slice())?;
+            }
+        }
+        for (name, content) in self.modified.iter() {
+            if !names.contains(name) {
+                writer.write_file(name, self.obfuscate(name, content.clone())?.as_slice())?;
+            }
+        }
+        writer.finish()
+    }
+
+    fn obfuscation(&self, name: &str) -> Option<&EncryptedData> {
+        self.reader
+            .encryption()
+            .and_then(|encryption| encryption.get(name))
+            .filter(|data| data.algorithm().is_obfuscation())
+    }
+
+    // obfuscated resources are written with a key from the current, maybe edited, identifiers
+    fn obfuscate(&self, name: &str, mut content: Vec<u8>) -> Result<Vec<u8>> {
+        if let Some(data) = self.obfuscation(name) {
+            ObfuscationKey::new(&self.package).apply(data, content.as_mut_slice())?;
+        }
+        Ok(content)
+    }
+
+    // `META-INF/encryption.xml`
+    pub fn encryption(&self) -> Option<&Encryption> {
+        self.reader.encryption()
+    }
+
+    pub fn metadata_mut(&mut self) -> &mut MetaData {
+        self.package.metadata_mut()
+    }
+
+    // `name` is a path in the container, use `resolve_path` for package relative hrefs
+    pub fn replace_file(&mut self, name: &str, content: Vec<u8>) {
+        self.modified.insert(name.to_string(), content);
+    }
+
+    // the EPUB 3 navigation document, or the NCX when the book has none
     pub fn nav(&self) -> Option<&Navigation> {
+        self.nav.as_ref().or(self.ncx.as_ref())
+    }
+
+    pub fn nav_document(&self) -> Option<&Navigation> {
         self.nav.as_ref()
     }
 
+    pub fn ncx(&self) -> Option<&Navigation> {
+        self.ncx.as_ref()
+    }
+
     pub fn package(&self) -> &Package {
         &self.package
     }
@@ -80,48 +258,1107 @@ impl Book {
         self.package.title()
     }
 
-    pub fn resolve_path(&self, name: &str) -> String {
-        let path = Path::new(self.rootdir.as_str()).join(name);
-        path.to_string_lossy().to_string()
+    // the container path of an href relative to the package document, without its fragment
+    pub fn resolve_path(&self, name: &str) -> Result<String> {
+        Ok(href::resolve(self.package.path(), name)?.path().to_string())
+    }
+
+    fn item_path(&self, item: &ManifestItem) -> Option<String> {
+        self.resolve_path(item.href()).ok()
     }
 
     pub fn content(&mut self, path: &str) -> Result<String> {
+        if let Some(content) = self.modified.get(path) {
+            return encoding::decode(content, path);
+        }
         self.reader.read_content(path)
     }
 
+    // entries of the source container, files added by `replace_file` are not listed
+    pub fn file_names(&mut self) -> Result<Vec<String>> {
+        self.reader.file_names()
+    }
+
+    // `None` for unpacked books
+    pub(crate) fn first_entry(&mut self) -> Result<Option<(String, bool)>> {
+        self.reader.first_entry()
+    }
+
     pub fn read_binary_file(&mut self, name: &str) -> Result<Vec<u8>> {
+        if let Some(content) = self.modified.get(name) {
+            return Ok(content.clone());
+        }
         self.reader.read_binary(name)
     }
 
-    pub fn chapters(&mut self) -> PageIterator {
+    // stream a large entry, like audio or video, without buffering all of it
+    pub fn open_file(&mut self, name: &str) -> Result<Box<dyn Read + '_>> {
+        if let Some(content) = self.modified.get(name) {
+            return Ok(Box::new(content.as_slice()));
+        }
+        self.reader.open(name)
+    }
+
+    // the first existing image of the EPUB 3 cover-image item, the EPUB 2 cover meta,
+    // the guide or landmarks cover page and manifest images named like a cover.
+    // an image that exists but cannot be read, like an encrypted one, is an error
+    pub fn cover(&mut self) -> Result<Option<Cover>> {
+        for path in self.cover_candidates() {
+            let Some(media_type) = self.image_media_type(&path) else {
+                continue;
+            };
+            if !self.modified.contains_key(&path) && !self.reader.contains(&path)? {
+                continue;
+            }
+            let content = self.read_binary_file(&path)?;
+            return Ok(Some(Cover {
+                path,
+                media_type,
+                content,
+            }));
+        }
+        Ok(None)
+    }
+
+    fn cover_candidates(&mut self) -> Vec<String> {
+        let manifest = self.package.manifest();
+        let mut candidates: Vec<&str> = manifest
+            .iter()
+            .filter(|item| item.properties().contains(&ManifestProperty::CoverImage))
+            .map(|item| item.href())
+            .collect();
+        if let Some(cover) = self.package.metadata().meta("cover") {
+            // some books reference the image by href instead of by id
+            match self.package.get_manifest(cover) {
+                Some(item) => candidates.push(item.href()),
+                None => candidates.push(cover),
+            }
+        }
+        let mut paths: Vec<String> = candidates
+            .into_iter()
+            .filter_map(|href| self.resolve_path(href).ok())
+            .collect();
+
+        let mut pages: Vec<String> = self
+            .package
+            .guide()
+            .iter()
+            .filter(|r| r.reference_type().eq_ignore_ascii_case("cover"))
+            .filter_map(|r| r.href())
+            .filter_map(|href| self.resolve_path(href).ok())
+            .collect();
+        let nav_path = self
+            .package
+            .nav_item()
+            .and_then(|item| self.item_path(item));
+        if let (Some(nav_path), Some(nav)) = (nav_path, self.nav.as_ref()) {
+            pages.extend(
+                nav.landmarks()
+                    .iter()
+                    .filter(|l| {
+                        l.epub_type()
+                            .is_some_and(|t| t.split_whitespace().any(|t| t == "cover"))
+                    })
+                    .filter_map(|l| href::resolve(&nav_path, l.href()).ok())
+                    .map(|href| href.path().to_string()),
+            );
+        }
+        for page in pages {
+            match self.image_media_type(&page) {
+                Some(_) => paths.push(page),
+                None => paths.extend(self.first_image(&page)),
+            }
+        }
+
+        paths.extend(
+            self.package
+                .manifest()
+                .iter()
+                .filter(|item| item.media_type().starts_with("image/"))
+                .filter(|item| {
+                    let name = item.href().rsplit('/').next().unwrap_or_default();
+                    name.to_ascii_lowercase().contains("cover")
+                        || item.id().to_ascii_lowercase().contains("cover")
+                })
+                .filter_map(|item| self.item_path(item)),
+        );
+        paths
+    }
+
+    // the first `<img>` or svg `<image>` of a cover page
+    fn first_image(&mut self, page: &str) -> Option<String> {
+        let content = self.read_binary_file(page).ok()?;
+        let doc = XMLDocument::try_new_from_entry(&content, page).ok()?;
+        let src = doc
+            .find_all_tag("img")
+            .iter()
+            .find_map(|img| xml::parse_attribute(img, "src"))
+            .or_else(|| {
+                doc.find_all_tag("image")
+                    .iter()
+                    .find_map(|image| xml::parse_attribute(image, "href"))
+            })?;
+        Some(href::resolve(page, &src).ok()?.path().to_string())
+    }
+
+    // the manifest media type of the image at `path`, or one guessed from its extension
+    fn image_media_type(&self, path: &str) -> Option<String> {
+        let media_type = self
+            .package
+            .manifest()
+            .iter()
+            .find(|item| self.item_path(item).as_deref() == Some(path))
+            .map(|item| item.media_type())
+            .or_else(|| media_type::guess(path).ok())?;
+        media_type
+            .starts_with("image/")
+            .then(|| media_type.to_string())
+    }
+
+    // the navigation document, or the NCX, with its manifest item
+    fn toc_navigation(&self) -> Option<(&ManifestItem, &Navigation)> {
+        let documents = [
+            (self.package.nav_item(), self.nav.as_ref()),
+            (self.package.ncx_item(), self.ncx.as_ref()),
+        ];
+        documents.into_iter().find_map(|(item, nav)| item.zip(nav))
+    }
+
+    // the toc of the navigation document, or of the NCX, in document order
+    fn toc_entries(&self) -> Vec<TocEntry<'_>> {
+        fn collect<'a>(
+            items: &'a [NavItem],
+            base: &str,
+            ancestors: &mut Vec<&'a NavItem>,
+            entries: &mut Vec<TocEntry<'a>>,
+        ) {
+            for item in items {
+                ancestors.push(item);
+                // an entry without a link only groups its children
+                let href = match item.href().is_empty() {
+                    true => None,
+                    false => href::resolve(base, item.href()).ok(),
+                };
+                if let Some(href) = href {
+                    entries.push(TocEntry {
+                        items: ancestors.clone(),
+                        path: href.path().to_string(),
+                        fragment: href.fragment().map(|f| f.to_string()),
+                    });
+                }
+                collect(item.children(), base, ancestors, entries);
+                ancestors.pop();
+            }
+        }
+        let mut entries = Vec::new();
+        if let Some((item, nav)) = self.toc_navigation() {
+            if let Some(path) = self.item_path(item) {
+                collect(nav.toc(), &path, &mut Vec::new(), &mut entries);
+            }
+        }
+        entries
+    }
+
+    fn toc_has_fragments(&self) -> bool {
+        fn any(items: &[NavItem]) -> bool {
+            items
+                .iter()
+                .any(|item| item.href().contains('#') || any(item.children()))
+        }
+        self.toc_navigation().is_some_and(|(_, nav)| any(nav.toc()))
+    }
+
+    // table of contents labels by the path they point at
+    fn toc_titles(&self) -> BTreeMap<String, Vec<String>> {
+        let mut titles: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for entry in self.toc_entries() {
+            if let Some(item) = entry.items.last() {
+                titles
+                    .entry(entry.path)
+                    .or_default()
+                    .push(item.text().to_string());
+            }
+        }
+        titles
+    }
+
+    // the spine index of a path in the container, a fragment is ignored
+    pub fn spine_index(&self, path: &str) -> Option<usize> {
+        let path = href::resolve("", path).ok()?;
+        (0..self.package.spine().len()).find(|i| {
+            self.package
+                .spine_content(*i)
+                .ok()
+                .flatten()
+                .and_then(|item| self.item_path(item))
+                .is_some_and(|item_path| item_path == path.path())
+        })
+    }
+
+    pub fn next_linear(&self, spine_index: usize) -> Option<usize> {
+        let spine = self.package.spine();
+        (spine_index + 1..spine.len()).find(|i| spine[*i].is_linear())
+    }
+
+    pub fn previous_linear(&self, spine_index: usize) -> Option<usize> {
+        let spine = self.package.spine();
+        (0..spine_index.min(spine.len()))
+            .rev()
+            .find(|i| spine[*i].is_linear())
+    }
+
+    // the toc items from the top level down to the deepest one covering the element `fragment`,
+    // or the start when it is `None`, of spine item `spine_index`.
+    // the items are owned, the spine document may have to be read to order its ids
+    pub fn breadcrumb(
+        &mut self,
+        spine_index: usize,
+        fragment: Option<&str>,
+    ) -> Result<Vec<NavItem>> {
+        // toc entries and the location inside one document are ordered by their element ids
+        let ids = match self.package.spine_content(spine_index)? {
+            Some(item) if fragment.is_some() || self.toc_has_fragments() => {
+                let path = self.resolve_path(item.href())?;
+                XMLDocument::try_new_from_entry(&self.read_binary_file(&path)?, &path)?.ids()
+            }
+            _ => Vec::new(),
+        };
+        let key = |fragment: Option<&str>| match fragment {
+            Some(f) => ids.iter().position(|id| id == f).map_or(0, |p| p + 1),
+            None => 0,
+        };
+        let location = (spine_index, key(fragment));
+        // the last of equally placed entries wins
+        let best = self
+            .toc_entries()
+            .into_iter()
+            .filter_map(|entry| {
+                let index = self.spine_index(&entry.path)?;
+                let position = match index == spine_index {
+                    true => (index, key(entry.fragment.as_deref())),
+                    false => (index, 0),
+                };
+                (position <= location).then_some((position, entry))
+            })
+            .max_by_key(|(position, _)| *position);
+        Ok(match best {
+            Some((_, entry)) => entry.items.into_iter().cloned().collect(),
+            None => Vec::new(),
+        })
+    }
+
+    // the deepest toc item covering a location, see `breadcrumb`
+    pub fn toc_item(
+        &mut self,
+        spine_index: usize,
+        fragment: Option<&str>,
+    ) -> Result<Option<NavItem>> {
+        Ok(self.breadcrumb(spine_index, fragment)?.pop())
+    }
+
+    fn read_chapter(
+        &mut self,
+        index: usize,
+        titles: &BTreeMap<String, Vec<String>>,
+    ) -> Result<Chapter> {
+        let itemref =
+            self.package.spine().get(index).ok_or_else(|| {
+                EpubError::FormatError(format!("spine index {} out of range", index))
+            })?;
+        let linear = itemref.is_linear();
+        let properties = itemref.properties();
+        let item = self.package.spine_content(index)?.ok_or_else(|| {
+            EpubError::FormatError(format!(
+                "spine item {:?} has no content document fallback",
+                itemref.idref()
+            ))
+        })?;
+        let id = item.id().to_string();
+        let href = item.href().to_string();
+        let media_type = item.media_type().to_string();
+        let path = self.resolve_path(&href)?;
+        let content = self.content(&path)?;
+        Ok(Chapter {
+            index,
+            id,
+            href,
+            titles: titles.get(&path).cloned().unwrap_or_default(),
+            path,
+            media_type,
+            linear,
+            properties,
+            content,
+            document: OnceCell::new(),
+        })
+    }
+
+    // spine items in order, foreign items are read from their content document fallback.
+    // the content of each chapter is read when the iterator reaches it, its DOM on first use
+    pub fn chapters(&mut self) -> PageIterator<'_> {
         PageIterator::new(self)
     }
+
+    // like `chapters`, without `linear="no"` spine items
+    pub fn linear_chapters(&mut self) -> PageIterator<'_> {
+        PageIterator {
+            linear: true,
+            ..PageIterator::new(self)
+        }
+    }
+
+    // the spine item, node and character offset a cfi points to, a range resolves to its start
+    pub fn resolve_cfi(&mut self, cfi: &Epubcfi) -> Result<CfiLocation> {
+        let cfi = cfi.start();
+        let paths = cfi.paths();
+        if paths.len() > 2 {
+            return Err(EpubError::CfiError(format!(
+                "indirection inside a content document is not supported:{}",
+                cfi
+            )));
+        }
+        let package_path = paths
+            .first()
+            .ok_or(EpubError::CfiError("empty cfi".to_string()))?;
+        let (itemref, _) = epubcfi::resolve_path(self.package_document()?, package_path)?;
+        let idref = xml::parse_attribute(&itemref, "idref")
+            .ok_or(EpubError::CfiError(format!("{} is not a spine item", cfi)))?;
+        let spine_index = self
+            .package
+            .spine()
+            .iter()
+            .position(|item| item.idref() == idref)
+            .ok_or(EpubError::CfiError(format!(
+                "{} is not in the spine",
+                idref
+            )))?;
+        let item = self
+            .package
+            .spine_content(spine_index)?
+            .ok_or(EpubError::CfiError(format!(
+                "spine item {:?} has no content document fallback",
+                idref
+            )))?;
+        let path = self.resolve_path(item.href())?;
+        let document = XMLDocument::try_new_from_entry(&self.read_binary_file(&path)?, &path)?;
+        let (node, offset) = match paths.get(1) {
+            Some(content) => epubcfi::resolve_path(&document, content)?,
+            None => (
+                document
+                    .root()
+                    .ok_or(EpubError::CfiError(format!("{} has no root element", path)))?,
+                None,
+            ),
+        };
+        Ok(CfiLocation {
+            spine_index,
+            path,
+            document,
+            node,
+            offset,
+        })
+    }
+
+    // plain text between the start and the end of a range inside one content document
+    pub fn text_for_cfi(&mut self, cfi: &Epubcfi) -> Result<String> {
+        if !cfi.is_range() {
+            return Err(EpubError::CfiError(format!("{} is not a range", cfi)));
+        }
+        let start = self.resolve_cfi(cfi)?;
+        let end = cfi.end();
+        if end.paths().len() != 2 || end.paths()[0].steps() != cfi.start().paths()[0].steps() {
+            return Err(EpubError::CfiError(format!(
+                "{} is not inside one content document",
+                cfi
+            )));
+        }
+        let (end_node, end_offset) = epubcfi::resolve_path(&start.document, &end.paths()[1])?;
+        let root = start.document.root().ok_or(EpubError::CfiError(format!(
+            "{} has no root element",
+            start.path
+        )))?;
+        let mut collector = TextCollector {
+            start: &start.node,
+            start_offset: start.offset.unwrap_or(0) as usize,
+            end: &end_node,
+            end_offset: end_offset.unwrap_or(0) as usize,
+            started: false,
+            text: String::new(),
+        };
+        collector.collect(&root);
+        Ok(collector.text)
+    }
+
+    fn package_document(&self) -> Result<&XMLDocument> {
+        if let Some(document) = self.package_document.get() {
+            return Ok(document);
+        }
+        let document = XMLDocument::try_new(&self.package.to_bytes()?)?;
+        Ok(self.package_document.get_or_init(|| document))
+    }
+
+    // the package path of a spine item, like `/6/4[chapter1]`
+    fn spine_path(&self, spine_index: usize) -> Result<epubcfi::Path> {
+        let itemref = self
+            .package_document()?
+            .find_tag("spine")
+            .and_then(|spine| {
+                xml::find_children(&spine, "itemref")
+                    .into_iter()
+                    .nth(spine_index)
+            })
+            .ok_or(EpubError::CfiError(format!(
+                "spine item {} not found",
+                spine_index
+            )))?;
+        epubcfi::Path::from_node(&itemref, None, false)
+    }
+
+    // `node` is in the content document of the spine item, see `Path::from_node`
+    pub fn cfi_for_node(
+        &self,
+        spine_index: usize,
+        node: &Rc<Node>,
+        offset: Option<u32>,
+        text_assertion: bool,
+    ) -> Result<Epubcfi> {
+        Epubcfi::new(vec![
+            self.spine_path(spine_index)?,
+            epubcfi::Path::from_node(node, offset, text_assertion)?,
+        ])
+    }
+
+    // a selection inside one content document
+    pub fn cfi_for_range(
+        &self,
+        spine_index: usize,
+        start: (&Rc<Node>, Option<u32>),
+        end: (&Rc<Node>, Option<u32>),
+        text_assertion: bool,
+    ) -> Result<Epubcfi> {
+        let start = self.cfi_for_node(spine_index, start.0, start.1, text_assertion)?;
+        let end = self.cfi_for_node(spine_index, end.0, end.1, text_assertion)?;
+        Epubcfi::new_range(&start, &end)
+    }
+}
+
+// an element as range end means the position right before it
+struct TextCollector<'a> {
+    start: &'a Rc<Node>,
+    start_offset: usize,
+    end: &'a Rc<Node>,
+    end_offset: usize,
+    started: bool,
+    text: String,
+}
+
+impl TextCollector<'_> {
+    // returns true once the end is reached
+    fn collect(&mut self, node: &Handle) -> bool {
+        let is_start = Rc::ptr_eq(node, self.start);
+        let is_end = Rc::ptr_eq(node, self.end);
+        match node.data {
+            NodeData::Text { ref contents } => {
+                self.started |= is_start;
+                let chars: Vec<char> = contents.borrow().chars().collect();
+                let from = if is_start { self.start_offset } else { 0 }.min(chars.len());
+                let to = if is_end { self.end_offset } else { chars.len() }.min(chars.len());
+                if self.started && from < to {
+                    self.text.extend(&chars[from..to]);
+                }
+                is_end
+            }
+            _ if is_end => true,
+            _ => {
+                self.started |= is_start;
+                node.children
+                    .borrow()
+                    .iter()
+                    .any(|child| self.collect(child))
+            }
+        }
+    }
+}
+
+struct TocEntry<'a> {
+    // the item and its ancestors, top level first
+    items: Vec<&'a NavItem>,
+    path: String,
+    fragment: Option<String>,
 }
 
 pub struct PageIterator<'a> {
     book: &'a mut Book,
     current_chapter: usize,
+    linear: bool,
+    titles: BTreeMap<String, Vec<String>>,
 }
 
 impl<'a> PageIterator<'a> {
     pub fn new(book: &'a mut Book) -> Self {
+        let titles = book.toc_titles();
         PageIterator {
             book,
             current_chapter: 0,
+            linear: false,
+            titles,
         }
     }
 }
 
 impl<'a> Iterator for PageIterator<'a> {
-    type Item = Chapter;
+    type Item = Result<Chapter>;
     fn next(&mut self) -> Option<Self::Item> {
-        if let Some(item) = self.book.package.chapter(self.current_chapter) {
-            let content = self.book.content(item.href()).unwrap();
-            let chapter = Chapter::new(self.current_chapter, item.href(), content);
-            self.current_chapter += 1;
-            Some(chapter)
-        } else {
-            None
+        let spine = self.book.package.spine();
+        if self.linear {
+            while spine
+                .get(self.current_chapter)
+                .is_some_and(|item| !item.is_linear())
+            {
+                self.current_chapter += 1;
+            }
+        }
+        if self.current_chapter >= spine.len() {
+            return None;
+        }
+        let index = self.current_chapter;
+        self.current_chapter += 1;
+        Some(self.book.read_chapter(index, &self.titles))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use crate::book::Book;
+    use crate::chapter::Chapter;
+    use crate::epubcfi::{CfiLocation, Epubcfi};
+    use crate::error::{EpubError, Result};
+    use crate::package::SpineProperty;
+    use crate::repair::WarningKind;
+    use crate::writer::{EpubWriter, ZipWriter};
+    use crate::xml::{self, XMLDocument};
+
+    const PACKAGE: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="3.0" unique-identifier="uid">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+    <dc:identifier id="uid">urn:uuid:dump-test</dc:identifier>
+    <dc:title>Old Title</dc:title>
+    <dc:creator id="a1">First Author</dc:creator>
+    <dc:creator id="a2">Second Author</dc:creator>
+    <dc:language>en</dc:language>
+    <meta property="dcterms:modified">2024-01-01T00:00:00Z</meta>
+  </metadata>
+  <manifest>
+    <item id="c1" href="c1.xhtml" media-type="application/xhtml+xml"/>
+    <item id="c2" href="c2.xhtml" media-type="application/xhtml+xml"/>
+  </manifest>
+  <spine>
+    <itemref idref="c1"/>
+    <itemref idref="c2"/>
+  </spine>
+  <x:unknown xmlns:x="urn:example">kept</x:unknown>
+</package>"#;
+
+    fn chapter(text: &str) -> String {
+        format!(
+            r#"<html xmlns="http://www.w3.org/1999/xhtml"><head><title>{0}</title></head><body><p>{0}</p></body></html>"#,
+            text
+        )
+    }
+
+    fn write_book<T: AsRef<[u8]>>(files: &[(&str, T)]) -> Vec<u8> {
+        let mut writer = ZipWriter::new_to_memory().unwrap();
+        writer.write_container("OEBPS/content.opf").unwrap();
+        for (name, content) in files {
+            writer.write_file(name, content.as_ref()).unwrap();
         }
+        writer.into_inner().unwrap().into_inner()
+    }
+
+    fn source_book() -> Vec<u8> {
+        write_book(&[
+            ("OEBPS/content.opf", PACKAGE),
+            ("OEBPS/c1.xhtml", chapter("one").as_str()),
+            ("OEBPS/c2.xhtml", chapter("two").as_str()),
+            ("META-INF/com.apple.ibooks.display-options.xml", "<x/>"),
+        ])
+    }
+
+    #[test]
+    fn test_dump_unchanged() {
+        let mut book = Book::open_from_memory(source_book()).unwrap();
+        let buffer = book.dump_to_memory().unwrap();
+        let mut copy = Book::open_from_memory(buffer).unwrap();
+        assert_eq!(
+            copy.read_binary_file("OEBPS/content.opf").unwrap(),
+            PACKAGE.as_bytes()
+        );
+        assert_eq!(
+            copy.read_binary_file("META-INF/com.apple.ibooks.display-options.xml")
+                .unwrap(),
+            b"<x/>"
+        );
+    }
+
+    #[test]
+    fn test_dump_edited() {
+        let mut book = Book::open_from_memory(source_book()).unwrap();
+        book.metadata_mut().set_title("New & Title");
+        book.replace_file("OEBPS/c2.xhtml", chapter("changed").into_bytes());
+        let buffer = book.dump_to_memory().unwrap();
+
+        let mut copy = Book::open_from_memory(buffer).unwrap();
+        assert_eq!(copy.title(), "New & Title");
+        let creators = copy
+            .package()
+            .metadata()
+            .items()
+            .iter()
+            .filter(|item| item.name() == "creator")
+            .count();
+        assert_eq!(creators, 2);
+        let package = copy.content("OEBPS/content.opf").unwrap();
+        assert!(package.contains(r#"<x:unknown xmlns:x="urn:example">kept</x:unknown>"#));
+        assert_eq!(copy.content("OEBPS/c1.xhtml").unwrap(), chapter("one"));
+        assert_eq!(copy.content("OEBPS/c2.xhtml").unwrap(), chapter("changed"));
+    }
+
+    #[test]
+    fn test_navigation_documents() {
+        let package = r#"<?xml version="1.0" encoding="UTF-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="3.0" unique-identifier="uid">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+    <dc:identifier id="uid">urn:uuid:nav-test</dc:identifier>
+    <dc:title>Nav</dc:title>
+  </metadata>
+  <manifest>
+    <item id="toc-page" href="Text/toc.xhtml" media-type="application/xhtml+xml" properties="scripted nav"/>
+    <item id="legacy" href="legacy.ncx" media-type="application/x-dtbncx+xml"/>
+    <item id="c1" href="c1.xhtml" media-type="application/xhtml+xml"/>
+  </manifest>
+  <spine toc="legacy">
+    <itemref idref="c1"/>
+  </spine>
+</package>"#;
+        let nav = r#"<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops"><head><title>Nav</title></head>
+<body><nav epub:type="toc"><ol><li><a href="../c1.xhtml">From nav</a></li></ol></nav></body></html>"#;
+        let ncx = r#"<ncx xmlns="http://www.daisy.org/z3986/2005/ncx/" version="2005-1"><docTitle><text>Nav</text></docTitle>
+<navMap><navPoint id="p1" playOrder="1"><navLabel><text>From ncx</text></navLabel><content src="c1.xhtml"/></navPoint></navMap></ncx>"#;
+        let buffer = write_book(&[
+            ("OEBPS/content.opf", package),
+            ("OEBPS/Text/toc.xhtml", nav),
+            ("OEBPS/legacy.ncx", ncx),
+            ("OEBPS/c1.xhtml", chapter("one").as_str()),
+        ]);
+        let book = Book::open_from_memory(buffer).unwrap();
+        assert_eq!(book.nav().unwrap().toc()[0].text(), "From nav");
+        assert_eq!(book.nav_document().unwrap().toc()[0].text(), "From nav");
+        assert_eq!(book.ncx().unwrap().toc()[0].text(), "From ncx");
+    }
+
+    const CFI_CHAPTER: &str = r#"<html xmlns="http://www.w3.org/1999/xhtml"><head><title>two</title></head><body>
+<p id="a">first</p>
+<p id="b">second</p></body></html>"#;
+
+    fn cfi_book() -> Book {
+        let buffer = write_book(&[
+            ("OEBPS/content.opf", PACKAGE),
+            ("OEBPS/c1.xhtml", chapter("one").as_str()),
+            ("OEBPS/c2.xhtml", CFI_CHAPTER),
+        ]);
+        Book::open_from_memory(buffer).unwrap()
+    }
+
+    #[test]
+    fn test_resolve_cfi() {
+        let mut book = cfi_book();
+        let text = |location: &CfiLocation| xml::parse_text(&xml::parent(location.node()).unwrap());
+
+        let cfi = Epubcfi::try_new("epubcfi(/6/4!/4/4[b]/1:2)").unwrap();
+        let location = book.resolve_cfi(&cfi).unwrap();
+        assert_eq!(location.spine_index(), 1);
+        assert_eq!(location.path(), "OEBPS/c2.xhtml");
+        assert_eq!(location.offset(), Some(2));
+        assert_eq!(text(&location), "second");
+
+        // the id assertion wins over a wrong index
+        let cfi = Epubcfi::try_new("epubcfi(/6/4!/4/2[b]/1:1)").unwrap();
+        assert_eq!(text(&book.resolve_cfi(&cfi).unwrap()), "second");
+
+        let cfi = Epubcfi::try_new("epubcfi(/6/2!/4/2,/1:0,/1:2)").unwrap();
+        let location = book.resolve_cfi(&cfi).unwrap();
+        assert_eq!(location.spine_index(), 0);
+        assert_eq!(text(&location), "one");
+
+        for cfi in ["/6/4!/4/2[missing]", "/6/8!/4", "/6/4!/4/4/1:20"] {
+            let cfi = Epubcfi::try_new(cfi).unwrap();
+            assert!(book.resolve_cfi(&cfi).is_err());
+        }
+        assert!(book.resolve_cfi(&Epubcfi::default()).is_err());
+    }
+
+    #[test]
+    fn test_generate_cfi() {
+        let mut book = cfi_book();
+        let doc = XMLDocument::try_new(CFI_CHAPTER.as_bytes()).unwrap();
+        let paragraphs = doc.find_all_tag("p");
+        let first = paragraphs[0].children.borrow()[0].clone();
+        let second = paragraphs[1].children.borrow()[0].clone();
+
+        let cfi = book.cfi_for_node(1, &second, Some(3), false).unwrap();
+        assert_eq!(cfi.to_string(), "epubcfi(/6/4!/4/4[b]/1:3)");
+        let location = book.resolve_cfi(&cfi).unwrap();
+        assert_eq!(location.offset(), Some(3));
+        assert_eq!(
+            xml::parse_text(&xml::parent(location.node()).unwrap()),
+            "second"
+        );
+
+        let cfi = book.cfi_for_node(1, &second, Some(3), true).unwrap();
+        assert_eq!(cfi.to_string(), "epubcfi(/6/4!/4/4[b]/1:3[sec,ond])");
+        let cfi = book.cfi_for_node(1, &paragraphs[0], None, false).unwrap();
+        assert_eq!(cfi.to_string(), "epubcfi(/6/4!/4/2[a])");
+
+        // the ends of a selection may come in any order
+        let range = book
+            .cfi_for_range(1, (&second, Some(2)), (&first, Some(1)), false)
+            .unwrap();
+        assert_eq!(range.to_string(), "epubcfi(/6/4!/4,/2[a]/1:1,/4[b]/1:2)");
+        let range = book
+            .cfi_for_range(1, (&first, Some(1)), (&first, Some(4)), false)
+            .unwrap();
+        assert_eq!(range.to_string(), "epubcfi(/6/4!/4/2[a],/1:1,/1:4)");
+        assert!(book.cfi_for_node(2, &first, None, false).is_err());
+    }
+
+    #[test]
+    fn test_text_for_cfi() {
+        let mut book = cfi_book();
+        let text = |book: &mut Book, cfi: &str| book.text_for_cfi(&Epubcfi::try_new(cfi).unwrap());
+        assert_eq!(text(&mut book, "/6/4!/4/2[a],/1:1,/1:4").unwrap(), "irs");
+        assert_eq!(
+            text(&mut book, "/6/4!/4,/2[a]/1:3,/4[b]/1:3").unwrap(),
+            "st\nsec"
+        );
+        assert_eq!(text(&mut book, "/6/4!/4,/2[a],/4[b]").unwrap(), "first\n");
+        assert!(text(&mut book, "/6/4!/4/2[a]/1:1").is_err());
+        assert!(text(&mut book, "/6,/2!/4/2/1:0,/4!/4/2/1:1").is_err());
+    }
+
+    #[test]
+    fn test_drm() {
+        let encryption = r#"<encryption xmlns="urn:oasis:names:tc:opendocument:xmlns:container" xmlns:enc="http://www.w3.org/2001/04/xmlenc#">
+<enc:EncryptedData><enc:EncryptionMethod Algorithm="http://www.w3.org/2001/04/xmlenc#aes128-cbc"/>
+<enc:CipherData><enc:CipherReference URI="OEBPS/c1.xhtml"/></enc:CipherData></enc:EncryptedData></encryption>"#;
+        let buffer = write_book(&[
+            ("OEBPS/content.opf", PACKAGE),
+            ("OEBPS/c1.xhtml", "\x01\x02encrypted"),
+            ("OEBPS/c2.xhtml", chapter("two").as_str()),
+            ("META-INF/encryption.xml", encryption),
+        ]);
+        let mut book = Book::open_from_memory(buffer).unwrap();
+        assert!(book.encryption().unwrap().has_drm());
+        assert!(matches!(
+            book.content("OEBPS/c1.xhtml"),
+            Err(EpubError::DrmError(_))
+        ));
+        assert_eq!(book.content("OEBPS/c2.xhtml").unwrap(), chapter("two"));
+
+        // encrypted resources are copied as they are
+        let mut copy = Book::open_from_memory(book.dump_to_memory().unwrap()).unwrap();
+        assert!(copy.content("OEBPS/c1.xhtml").is_err());
+    }
+
+    #[test]
+    fn test_open_from_reader() {
+        let path = std::env::temp_dir().join(format!("epub-reader-{}.epub", std::process::id()));
+        std::fs::write(path.as_path(), source_book()).unwrap();
+        let file = std::fs::File::open(path.as_path()).unwrap();
+        let mut book = Book::open_from_reader(file).unwrap();
+        assert_eq!(book.title(), "Old Title");
+        let mut content = String::new();
+        book.open_file("OEBPS/c2.xhtml")
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, chapter("two"));
+        assert!(book.open_file("OEBPS/missing.xhtml").is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_repair() {
+        let package = PACKAGE
+            .replace(
+                r#"<item id="c2" href="c2.xhtml" media-type="application/xhtml+xml"/>"#,
+                r#"<item id="c2" href="c2.xhtml"/><item id="c3"/>"#,
+            )
+            .replace("</spine>", r#"<itemref idref="c3"/></spine>"#);
+        // no container.xml and a chapter stored with the wrong case
+        let mut writer = ZipWriter::new_to_memory().unwrap();
+        writer
+            .write_file("OEBPS/content.opf", package.as_bytes())
+            .unwrap();
+        writer
+            .write_file("OEBPS/C1.xhtml", chapter("one").as_bytes())
+            .unwrap();
+        writer
+            .write_file("OEBPS/c2.xhtml", chapter("two").as_bytes())
+            .unwrap();
+        let buffer = writer.into_inner().unwrap().into_inner();
+        assert!(Book::open_from_memory(buffer.clone()).is_err());
+
+        let (mut book, warnings) = Book::repair_from_memory(buffer).unwrap();
+        let kinds: Vec<WarningKind> = warnings.iter().map(|w| w.kind()).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                WarningKind::MissingContainer,
+                WarningKind::RepairedItem,
+                WarningKind::InvalidItem,
+                WarningKind::InvalidItem,
+                WarningKind::PathCase,
+            ]
+        );
+        assert_eq!(warnings[4].path(), "OEBPS/content.opf");
+        assert_eq!(book.package().manifest().len(), 2);
+        assert_eq!(book.package().spine().len(), 2);
+        assert_eq!(
+            book.package().get_manifest("c2").unwrap().media_type(),
+            "application/xhtml+xml"
+        );
+        assert_eq!(book.content("OEBPS/c1.xhtml").unwrap(), chapter("one"));
+    }
+
+    #[test]
+    fn test_repair_encryption() {
+        let buffer = write_book(&[
+            ("OEBPS/content.opf", PACKAGE),
+            ("OEBPS/c1.xhtml", chapter("one").as_str()),
+            ("OEBPS/c2.xhtml", chapter("two").as_str()),
+            (
+                "META-INF/encryption.xml",
+                "<encryption><EncryptedData/></encryption>",
+            ),
+        ]);
+        assert!(Book::open_from_memory(buffer.clone()).is_err());
+
+        let (mut book, warnings) = Book::repair_from_memory(buffer).unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].kind(), WarningKind::Encryption);
+        assert_eq!(warnings[0].path(), "META-INF/encryption.xml");
+        assert!(book.encryption().is_none());
+        assert_eq!(book.content("OEBPS/c1.xhtml").unwrap(), chapter("one"));
+    }
+
+    #[test]
+    fn test_chapters() {
+        let package = PACKAGE
+            .replace(
+                r#"<itemref idref="c1"/>"#,
+                r#"<itemref idref="c1" linear="no" properties="page-spread-left"/>"#,
+            )
+            .replace(
+                "</manifest>",
+                r#"<item id="nav" href="text/nav.xhtml" media-type="application/xhtml+xml" properties="nav"/>
+<item id="pdf" href="c3.pdf" media-type="application/pdf" fallback="c2"/>
+<item id="c4" href="c4.xhtml" media-type="application/xhtml+xml"/></manifest>"#,
+            )
+            .replace("</spine>", r#"<itemref idref="pdf"/><itemref idref="c4"/></spine>"#);
+        let nav = r#"<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops"><body>
+<nav epub:type="toc"><ol><li><a href="../c2.xhtml">Two</a><ol><li><a href="../c2.xhtml#end">End</a></li></ol></li></ol></nav>
+</body></html>"#;
+        let mut book = Book::open_from_memory(write_book(&[
+            ("OEBPS/content.opf", package.as_str()),
+            ("OEBPS/text/nav.xhtml", nav),
+            ("OEBPS/c1.xhtml", chapter("one").as_str()),
+            ("OEBPS/c2.xhtml", chapter("two").as_str()),
+        ]))
+        .unwrap();
+        let chapters: Vec<Result<Chapter>> = book.chapters().collect();
+        assert_eq!(chapters.len(), 4);
+        let first = chapters[0].as_ref().unwrap();
+        assert_eq!(first.path(), "OEBPS/c1.xhtml");
+        assert!(!first.is_linear());
+        assert_eq!(first.properties(), &[SpineProperty::PageSpreadLeft]);
+        assert!(first.titles().is_empty());
+        let second = chapters[1].as_ref().unwrap();
+        assert_eq!((second.id(), second.href()), ("c2", "c2.xhtml"));
+        assert_eq!(second.media_type(), "application/xhtml+xml");
+        assert_eq!(second.titles(), &["Two".to_string(), "End".to_string()]);
+        let root = second.document().root().unwrap();
+        assert_eq!(xml::parse_all_text(&root), "twotwo");
+        // a foreign item is read from its fallback
+        let third = chapters[2].as_ref().unwrap();
+        assert_eq!((third.index(), third.id()), (2, "c2"));
+        // a missing file does not end the iteration
+        assert!(chapters[3].is_err());
+
+        let linear: Vec<usize> = book
+            .linear_chapters()
+            .filter_map(|c| c.ok())
+            .map(|c| c.index())
+            .collect();
+        assert_eq!(linear, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_encoded_chapter() {
+        let text =
+            chapter("中文内容").replace("<html", "<?xml version=\"1.0\" encoding=\"GBK\"?><html");
+        let (gbk, _, _) = encoding_rs::GBK.encode(&text);
+        let mut writer = ZipWriter::new_to_memory().unwrap();
+        writer.write_container("OEBPS/content.opf").unwrap();
+        writer
+            .write_file("OEBPS/content.opf", PACKAGE.as_bytes())
+            .unwrap();
+        writer.write_file("OEBPS/c1.xhtml", &gbk).unwrap();
+        let mut book = Book::open_from_memory(writer.into_inner().unwrap().into_inner()).unwrap();
+
+        // the text is UTF-8 now and declared so
+        let utf8 = text.replace("GBK", "UTF-8");
+        let content = book.content("OEBPS/c1.xhtml").unwrap();
+        assert_eq!(content, utf8);
+        let chapter = book.chapters().next().unwrap().unwrap();
+        assert_eq!(chapter.content(), utf8);
+        let root = chapter.document().root().unwrap();
+        assert_eq!(xml::parse_all_text(&root), "中文内容中文内容");
+
+        // an edit written back as UTF-8 is read as it was written
+        let edited = content.replace("中文内容", "汉语内容");
+        book.replace_file("OEBPS/c1.xhtml", edited.clone().into_bytes());
+        assert_eq!(book.content("OEBPS/c1.xhtml").unwrap(), edited);
+        let mut copy = Book::open_from_memory(book.dump_to_memory().unwrap()).unwrap();
+        let chapter = copy.chapters().next().unwrap().unwrap();
+        let root = chapter.document().root().unwrap();
+        assert_eq!(xml::parse_all_text(&root), "汉语内容汉语内容");
+        let err = XMLDocument::try_new_from_entry(b"<p>\xff</p>", "OEBPS/c2.xhtml").err();
+        assert!(err.is_some_and(|e| e.to_string().contains("OEBPS/c2.xhtml")));
+    }
+
+    #[test]
+    fn test_cover() {
+        let image: &[u8] = b"\xff\xd8\xff\xe0";
+        let package = PACKAGE
+            .replace(
+                "</manifest>",
+                r#"<item id="page" href="text/cover.xhtml" media-type="application/xhtml+xml"/>
+<item id="img" href="images/front.jpg" media-type="image/jpeg"/>
+<item id="other" href="images/cover-back.png" media-type="image/png"/></manifest>"#,
+            )
+            .replace(
+                "</spine>",
+                r#"</spine><guide><reference type="cover" href="text/cover.xhtml"/></guide>"#,
+            );
+        let page = r#"<html xmlns="http://www.w3.org/1999/xhtml"><body><img src="../images/front.jpg"/></body></html>"#;
+        let files = [
+            ("OEBPS/content.opf", package.as_bytes()),
+            ("OEBPS/text/cover.xhtml", page.as_bytes()),
+            ("OEBPS/images/front.jpg", image),
+            ("OEBPS/images/cover-back.png", b"\x89PNG"),
+        ];
+        let mut book = Book::open_from_memory(write_book(&files)).unwrap();
+        let cover = book.cover().unwrap().unwrap();
+        assert_eq!(cover.path(), "OEBPS/images/front.jpg");
+        assert_eq!(cover.media_type(), "image/jpeg");
+        assert_eq!(cover.content(), image);
+
+        // the EPUB 2 meta wins over the guide
+        let meta = package.replace(
+            "</metadata>",
+            r#"<meta name="cover" content="other"/></metadata>"#,
+        );
+        let files = [
+            ("OEBPS/content.opf", meta.as_bytes()),
+            files[1],
+            files[2],
+            files[3],
+        ];
+        let mut book = Book::open_from_memory(write_book(&files)).unwrap();
+        assert_eq!(
+            book.cover().unwrap().unwrap().path(),
+            "OEBPS/images/cover-back.png"
+        );
+
+        // a file name is the last resort
+        let files = [
+            ("OEBPS/content.opf", package.as_bytes()),
+            files[2],
+            files[3],
+        ];
+        let mut book = Book::open_from_memory(write_book(&files)).unwrap();
+        assert_eq!(
+            book.cover().unwrap().unwrap().path(),
+            "OEBPS/images/cover-back.png"
+        );
+
+        // an encrypted cover is not skipped for the next candidate
+        let encryption = r#"<encryption xmlns="urn:oasis:names:tc:opendocument:xmlns:container" xmlns:enc="http://www.w3.org/2001/04/xmlenc#">
+<enc:EncryptedData><enc:EncryptionMethod Algorithm="http://www.w3.org/2001/04/xmlenc#aes128-cbc"/>
+<enc:CipherData><enc:CipherReference URI="OEBPS/images/front.jpg"/></enc:CipherData></enc:EncryptedData></encryption>"#;
+        let files = [
+            files[0],
+            ("OEBPS/text/cover.xhtml", page.as_bytes()),
+            ("OEBPS/images/front.jpg", image),
+            files[2],
+            ("META-INF/encryption.xml", encryption.as_bytes()),
+        ];
+        let mut book = Book::open_from_memory(write_book(&files)).unwrap();
+        assert!(matches!(book.cover(), Err(EpubError::DrmError(_))));
+
+        let mut book = Book::open_from_memory(source_book()).unwrap();
+        assert!(book.cover().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_reading_order() {
+        let package = PACKAGE
+            .replace(
+                r#"<itemref idref="c2"/>"#,
+                r#"<itemref idref="c2" linear="no"/><itemref idref="c3"/>"#,
+            )
+            .replace(
+                "</manifest>",
+                r#"<item id="nav" href="nav.xhtml" media-type="application/xhtml+xml" properties="nav"/>
+<item id="c3" href="c3.xhtml" media-type="application/xhtml+xml"/></manifest>"#,
+            );
+        let nav = r#"<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops"><body>
+<nav epub:type="toc"><ol>
+<li><a href="c1.xhtml">One</a><ol><li><a href="c1.xhtml#a">A</a></li><li><a href="c1.xhtml#b">B</a></li></ol></li>
+<li><a href="c3.xhtml">Three</a></li>
+</ol></nav></body></html>"#;
+        let c1 = r#"<html xmlns="http://www.w3.org/1999/xhtml"><body><h1 id="a">A</h1><p id="x">x</p><h1 id="b">B</h1></body></html>"#;
+        let mut book = Book::open_from_memory(write_book(&[
+            ("OEBPS/content.opf", package.as_str()),
+            ("OEBPS/nav.xhtml", nav),
+            ("OEBPS/c1.xhtml", c1),
+            ("OEBPS/c2.xhtml", chapter("two").as_str()),
+            ("OEBPS/c3.xhtml", chapter("three").as_str()),
+        ]))
+        .unwrap();
+        assert_eq!(book.spine_index("OEBPS/c3.xhtml#x"), Some(2));
+        assert_eq!(book.spine_index("OEBPS/missing.xhtml"), None);
+        assert_eq!(book.next_linear(0), Some(2));
+        assert_eq!(book.previous_linear(2), Some(0));
+        assert_eq!(book.next_linear(2), None);
+
+        let mut crumbs = |index: usize, fragment: Option<&str>| -> Vec<String> {
+            book.breadcrumb(index, fragment)
+                .unwrap()
+                .iter()
+                .map(|item| item.text().to_string())
+                .collect()
+        };
+        assert_eq!(crumbs(0, None), vec!["One"]);
+        assert_eq!(crumbs(0, Some("b")), vec!["One", "B"]);
+        assert_eq!(crumbs(0, Some("x")), vec!["One", "A"]);
+        assert_eq!(crumbs(1, None), vec!["One", "B"]);
+        let item = book.toc_item(2, None).unwrap().unwrap();
+        assert_eq!(item.text(), "Three");
+        assert_eq!(
+            book.spine_index(&book.resolve_path(item.href()).unwrap()),
+            Some(2)
+        );
     }
 }
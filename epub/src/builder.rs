@@ -0,0 +1,507 @@
+use std::fs;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use markup5ever_rcdom::NodeData;
+
+use crate::encryption::{Algorithm, EncryptedData, Encryption, ObfuscationKey, ENCRYPTION_PATH};
+use crate::error::{EpubError, Result};
+use crate::media_type;
+use crate::nav::{NavItem, Navigation};
+use crate::package::{ItemRef, ManifestItem, MetaItem, Package, NCX_MEDIA_TYPE};
+use crate::writer::{EpubWriter, Writer, ZipWriter};
+use crate::xml::{self, XMLDocument};
+
+pub const CONTENT_DIR: &str = "OEBPS";
+const PACKAGE_FILE: &str = "content.opf";
+const NAV_FILE: &str = "nav.xhtml";
+const NCX_FILE: &str = "toc.ncx";
+
+enum ChapterSource {
+    Content(String),
+    File(PathBuf),
+}
+
+struct BuilderChapter {
+    title: String,
+    source: ChapterSource,
+}
+
+struct Resource {
+    href: String,
+    content: Vec<u8>,
+}
+
+#[derive(Default)]
+pub struct BookBuilder {
+    title: Option<String>,
+    authors: Vec<String>,
+    language: Option<String>,
+    identifier: Option<String>,
+    modified: Option<String>,
+    chapters: Vec<BuilderChapter>,
+    stylesheets: Vec<Resource>,
+    images: Vec<Resource>,
+    cover: Option<Resource>,
+    fonts: Vec<Resource>,
+    obfuscate_fonts: bool,
+    ncx: bool,
+}
+
+// seconds since the unix epoch as `CCYY-MM-DDThh:mm:ssZ`
+fn format_timestamp(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+    // civil from days, http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+impl BookBuilder {
+    pub fn new() -> Self {
+        BookBuilder::default()
+    }
+
+    pub fn title(mut self, title: &str) -> Self {
+        self.title = Some(title.to_string());
+        self
+    }
+
+    pub fn author(mut self, author: &str) -> Self {
+        self.authors.push(author.to_string());
+        self
+    }
+
+    pub fn language(mut self, language: &str) -> Self {
+        self.language = Some(language.to_string());
+        self
+    }
+
+    pub fn identifier(mut self, identifier: &str) -> Self {
+        self.identifier = Some(identifier.to_string());
+        self
+    }
+
+    // dcterms:modified, defaults to the build time
+    pub fn modified(mut self, modified: &str) -> Self {
+        self.modified = Some(modified.to_string());
+        self
+    }
+
+    // a complete XHTML document, or a body fragment that gets wrapped into one
+    pub fn chapter(mut self, title: &str, content: &str) -> Self {
+        self.chapters.push(BuilderChapter {
+            title: title.to_string(),
+            source: ChapterSource::Content(content.to_string()),
+        });
+        self
+    }
+
+    pub fn chapter_file<T: AsRef<Path>>(mut self, title: &str, path: T) -> Self {
+        self.chapters.push(BuilderChapter {
+            title: title.to_string(),
+            source: ChapterSource::File(path.as_ref().into()),
+        });
+        self
+    }
+
+    pub fn stylesheet(mut self, href: &str, content: &[u8]) -> Self {
+        self.stylesheets.push(Resource {
+            href: href.to_string(),
+            content: content.to_vec(),
+        });
+        self
+    }
+
+    pub fn image(mut self, href: &str, content: &[u8]) -> Self {
+        self.images.push(Resource {
+            href: href.to_string(),
+            content: content.to_vec(),
+        });
+        self
+    }
+
+    pub fn cover(mut self, href: &str, content: &[u8]) -> Self {
+        self.cover = Some(Resource {
+            href: href.to_string(),
+            content: content.to_vec(),
+        });
+        self
+    }
+
+    pub fn font(mut self, href: &str, content: &[u8]) -> Self {
+        self.fonts.push(Resource {
+            href: href.to_string(),
+            content: content.to_vec(),
+        });
+        self
+    }
+
+    // obfuscate fonts with the IDPF algorithm, keyed by the identifier
+    pub fn obfuscate_fonts(mut self, obfuscate: bool) -> Self {
+        self.obfuscate_fonts = obfuscate;
+        self
+    }
+
+    // also write an EPUB 2 NCX for old reading systems
+    pub fn ncx(mut self, ncx: bool) -> Self {
+        self.ncx = ncx;
+        self
+    }
+
+    fn chapter_href(index: usize) -> String {
+        format!("chapter_{}.xhtml", index + 1)
+    }
+
+    fn required<'a>(value: &'a Option<String>, name: &str) -> Result<&'a str> {
+        value
+            .as_deref()
+            .ok_or(EpubError::FormatError(format!("{} is required", name)))
+    }
+
+    pub fn package(&self) -> Result<Package> {
+        let title = BookBuilder::required(&self.title, "title")?;
+        let language = BookBuilder::required(&self.language, "language")?;
+        let identifier = BookBuilder::required(&self.identifier, "identifier")?;
+        let modified = match self.modified {
+            Some(ref m) => m.clone(),
+            None => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or_default();
+                format_timestamp(now)
+            }
+        };
+
+        let path = format!("{}/{}", CONTENT_DIR, PACKAGE_FILE);
+        let mut package = Package::create(path.as_str(), "3.0");
+        package.set_unique_identifier("uid");
+        let metadata = package.metadata_mut();
+        metadata.add_item(MetaItem::new_dc("identifier", identifier).with_attribute("id", "uid"));
+        metadata.add_item(MetaItem::new_dc("title", title));
+        metadata.add_item(MetaItem::new_dc("language", language));
+        for (i, author) in self.authors.iter().enumerate() {
+            let id = format!("creator{}", i + 1);
+            metadata.add_item(MetaItem::new_dc("creator", author).with_attribute("id", &id));
+        }
+        metadata.add_item(MetaItem::new_meta("dcterms:modified", &modified));
+        if self.cover.is_some() {
+            // EPUB 2 reading systems find the cover through this meta
+            metadata.add_item(
+                MetaItem::new_opf("meta", "")
+                    .with_attribute("name", "cover")
+                    .with_attribute("content", "cover-image"),
+            );
+        }
+
+        package.add_manifest(
+            ManifestItem::new("nav", NAV_FILE, "application/xhtml+xml").with_properties("nav"),
+        );
+        if self.ncx {
+            package.add_manifest(ManifestItem::new("ncx", NCX_FILE, NCX_MEDIA_TYPE));
+            package.set_toc("ncx");
+        }
+        if let Some(ref cover) = self.cover {
+            package.add_manifest(
+                ManifestItem::new("cover-image", &cover.href, media_type::guess(&cover.href)?)
+                    .with_properties("cover-image"),
+            );
+        }
+        for (i, css) in self.stylesheets.iter().enumerate() {
+            let id = format!("css{}", i + 1);
+            package.add_manifest(ManifestItem::new(
+                &id,
+                &css.href,
+                media_type::guess(&css.href)?,
+            ));
+        }
+        for (i, image) in self.images.iter().enumerate() {
+            let id = format!("image{}", i + 1);
+            package.add_manifest(ManifestItem::new(
+                &id,
+                &image.href,
+                media_type::guess(&image.href)?,
+            ));
+        }
+        for (i, font) in self.fonts.iter().enumerate() {
+            let id = format!("font{}", i + 1);
+            package.add_manifest(ManifestItem::new(
+                &id,
+                &font.href,
+                media_type::guess(&font.href)?,
+            ));
+        }
+        for i in 0..self.chapters.len() {
+            let id = format!("chapter{}", i + 1);
+            let href = BookBuilder::chapter_href(i);
+            package.add_manifest(ManifestItem::new(&id, &href, "application/xhtml+xml"));
+            package.add_spine(ItemRef::new(&id));
+        }
+        Ok(package)
+    }
+
+    pub fn navigation(&self) -> Navigation {
+        let toc = self
+            .chapters
+            .iter()
+            .enumerate()
+            .map(|(i, chapter)| {
+                NavItem::new(&chapter.title, &BookBuilder::chapter_href(i), Vec::new())
+            })
+            .collect();
+        Navigation::new(self.title.as_deref().unwrap_or_default(), toc)
+    }
+
+    // a full document has a prolog or an `html` document element, anything else is body content
+    fn is_document(content: &str) -> bool {
+        // text outside of the document element is dropped by the parser
+        if !content.trim_start().starts_with('<') {
+            return false;
+        }
+        let doc = XMLDocument::new_from_str(content);
+        for child in doc.document().children.borrow().iter() {
+            match child.data {
+                NodeData::Doctype { .. } => return true,
+                NodeData::ProcessingInstruction { ref target, .. } if &**target == "xml" => {
+                    return true
+                }
+                NodeData::Element { ref name, .. } => {
+                    return name.local.as_ref().eq_ignore_ascii_case("html")
+                }
+                NodeData::Text { ref contents } if !contents.borrow().trim().is_empty() => {
+                    return false
+                }
+                _ => {}
+            }
+        }
+        false
+    }
+
+    fn chapter_content(&self, chapter: &BuilderChapter) -> Result<String> {
+        let content = match chapter.source {
+            ChapterSource::Content(ref c) => c.clone(),
+            ChapterSource::File(ref path) => fs::read_to_string(path).map_err(|e| {
+                EpubError::ReaderError(format!("read file:{:?} error:{:?}", path, e))
+            })?,
+        };
+        if BookBuilder::is_document(&content) {
+            return Ok(content);
+        }
+        let mut res = String::new();
+        res.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<!DOCTYPE html>\n");
+        res.push_str(&format!(
+            "<html xmlns=\"http://www.w3.org/1999/xhtml\" xmlns:epub=\"http://www.idpf.org/2007/ops\" xml:lang=\"{}\">\n",
+            xml::escape(self.language.as_deref().unwrap_or_default())
+        ));
+        res.push_str(&format!(
+            "<head>\n  <title>{}</title>\n",
+            xml::escape(&chapter.title)
+        ));
+        for css in self.stylesheets.iter() {
+            res.push_str(&format!(
+                "  <link rel=\"stylesheet\" type=\"text/css\" href=\"{}\"/>\n",
+                xml::escape(&css.href)
+            ));
+        }
+        res.push_str(&format!("</head>\n<body>\n{}\n</body>\n</html>\n", content));
+        Ok(res)
+    }
+
+    pub fn build(&self, writer: &mut dyn EpubWriter) -> Result<()> {
+        let package = self.package()?;
+        let navigation = self.navigation();
+        let resolve = |href: &str| format!("{}/{}", CONTENT_DIR, href);
+
+        writer.write_container(package.path())?;
+        writer.write_file(package.path(), package.to_bytes()?.as_slice())?;
+        writer.write_file(&resolve(NAV_FILE), navigation.to_nav_xml().as_bytes())?;
+        if self.ncx {
+            let uid = self.identifier.as_deref().unwrap_or_default();
+            writer.write_file(&resolve(NCX_FILE), navigation.to_ncx_xml(uid).as_bytes())?;
+        }
+        for resource in self
+            .cover
+            .iter()
+            .chain(self.stylesheets.iter())
+            .chain(self.images.iter())
+        {
+            writer.write_file(&resolve(&resource.href), resource.content.as_slice())?;
+        }
+        let mut encryption = Encryption::default();
+        let key = ObfuscationKey::new(&package);
+        for font in self.fonts.iter() {
+            let path = resolve(&font.href);
+            let mut content = font.content.clone();
+            if self.obfuscate_fonts {
+                let data = EncryptedData::new(&path, Algorithm::Idpf);
+                key.apply(&data, content.as_mut_slice())?;
+                encryption.add(data);
+            }
+            writer.write_file(&path, content.as_slice())?;
+        }
+        if !encryption.is_empty() {
+            writer.write_file(ENCRYPTION_PATH, encryption.to_xml().as_bytes())?;
+        }
+        for (i, chapter) in self.chapters.iter().enumerate() {
+            let content = self.chapter_content(chapter)?;
+            writer.write_file(&resolve(&BookBuilder::chapter_href(i)), content.as_bytes())?;
+        }
+        writer.finish()
+    }
+
+    pub fn build_to_memory(&self) -> Result<Vec<u8>> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()))?;
+        self.build(&mut writer)?;
+        Ok(writer.into_inner()?.into_inner())
+    }
+
+    pub fn build_to_path<T: AsRef<Path>>(&self, path: T) -> Result<()> {
+        let mut writer = Writer::new_from_path(path.as_ref().into())?;
+        self.build(&mut writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read};
+
+    use zip::ZipArchive;
+
+    use crate::book::Book;
+    use crate::builder::{format_timestamp, BookBuilder};
+
+    fn builder() -> BookBuilder {
+        BookBuilder::new()
+            .title("Built & Tested")
+            .author("Jane Doe")
+            .author("John Doe")
+            .language("en")
+            .identifier("urn:uuid:0c9c3e5a-8a3f-4d0c-9d0e-3f8f1a2b3c4d")
+            .modified("2024-02-03T04:05:06Z")
+            .stylesheet("style.css", b"p { margin: 0 }")
+            .image("images/dot.png", b"\x89PNG")
+            .cover("images/cover.jpg", b"\xff\xd8\xff")
+            .chapter("One", "<p>first</p>")
+            .chapter("Two", "<p>second</p>")
+            .ncx(true)
+    }
+
+    #[test]
+    fn test_build() {
+        let builder = builder();
+        let buffer = builder.build_to_memory().unwrap();
+        let mut book = Book::open_from_memory(buffer).unwrap();
+
+        assert_eq!(book.title(), "Built & Tested");
+        assert_eq!(book.nav().unwrap().toc(), builder.navigation().toc());
+        let package = book.package();
+        assert_eq!(package.version(), "3.0");
+        assert_eq!(package.spine().len(), 2);
+        assert_eq!(package.toc(), Some("ncx"));
+        assert_eq!(
+            package.manifest().len(),
+            builder.package().unwrap().manifest().len()
+        );
+        let cover = package.get_manifest("cover-image").unwrap();
+        assert_eq!(cover.media_type(), "image/jpeg");
+
+        let chapter = book.content("OEBPS/chapter_1.xhtml").unwrap();
+        assert!(chapter.contains("<p>first</p>"));
+        assert!(chapter.contains(r#"href="style.css""#));
+        let ncx = book.content("OEBPS/toc.ncx").unwrap();
+        assert!(ncx.contains(r#"<content src="chapter_2.xhtml"/>"#));
+        assert_eq!(
+            book.read_binary_file("OEBPS/images/dot.png").unwrap(),
+            b"\x89PNG"
+        );
+    }
+
+    #[test]
+    fn test_is_document() {
+        assert!(BookBuilder::is_document("<html><body/></html>"));
+        assert!(BookBuilder::is_document(
+            "<!-- generated -->\n<HTML><BODY/></HTML>"
+        ));
+        assert!(BookBuilder::is_document(
+            "<?xml version=\"1.0\"?>\n<!DOCTYPE html>\n<HTML/>"
+        ));
+        assert!(BookBuilder::is_document("<!DOCTYPE html>"));
+        assert!(!BookBuilder::is_document("<p>the <html> element</p>"));
+        assert!(!BookBuilder::is_document("<!-- <html> -->\n<p>text</p>"));
+        assert!(!BookBuilder::is_document("plain <html> text"));
+    }
+
+    #[test]
+    fn test_build_fonts() {
+        let font: Vec<u8> = (0..2000).map(|i| (i % 251) as u8).collect();
+        let buffer = builder()
+            .font("fonts/a.otf", &font)
+            .obfuscate_fonts(true)
+            .build_to_memory()
+            .unwrap();
+
+        let mut archive = ZipArchive::new(Cursor::new(buffer.clone())).unwrap();
+        let mut raw = Vec::new();
+        archive
+            .by_name("OEBPS/fonts/a.otf")
+            .unwrap()
+            .read_to_end(&mut raw)
+            .unwrap();
+        assert_ne!(raw[..1040], font[..1040]);
+        assert_eq!(raw[1040..], font[1040..]);
+
+        let mut book = Book::open_from_memory(buffer).unwrap();
+        assert_eq!(book.encryption().unwrap().items().len(), 1);
+        assert_eq!(book.read_binary_file("OEBPS/fonts/a.otf").unwrap(), font);
+        let mut stream = book.open_file("OEBPS/fonts/a.otf").unwrap();
+        let mut streamed = Vec::new();
+        let mut chunk = [0; 7];
+        loop {
+            let n = stream.read(&mut chunk).unwrap();
+            if n == 0 {
+                break;
+            }
+            streamed.extend_from_slice(&chunk[..n]);
+        }
+        drop(stream);
+        assert_eq!(streamed, font);
+
+        // a new identifier means a new key
+        let identifier = book.metadata_mut().identifier("uid").unwrap().to_string();
+        assert!(identifier.starts_with("urn:uuid:"));
+        book.metadata_mut()
+            .set_item("identifier", "urn:isbn:9780000000001");
+        let mut copy = Book::open_from_memory(book.dump_to_memory().unwrap()).unwrap();
+        assert_eq!(copy.read_binary_file("OEBPS/fonts/a.otf").unwrap(), font);
+    }
+
+    #[test]
+    fn test_build_required() {
+        let builder = BookBuilder::new().title("No Identifier").language("en");
+        assert!(builder.build_to_memory().is_err());
+    }
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0), "1970-01-01T00:00:00Z");
+        assert_eq!(format_timestamp(1709251199), "2024-02-29T23:59:59Z");
+    }
+}
@@ -1,27 +1,71 @@
+use std::cell::OnceCell;
+
+use crate::package::SpineProperty;
+use crate::xml::XMLDocument;
+
 pub struct Chapter {
-    index: usize,
-    href: String,
-    content: String,
+    pub(crate) index: usize,
+    pub(crate) id: String,
+    pub(crate) href: String,
+    pub(crate) path: String,
+    pub(crate) media_type: String,
+    pub(crate) linear: bool,
+    pub(crate) properties: Vec<SpineProperty>,
+    pub(crate) titles: Vec<String>,
+    pub(crate) content: String,
+    pub(crate) document: OnceCell<XMLDocument>,
 }
 
 impl Chapter {
-    pub fn new(index: usize, href: &str, content: String) -> Self {
-        Chapter {
-            index,
-            href: href.to_string(),
-            content,
-        }
-    }
-
+    // the spine index
     pub fn index(&self) -> usize {
         self.index
     }
 
+    // the manifest id, of the fallback item for foreign spine items
+    pub fn id(&self) -> &str {
+        self.id.as_str()
+    }
+
+    // relative to the package document
     pub fn href(&self) -> &str {
         self.href.as_str()
     }
 
+    // the path in the container
+    pub fn path(&self) -> &str {
+        self.path.as_str()
+    }
+
+    pub fn media_type(&self) -> &str {
+        self.media_type.as_str()
+    }
+
+    pub fn is_linear(&self) -> bool {
+        self.linear
+    }
+
+    pub fn properties(&self) -> &[SpineProperty] {
+        self.properties.as_slice()
+    }
+
+    // labels of the table of contents entries pointing at this chapter
+    pub fn titles(&self) -> &[String] {
+        self.titles.as_slice()
+    }
+
+    pub fn title(&self) -> Option<&str> {
+        self.titles.first().map(|title| title.as_str())
+    }
+
+    // read and decoded to UTF-8 when the chapter is created
     pub fn content(&self) -> &str {
         self.content.as_str()
     }
+
+    // parsed from `content` on first use, the declared encoding was already applied
+    pub fn document(&self) -> &XMLDocument {
+        self.document
+            .get_or_init(|| XMLDocument::new_from_str(&self.content))
+    }
 }
@@ -2,8 +2,12 @@ use std::borrow::Borrow;
 
 use crate::error::EpubError;
 use crate::error::Result;
+use crate::repair::{Repair, WarningKind};
 use crate::xml::{self, XMLDocument};
 
+pub const CONTAINER_PATH: &str = "META-INF/container.xml";
+pub const PACKAGE_MEDIA_TYPE: &str = "application/oebps-package+xml";
+
 #[derive(Debug)]
 #[allow(dead_code)]
 pub struct RootFile {
@@ -22,16 +26,33 @@ pub(crate) struct Container {
     root_files: Vec<RootFile>,
 }
 
-fn parse_root_files(doc: &XMLDocument) -> Result<Vec<RootFile>> {
+fn parse_root_files(doc: &XMLDocument, repair: &mut Repair) -> Result<Vec<RootFile>> {
     let rootfiles = doc.find_all_tag("rootfile");
     let mut files = Vec::new();
     for rootfile in rootfiles {
-        let full_path = xml::parse_attribute_must_exist(&rootfile, "full-path")?;
-        let media_type = xml::parse_attribute_must_exist(&rootfile, "media-type")?;
-        if media_type != "application/oebps-package+xml" {
-            return Err(EpubError::FormatError(
+        let full_path = match xml::parse_attribute_must_exist(&rootfile, "full-path") {
+            Ok(full_path) => full_path,
+            Err(e) => {
+                repair.recover(
+                    WarningKind::InvalidContainer,
+                    CONTAINER_PATH,
+                    "rootfile skipped",
+                    e,
+                )?;
+                continue;
+            }
+        };
+        let media_type = xml::parse_attribute(&rootfile, "media-type").unwrap_or_default();
+        if media_type != PACKAGE_MEDIA_TYPE {
+            let e = EpubError::FormatError(
                 "media_type must be applicatioin/oebps-package+xml".to_string(),
-            ));
+            );
+            let context = format!("rootfile {:?}", full_path);
+            repair.recover(WarningKind::InvalidContainer, CONTAINER_PATH, &context, e)?;
+            // other renditions, like a pdf, are listed with their own media type
+            if !full_path.to_ascii_lowercase().ends_with(".opf") {
+                continue;
+            }
         }
         files.push(RootFile {
             full_path,
@@ -41,24 +62,64 @@ fn parse_root_files(doc: &XMLDocument) -> Result<Vec<RootFile>> {
     Ok(files)
 }
 
-fn parse_version(doc: &XMLDocument) -> Result<String> {
+fn parse_version(doc: &XMLDocument, repair: &mut Repair) -> Result<String> {
     let container = doc.find_tag("container").ok_or(EpubError::ContainerError(
         "container not in container.xml file".to_string(),
     ))?;
-    xml::parse_attribute_must_exist(&container, "version")
+    match xml::parse_attribute_must_exist(&container, "version") {
+        Ok(version) => Ok(version),
+        Err(e) => {
+            repair.recover(
+                WarningKind::InvalidContainer,
+                CONTAINER_PATH,
+                "container version",
+                e,
+            )?;
+            Ok("1.0".to_string())
+        }
+    }
 }
 
 impl Container {
-    pub fn new(content: &[u8]) -> Result<Self> {
-        let doc = XMLDocument::try_new(content)?;
-        let version = parse_version(&doc)?;
-        let root_files = parse_root_files(&doc)?;
+    pub fn new(content: &[u8], repair: &mut Repair) -> Result<Self> {
+        let doc = XMLDocument::try_new_from_entry(content, CONTAINER_PATH)?;
+        let version = parse_version(&doc, repair)?;
+        let root_files = parse_root_files(&doc, repair)?;
         Ok(Container {
             version,
             root_files,
         })
     }
 
+    pub fn new_with_package(full_path: &str) -> Self {
+        Container {
+            version: "1.0".to_string(),
+            root_files: vec![RootFile {
+                full_path: full_path.to_string(),
+                media_type: PACKAGE_MEDIA_TYPE.to_string(),
+            }],
+        }
+    }
+
+    pub fn to_xml(&self) -> String {
+        let mut content = String::new();
+        content.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        content.push_str(&format!(
+            "<container version=\"{}\" xmlns=\"urn:oasis:names:tc:opendocument:xmlns:container\">\n",
+            xml::escape(&self.version)
+        ));
+        content.push_str("  <rootfiles>\n");
+        for rootfile in self.root_files.iter() {
+            content.push_str(&format!(
+                "    <rootfile full-path=\"{}\" media-type=\"{}\"/>\n",
+                xml::escape(&rootfile.full_path),
+                xml::escape(&rootfile.media_type)
+            ));
+        }
+        content.push_str("  </rootfiles>\n</container>\n");
+        content
+    }
+
     pub fn full_path(&self) -> Option<String> {
         if self.root_files.is_empty() {
             return None;
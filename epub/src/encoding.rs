@@ -0,0 +1,185 @@
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8};
+
+use crate::error::{EpubError, Result};
+
+// declarations are only searched for at the start of a document
+const SNIFF_LEN: usize = 1024;
+
+// the encoding from a BOM, the XML declaration or an HTML `<meta charset>`, UTF-8 otherwise
+pub fn detect(content: &[u8]) -> &'static Encoding {
+    if let Some((encoding, _)) = Encoding::for_bom(content) {
+        return encoding;
+    }
+    // `<?` of a UTF-16 XML declaration without BOM
+    if content.starts_with(&[0x3c, 0x00, 0x3f, 0x00]) {
+        return UTF_16LE;
+    }
+    if content.starts_with(&[0x00, 0x3c, 0x00, 0x3f]) {
+        return UTF_16BE;
+    }
+    let head =
+        String::from_utf8_lossy(&content[..content.len().min(SNIFF_LEN)]).to_ascii_lowercase();
+    let label = xml_declaration(&head).or_else(|| meta_charset(&head));
+    match label.and_then(|label| Encoding::for_label(label.trim().as_bytes())) {
+        // a declaration that could be read as ASCII is not UTF-16
+        Some(encoding) if encoding == UTF_16LE || encoding == UTF_16BE => UTF_8,
+        Some(encoding) => encoding,
+        None => UTF_8,
+    }
+}
+
+// transcode to UTF-8 and declare it, `name` is only used in the error message
+pub fn decode(content: &[u8], name: &str) -> Result<String> {
+    let (encoding, bom) = match Encoding::for_bom(content) {
+        Some((encoding, len)) => (encoding, len),
+        None => (detect(content), 0),
+    };
+    let mut text = encoding
+        .decode_without_bom_handling_and_without_replacement(&content[bom..])
+        .map(|text| text.into_owned())
+        .ok_or(EpubError::EncodingError(format!(
+            "{:?} is not valid {}",
+            name,
+            encoding.name()
+        )))?;
+    if encoding != UTF_8 {
+        declare_utf8(&mut text);
+    }
+    Ok(text)
+}
+
+// the XML declaration names the encoding the text was decoded from, written back it is UTF-8
+fn declare_utf8(text: &mut String) {
+    if !text.starts_with("<?xml") {
+        return;
+    }
+    let Some(end) = text.find("?>") else {
+        return;
+    };
+    let declaration = &text[..end];
+    let label = attribute_value(&declaration["<?xml".len()..], "encoding");
+    let (Some(label), Some(key)) = (label, declaration.find("encoding")) else {
+        return;
+    };
+    let start = key + declaration[key..].find(label).unwrap_or_default();
+    text.replace_range(start..start + label.len(), "UTF-8");
+}
+
+// attributes of a start tag without the tag name, values may be unquoted
+fn attribute_value<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let mut rest = tag;
+    loop {
+        rest = rest.trim_start_matches(|c: char| c.is_ascii_whitespace() || c == '/');
+        if rest.is_empty() {
+            return None;
+        }
+        let end = rest
+            .find(|c: char| c.is_ascii_whitespace() || c == '=')
+            .unwrap_or(rest.len());
+        let key = &rest[..end];
+        rest = rest[end..].trim_start();
+        if !rest.starts_with('=') {
+            continue;
+        }
+        rest = rest[1..].trim_start();
+        let value = match rest.chars().next() {
+            Some(quote) if quote == '"' || quote == '\'' => {
+                let end = rest[1..].find(quote)? + 1;
+                let value = &rest[1..end];
+                rest = &rest[end + 1..];
+                value
+            }
+            _ => {
+                let end = rest
+                    .find(|c: char| c.is_ascii_whitespace())
+                    .unwrap_or(rest.len());
+                let value = &rest[..end];
+                rest = &rest[end..];
+                value
+            }
+        };
+        if key == name {
+            return Some(value);
+        }
+    }
+}
+
+fn xml_declaration(head: &str) -> Option<&str> {
+    let head = head.trim_start();
+    if !head.starts_with("<?xml") {
+        return None;
+    }
+    let end = head.find("?>")?;
+    attribute_value(&head["<?xml".len()..end], "encoding")
+}
+
+// `<meta charset="...">` or `<meta http-equiv="Content-Type" content="text/html; charset=...">`
+fn meta_charset(head: &str) -> Option<&str> {
+    let mut rest = head;
+    while let Some(i) = rest.find("<meta") {
+        rest = &rest[i + "<meta".len()..];
+        let tag = &rest[..rest.find('>').unwrap_or(rest.len())];
+        if let Some(charset) = attribute_value(tag, "charset") {
+            return Some(charset);
+        }
+        let content = attribute_value(tag, "content").unwrap_or_default();
+        if let Some(i) = content.find("charset=") {
+            let charset = &content[i + "charset=".len()..];
+            return Some(charset.split(';').next().unwrap_or_default());
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use encoding_rs::{GBK, SHIFT_JIS, UTF_16BE, UTF_16LE, UTF_8};
+
+    use crate::encoding::{decode, detect};
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(detect(b"<html/>"), UTF_8);
+        assert_eq!(
+            detect(b"\xef\xbb\xbf<?xml version=\"1.0\" encoding=\"GBK\"?>"),
+            UTF_8
+        );
+        assert_eq!(detect(b"\xff\xfe<\x00"), UTF_16LE);
+        assert_eq!(detect(b"\x00<\x00?\x00x"), UTF_16BE);
+        assert_eq!(
+            detect(b"<?xml version='1.0' encoding='gb2312'?><html/>"),
+            GBK
+        );
+        assert_eq!(
+            detect(b"<html><head><meta charset=Shift_JIS></head></html>"),
+            SHIFT_JIS
+        );
+        assert_eq!(
+            detect(b"<html><head><meta http-equiv=\"Content-Type\" content=\"text/html; charset=GBK\"/></head></html>"),
+            GBK
+        );
+        assert_eq!(
+            detect(b"<?xml version=\"1.0\" encoding=\"UTF-16\"?>"),
+            UTF_8
+        );
+    }
+
+    #[test]
+    fn test_decode() {
+        let text = "<?xml version=\"1.0\" encoding=\"GBK\"?><p>中文</p>";
+        let (bytes, _, _) = GBK.encode(text);
+        assert_eq!(
+            decode(&bytes, "gbk.xhtml").unwrap(),
+            text.replace("GBK", "UTF-8")
+        );
+
+        let mut bytes = vec![0xff, 0xfe];
+        for unit in "<p>日本語</p>".encode_utf16() {
+            bytes.extend(unit.to_le_bytes());
+        }
+        assert_eq!(decode(&bytes, "utf16.xhtml").unwrap(), "<p>日本語</p>");
+
+        let err = decode(b"<p>\xc3\x28</p>", "broken.xhtml").unwrap_err();
+        assert!(err.to_string().contains("broken.xhtml"));
+    }
+}
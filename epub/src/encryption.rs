@@ -0,0 +1,370 @@
+use std::io::Read;
+use std::rc::Rc;
+
+use markup5ever_rcdom::Node;
+
+use crate::error::{EpubError, Result};
+use crate::href;
+use crate::package::Package;
+use crate::xml::{self, XMLDocument};
+
+pub const ENCRYPTION_PATH: &str = "META-INF/encryption.xml";
+pub const IDPF_OBFUSCATION: &str = "http://www.idpf.org/2008/embedding";
+pub const ADOBE_OBFUSCATION: &str = "http://ns.adobe.com/pdf/enc#RC";
+
+// number of obfuscated bytes at the start of a resource
+const IDPF_LENGTH: usize = 1040;
+const ADOBE_LENGTH: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Algorithm {
+    Idpf,
+    Adobe,
+    // real encryption, resources can't be read without the DRM key
+    Other(String),
+}
+
+impl Algorithm {
+    pub fn new(uri: &str) -> Self {
+        match uri.trim() {
+            IDPF_OBFUSCATION => Algorithm::Idpf,
+            ADOBE_OBFUSCATION => Algorithm::Adobe,
+            other => Algorithm::Other(other.to_string()),
+        }
+    }
+
+    pub fn uri(&self) -> &str {
+        match self {
+            Algorithm::Idpf => IDPF_OBFUSCATION,
+            Algorithm::Adobe => ADOBE_OBFUSCATION,
+            Algorithm::Other(uri) => uri,
+        }
+    }
+
+    pub fn is_obfuscation(&self) -> bool {
+        !matches!(self, Algorithm::Other(_))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct EncryptedData {
+    id: Option<String>,
+    algorithm: Algorithm,
+    uri: String,
+    key_name: Option<String>,
+    compression: Option<String>,
+    original_length: Option<u64>,
+}
+
+impl EncryptedData {
+    pub fn new(uri: &str, algorithm: Algorithm) -> Self {
+        EncryptedData {
+            id: None,
+            algorithm,
+            uri: uri.to_string(),
+            key_name: None,
+            compression: None,
+            original_length: None,
+        }
+    }
+
+    fn parse(node: &Rc<Node>) -> Result<Self> {
+        let algorithm = xml::find_descendant(node, "EncryptionMethod")
+            .and_then(|method| xml::parse_attribute(&method, "Algorithm"))
+            .ok_or(EpubError::FormatError(
+                "EncryptedData without EncryptionMethod".to_string(),
+            ))?;
+        let reference = xml::find_descendant(node, "CipherReference").ok_or(
+            EpubError::FormatError("EncryptedData without CipherReference".to_string()),
+        )?;
+        let compression = xml::find_descendant(node, "Compression");
+        Ok(EncryptedData {
+            id: xml::parse_attribute(node, "Id"),
+            algorithm: Algorithm::new(&algorithm),
+            uri: xml::parse_attribute_must_exist(&reference, "URI")?,
+            key_name: xml::find_descendant(node, "KeyName")
+                .map(|name| xml::parse_all_text(&name).trim().to_string()),
+            compression: compression
+                .as_ref()
+                .and_then(|c| xml::parse_attribute(c, "Method")),
+            original_length: compression
+                .as_ref()
+                .and_then(|c| xml::parse_attribute(c, "OriginalLength"))
+                .and_then(|v| v.trim().parse().ok()),
+        })
+    }
+
+    pub fn id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+
+    pub fn algorithm(&self) -> &Algorithm {
+        &self.algorithm
+    }
+
+    // path of the resource in the container
+    pub fn uri(&self) -> &str {
+        &self.uri
+    }
+
+    pub fn key_name(&self) -> Option<&str> {
+        self.key_name.as_deref()
+    }
+
+    // `8` when the resource was deflated before it was encrypted
+    pub fn compression(&self) -> Option<&str> {
+        self.compression.as_deref()
+    }
+
+    pub fn original_length(&self) -> Option<u64> {
+        self.original_length
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Encryption {
+    items: Vec<EncryptedData>,
+}
+
+impl Encryption {
+    pub fn new(content: &[u8]) -> Result<Self> {
+        let doc = XMLDocument::try_new_from_entry(content, ENCRYPTION_PATH)?;
+        let items = doc
+            .find_all_tag("EncryptedData")
+            .iter()
+            .map(EncryptedData::parse)
+            .collect::<Result<Vec<EncryptedData>>>()?;
+        Ok(Encryption { items })
+    }
+
+    pub fn items(&self) -> &[EncryptedData] {
+        self.items.as_slice()
+    }
+
+    // `path` is in the container, uris are percent-encoded and relative to the container root
+    pub fn get(&self, path: &str) -> Option<&EncryptedData> {
+        self.items
+            .iter()
+            .find(|item| href::resolve("", &item.uri).is_ok_and(|href| href.path() == path))
+    }
+
+    pub fn add(&mut self, data: EncryptedData) {
+        self.items.push(data);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    // true when some resource needs a key the book doesn't contain
+    pub fn has_drm(&self) -> bool {
+        self.items
+            .iter()
+            .any(|item| !item.algorithm.is_obfuscation())
+    }
+
+    pub fn to_xml(&self) -> String {
+        let mut res = String::new();
+        res.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        res.push_str("<encryption xmlns=\"urn:oasis:names:tc:opendocument:xmlns:container\" xmlns:enc=\"http://www.w3.org/2001/04/xmlenc#\" xmlns:ds=\"http://www.w3.org/2000/09/xmldsig#\">\n");
+        for item in self.items.iter() {
+            match item.id {
+                Some(ref id) => res.push_str(&format!(
+                    "  <enc:EncryptedData Id=\"{}\">\n",
+                    xml::escape(id)
+                )),
+                None => res.push_str("  <enc:EncryptedData>\n"),
+            }
+            res.push_str(&format!(
+                "    <enc:EncryptionMethod Algorithm=\"{}\"/>\n",
+                xml::escape(item.algorithm.uri())
+            ));
+            if let Some(ref name) = item.key_name {
+                res.push_str(&format!(
+                    "    <ds:KeyInfo><ds:KeyName>{}</ds:KeyName></ds:KeyInfo>\n",
+                    xml::escape(name)
+                ));
+            }
+            res.push_str(&format!(
+                "    <enc:CipherData><enc:CipherReference URI=\"{}\"/></enc:CipherData>\n",
+                xml::escape(&item.uri)
+            ));
+            res.push_str("  </enc:EncryptedData>\n");
+        }
+        res.push_str("</encryption>\n");
+        res
+    }
+}
+
+// keys of the two font obfuscation algorithms, derived from the package identifiers
+#[derive(Debug, Default, Clone)]
+pub struct ObfuscationKey {
+    idpf: Option<[u8; 20]>,
+    adobe: Option<[u8; 16]>,
+}
+
+impl ObfuscationKey {
+    // the IDPF key is the SHA-1 of the unique identifier, the Adobe key is the first UUID identifier
+    pub fn new(package: &Package) -> Self {
+        let metadata = package.metadata();
+        let unique = package
+            .unique_identifier()
+            .and_then(|id| metadata.identifier(id));
+        let adobe = unique.and_then(adobe_key).or_else(|| {
+            metadata
+                .identifiers()
+                .iter()
+                .find_map(|i| adobe_key(i.value()))
+        });
+        ObfuscationKey {
+            idpf: unique.map(idpf_key),
+            adobe,
+        }
+    }
+
+    // the key bytes and the number of bytes they cover
+    fn key(&self, data: &EncryptedData) -> Result<(&[u8], usize)> {
+        match data.algorithm {
+            Algorithm::Idpf => self
+                .idpf
+                .as_ref()
+                .map(|key| (key.as_slice(), IDPF_LENGTH))
+                .ok_or(EpubError::FormatError(format!(
+                    "no unique identifier to de-obfuscate {:?}",
+                    data.uri
+                ))),
+            Algorithm::Adobe => self
+                .adobe
+                .as_ref()
+                .map(|key| (key.as_slice(), ADOBE_LENGTH))
+                .ok_or(EpubError::FormatError(format!(
+                    "no uuid identifier to de-obfuscate {:?}",
+                    data.uri
+                ))),
+            Algorithm::Other(ref uri) => Err(EpubError::DrmError(format!(
+                "{:?} is encrypted with {}",
+                data.uri, uri
+            ))),
+        }
+    }
+
+    // XOR is its own inverse, the same call obfuscates and de-obfuscates
+    pub fn apply(&self, data: &EncryptedData, content: &mut [u8]) -> Result<()> {
+        let (key, length) = self.key(data)?;
+        for (i, byte) in content.iter_mut().take(length).enumerate() {
+            *byte ^= key[i % key.len()];
+        }
+        Ok(())
+    }
+
+    pub fn reader<'a>(
+        &self,
+        data: &EncryptedData,
+        inner: Box<dyn Read + 'a>,
+    ) -> Result<Box<dyn Read + 'a>> {
+        let (key, length) = self.key(data)?;
+        Ok(Box::new(ObfuscatedReader {
+            inner,
+            key: key.to_vec(),
+            length,
+            position: 0,
+        }))
+    }
+}
+
+struct ObfuscatedReader<R> {
+    inner: R,
+    key: Vec<u8>,
+    length: usize,
+    position: usize,
+}
+
+impl<R: Read> Read for ObfuscatedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        for byte in buf[..n].iter_mut() {
+            if self.position >= self.length {
+                break;
+            }
+            *byte ^= self.key[self.position % self.key.len()];
+            self.position += 1;
+        }
+        Ok(n)
+    }
+}
+
+fn idpf_key(identifier: &str) -> [u8; 20] {
+    let identifier: String = identifier
+        .chars()
+        .filter(|c| !matches!(c, ' ' | '\t' | '\r' | '\n'))
+        .collect();
+    sha1_smol::Sha1::from(identifier.as_bytes())
+        .digest()
+        .bytes()
+}
+
+fn adobe_key(identifier: &str) -> Option<[u8; 16]> {
+    let identifier = identifier.trim();
+    let uuid = identifier.strip_prefix("urn:uuid:").unwrap_or(identifier);
+    let hex: Vec<u8> = uuid.bytes().filter(|b| *b != b'-').collect();
+    if hex.len() != 32 {
+        return None;
+    }
+    let mut key = [0; 16];
+    for (i, pair) in hex.chunks(2).enumerate() {
+        let pair = std::str::from_utf8(pair).ok()?;
+        key[i] = u8::from_str_radix(pair, 16).ok()?;
+    }
+    Some(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::encryption::{adobe_key, idpf_key, Algorithm, EncryptedData, Encryption};
+
+    const ENCRYPTION: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<encryption xmlns="urn:oasis:names:tc:opendocument:xmlns:container" xmlns:enc="http://www.w3.org/2001/04/xmlenc#" xmlns:ds="http://www.w3.org/2000/09/xmldsig#">
+  <enc:EncryptedData>
+    <enc:EncryptionMethod Algorithm="http://www.idpf.org/2008/embedding"/>
+    <enc:CipherData><enc:CipherReference URI="OEBPS/fonts/a.otf"/></enc:CipherData>
+  </enc:EncryptedData>
+  <enc:EncryptedData Id="ed2">
+    <enc:EncryptionMethod Algorithm="http://www.w3.org/2001/04/xmlenc#aes128-cbc"/>
+    <ds:KeyInfo><ds:KeyName>urn:uuid:key</ds:KeyName></ds:KeyInfo>
+    <enc:CipherData><enc:CipherReference URI="OEBPS/c1.xhtml"/></enc:CipherData>
+    <enc:EncryptionProperties><enc:EncryptionProperty>
+      <Compression xmlns="http://www.idpf.org/2016/encryption#compression" Method="8" OriginalLength="1024"/>
+    </enc:EncryptionProperty></enc:EncryptionProperties>
+  </enc:EncryptedData>
+</encryption>"#;
+
+    #[test]
+    fn test_parse() {
+        let encryption = Encryption::new(ENCRYPTION.as_bytes()).unwrap();
+        assert_eq!(encryption.items().len(), 2);
+        let font = encryption.get("OEBPS/fonts/a.otf").unwrap();
+        assert_eq!(font.algorithm(), &Algorithm::Idpf);
+        let chapter = encryption.get("OEBPS/c1.xhtml").unwrap();
+        assert_eq!(chapter.id(), Some("ed2"));
+        assert_eq!(chapter.key_name(), Some("urn:uuid:key"));
+        assert_eq!(chapter.compression(), Some("8"));
+        assert_eq!(chapter.original_length(), Some(1024));
+        assert!(encryption.has_drm());
+
+        let mut copy = Encryption::default();
+        copy.add(EncryptedData::new("OEBPS/fonts/a.otf", Algorithm::Adobe));
+        let copy = Encryption::new(copy.to_xml().as_bytes()).unwrap();
+        assert_eq!(copy.items()[0].algorithm(), &Algorithm::Adobe);
+        assert!(!copy.has_drm());
+    }
+
+    #[test]
+    fn test_keys() {
+        assert_eq!(idpf_key("urn:uuid:a b"), idpf_key("urn:uuid:ab\n"));
+        assert_eq!(
+            adobe_key("urn:uuid:0c9c3e5a-8a3f-4d0c-9d0e-3f8f1a2b3c4d").unwrap()[..4],
+            [0x0c, 0x9c, 0x3e, 0x5a]
+        );
+        assert!(adobe_key("isbn:9780000000000").is_none());
+    }
+}
@@ -1,75 +1,294 @@
 /**
 https://idpf.org/epub/linking/cfi/epub-cfi.html
 **/
+use std::cmp::Ordering;
+use std::fmt;
+use std::rc::Rc;
+use std::str::FromStr;
+
+use markup5ever_rcdom::{Handle, Node, NodeData};
+
 use crate::error::{EpubError, Result};
+use crate::xml::{self, XMLDocument};
+
+const SPECIAL_CHARS: [char; 8] = ['^', '[', ']', '(', ')', ',', ';', '='];
+
+fn escape(value: &str) -> String {
+    let mut res = String::with_capacity(value.len());
+    for c in value.chars() {
+        if SPECIAL_CHARS.contains(&c) {
+            res.push('^');
+        }
+        res.push(c);
+    }
+    res
+}
+
+// `[...]` after a step or an offset, `values` are the comma separated values before the
+// first parameter, `[id]` for steps and `[before,after]` for text locations
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Assertion {
+    values: Vec<String>,
+    parameters: Vec<(String, Vec<String>)>,
+}
 
-#[derive(Debug, Default, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SideBias {
+    Before,
+    After,
+}
+
+impl Assertion {
+    pub fn values(&self) -> &[String] {
+        self.values.as_slice()
+    }
+
+    pub fn parameters(&self) -> &[(String, Vec<String>)] {
+        self.parameters.as_slice()
+    }
+
+    pub fn parameter(&self, name: &str) -> Option<&[String]> {
+        self.parameters
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_slice())
+    }
+
+    // id assertion of a step
+    pub fn id(&self) -> Option<&str> {
+        self.values
+            .first()
+            .map(|v| v.as_str())
+            .filter(|v| !v.is_empty())
+    }
+
+    // text expected before the location, same position as the step id
+    pub fn text_before(&self) -> Option<&str> {
+        self.id()
+    }
+
+    pub fn text_after(&self) -> Option<&str> {
+        self.values
+            .get(1)
+            .map(|v| v.as_str())
+            .filter(|v| !v.is_empty())
+    }
+
+    pub fn side_bias(&self) -> Option<SideBias> {
+        match self.parameter("s")?.first()?.as_str() {
+            "b" => Some(SideBias::Before),
+            "a" => Some(SideBias::After),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
 pub struct Step {
     index: u32,
-    #[allow(dead_code)]
-    asseration: Option<String>,
+    assertion: Option<Assertion>,
 }
 impl Step {
+    // even indexes are elements, odd indexes are the text between them
     pub fn index(&self) -> u32 {
-        self.index / 2
+        self.index
+    }
+
+    pub fn is_element(&self) -> bool {
+        self.index.is_multiple_of(2)
+    }
+
+    pub fn assertion(&self) -> Option<&Assertion> {
+        self.assertion.as_ref()
+    }
+
+    pub fn id(&self) -> Option<&str> {
+        self.assertion.as_ref().and_then(|a| a.id())
     }
 }
 
-#[derive(Debug, Default, Clone)]
-pub struct Offset {}
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Offset {
+    character: Option<u32>,
+    temporal: Option<f64>,
+    spatial: Option<(f64, f64)>,
+    assertion: Option<Assertion>,
+}
 
-#[derive(Debug, Default, Clone)]
+impl Offset {
+    // `:n`, characters into a text node or the image of an element
+    pub fn character(&self) -> Option<u32> {
+        self.character
+    }
+
+    // `~s`, seconds into audio or video
+    pub fn temporal(&self) -> Option<f64> {
+        self.temporal
+    }
+
+    // `@x:y`, percentages of the width and height
+    pub fn spatial(&self) -> Option<(f64, f64)> {
+        self.spatial
+    }
+
+    pub fn assertion(&self) -> Option<&Assertion> {
+        self.assertion.as_ref()
+    }
+
+    pub fn side_bias(&self) -> Option<SideBias> {
+        self.assertion.as_ref().and_then(|a| a.side_bias())
+    }
+}
+
+// steps inside one document, an indirection `!` starts a new path
+#[derive(Debug, Default, Clone, PartialEq)]
 pub struct Path {
     steps: Vec<Step>,
+    offset: Option<Offset>,
 }
 
 impl Path {
     pub fn steps(&self) -> &[Step] {
         self.steps.as_slice()
     }
-}
 
-#[derive(Debug, Default, Clone, PartialEq, Eq)]
-pub struct RangeItem {
-    offset: u32,
-    n: u32,
-}
+    pub fn offset(&self) -> Option<&Offset> {
+        self.offset.as_ref()
+    }
 
-impl RangeItem {
-    fn new(offset: u32, n: u32) -> Self {
-        RangeItem { offset, n }
+    fn is_empty(&self) -> bool {
+        self.steps.is_empty() && self.offset.is_none()
     }
 
-    pub fn is_element(&self) -> bool {
-        self.offset % 2 == 0
+    // steps from the document element to `node`, elements with an id get an id assertion,
+    // a text node becomes an odd step and `offset` counts from the start of its text chunk
+    pub fn from_node(node: &Rc<Node>, offset: Option<u32>, text_assertion: bool) -> Result<Path> {
+        let mut path = Path::default();
+        let mut current = node.clone();
+        if let NodeData::Text { .. } = node.data {
+            let parent = xml::parent(node)
+                .ok_or(EpubError::CfiError("text node without parent".to_string()))?;
+            let (index, before, chunk) = text_position(&parent, node);
+            let character = before + offset.unwrap_or(0);
+            let assertion = if text_assertion {
+                Some(Assertion {
+                    values: text_context(&chunk, character as usize),
+                    parameters: Vec::new(),
+                })
+            } else {
+                None
+            };
+            path.steps.push(Step {
+                index,
+                assertion: None,
+            });
+            path.offset = Some(Offset {
+                character: Some(character),
+                assertion,
+                ..Default::default()
+            });
+            current = parent;
+        } else if let Some(character) = offset {
+            path.offset = Some(Offset {
+                character: Some(character),
+                ..Default::default()
+            });
+        }
+        loop {
+            let parent = match xml::parent(&current) {
+                Some(parent) if matches!(parent.data, NodeData::Element { .. }) => parent,
+                // the document element itself has no step
+                _ => break,
+            };
+            let position = parent
+                .children
+                .borrow()
+                .iter()
+                .filter(|child| matches!(child.data, NodeData::Element { .. }))
+                .position(|child| Rc::ptr_eq(child, &current))
+                .ok_or(EpubError::CfiError(
+                    "node is not a child of its parent".to_string(),
+                ))?;
+            let assertion = xml::parse_attribute(&current, "id").map(|id| Assertion {
+                values: vec![id],
+                parameters: Vec::new(),
+            });
+            path.steps.push(Step {
+                index: (position as u32 + 1) * 2,
+                assertion,
+            });
+            current = parent;
+        }
+        if !matches!(current.data, NodeData::Element { .. }) {
+            return Err(EpubError::CfiError(
+                "node is not inside an element".to_string(),
+            ));
+        }
+        path.steps.reverse();
+        Ok(path)
     }
+}
 
-    pub fn offset(&self) -> u32 {
-        //TODO check underflow
-        if self.is_element() {
-            self.offset / 2 - 1
-        } else {
-            (self.offset - 1) / 2
+// odd step of a text node, characters before it in its chunk and the text of the chunk
+fn text_position(parent: &Handle, node: &Handle) -> (u32, u32, String) {
+    let mut elements = 0;
+    let mut before = 0;
+    let mut chunk = String::new();
+    let mut found = false;
+    for child in parent.children.borrow().iter() {
+        match child.data {
+            NodeData::Element { .. } if found => break,
+            NodeData::Element { .. } => {
+                elements += 1;
+                before = 0;
+                chunk.clear();
+            }
+            NodeData::Text { ref contents } => {
+                if Rc::ptr_eq(child, node) {
+                    found = true;
+                } else if !found {
+                    before += text_len(child);
+                }
+                chunk.push_str(&contents.borrow());
+            }
+            _ => {}
         }
     }
+    (elements * 2 + 1, before, chunk)
+}
+
+const TEXT_ASSERTION_LEN: usize = 10;
+
+// a few characters on each side of the location, `[before,after]`
+fn text_context(text: &str, offset: usize) -> Vec<String> {
+    let chars: Vec<char> = text.chars().collect();
+    let offset = offset.min(chars.len());
+    let before: String = chars[offset.saturating_sub(TEXT_ASSERTION_LEN)..offset]
+        .iter()
+        .collect();
+    let after: String = chars[offset..(offset + TEXT_ASSERTION_LEN).min(chars.len())]
+        .iter()
+        .collect();
+    vec![before, after]
 }
 
-#[derive(Debug, Default, Clone)]
+// `,start,end` relative to the last path of the cfi
+#[derive(Debug, Default, Clone, PartialEq)]
 pub struct Range {
-    start: RangeItem,
-    end: RangeItem,
+    start: Vec<Path>,
+    end: Vec<Path>,
 }
 impl Range {
-    pub fn start_offset(&self) -> u32 {
-        self.start.offset()
+    pub fn start(&self) -> &[Path] {
+        self.start.as_slice()
     }
 
-    pub fn end_offset(&self) -> u32 {
-        self.end.offset()
+    pub fn end(&self) -> &[Path] {
+        self.end.as_slice()
     }
 }
 
-#[derive(Debug, Default, Clone)]
+#[derive(Debug, Default, Clone, PartialEq)]
 pub struct Epubcfi {
     paths: Vec<Path>,
     range: Option<Range>,
@@ -87,170 +306,847 @@ impl<'a> EpubCfiParser<'a> {
         }
     }
 
-    fn get_char(&mut self) -> Result<&char> {
-        let char = self
-            .content
-            .get(self.current)
-            .ok_or(EpubError::CfiError(format!(
-                "read char error:{}",
-                self.current()
-            )));
+    fn error(&self, msg: &str) -> EpubError {
+        EpubError::CfiError(format!("{} at position {}", msg, self.current))
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.content.get(self.current).copied()
+    }
+
+    fn get_char(&mut self) -> Result<char> {
+        let c = self.peek().ok_or_else(|| self.error("unexpected end"))?;
         self.current += 1;
-        char
+        Ok(c)
+    }
+
+    fn expect(&mut self, expected: char) -> Result<()> {
+        match self.peek() {
+            Some(c) if c == expected => {
+                self.current += 1;
+                Ok(())
+            }
+            Some(c) => Err(self.error(&format!("expected '{}' found '{}'", expected, c))),
+            None => Err(self.error(&format!("expected '{}'", expected))),
+        }
     }
 
     fn is_finish(&self) -> bool {
         self.current >= self.content.len()
     }
 
+    fn starts_with(&self, prefix: &str) -> bool {
+        let mut chars = self.content[self.current..].iter();
+        prefix.chars().all(|c| chars.next() == Some(&c))
+    }
+
     pub fn parse(&mut self) -> Result<Epubcfi> {
-        let mut steps = Vec::new();
+        if self.peek() == Some('#') {
+            self.current += 1;
+        }
+        let wrapped = self.starts_with("epubcfi(");
+        if wrapped {
+            self.current += "epubcfi(".len();
+        }
+        if self.peek() != Some('/') {
+            return Err(self.error("cfi must start with a step"));
+        }
+        let paths = self.parse_local_path()?;
+        let range = if self.peek() == Some(',') {
+            self.current += 1;
+            let start = self.parse_local_path()?;
+            self.expect(',')?;
+            let end = self.parse_local_path()?;
+            Some(Range { start, end })
+        } else {
+            None
+        };
+        if wrapped {
+            self.expect(')')?;
+        }
+        if !self.is_finish() {
+            return Err(self.error("unexpected character"));
+        }
+        Ok(Epubcfi { paths, range })
+    }
+
+    fn parse_local_path(&mut self) -> Result<Vec<Path>> {
         let mut paths = Vec::new();
-        let mut range = None;
-        while !self.is_finish() {
-            let c = self.get_char()?;
-            match c {
-                '/' => {
-                    let item = self.parse_path_item();
-                    steps.push(item);
-                }
-                '!' => {
-                    if !steps.is_empty() {
-                        let path = Path {
-                            steps: steps.clone(),
-                        };
-                        steps.clear();
-                        paths.push(path);
+        let mut path = Path::default();
+        loop {
+            match self.peek() {
+                Some('/') => path.steps.push(self.parse_step()?),
+                Some('!') => {
+                    if path.is_empty() {
+                        return Err(self.error("empty path before indirection"));
                     }
+                    self.current += 1;
+                    paths.push(std::mem::take(&mut path));
                 }
-                ',' | ':' | '~' | '@' => {
-                    if !steps.is_empty() {
-                        steps.clear();
-                    }
-                    range = Some(self.parse_range()?);
+                Some(':') | Some('~') | Some('@') => {
+                    // an offset always terminates the path
+                    path.offset = Some(self.parse_offset()?);
                     break;
                 }
-                _ => {
-                    println!("impossiable:{}", c);
-                }
+                _ => break,
             }
         }
-        Ok(Epubcfi { paths, range })
+        if path.is_empty() {
+            return Err(self.error("expected a step or an offset"));
+        }
+        paths.push(path);
+        Ok(paths)
     }
 
-    fn current(&self) -> usize {
-        self.current
+    fn parse_step(&mut self) -> Result<Step> {
+        self.expect('/')?;
+        let index = self.read_integer()?;
+        let assertion = if self.peek() == Some('[') {
+            Some(self.parse_assertion()?)
+        } else {
+            None
+        };
+        Ok(Step { index, assertion })
     }
 
-    fn read_number(&mut self) -> Result<u32> {
-        let mut n: u32 = 0;
-
-        loop {
-            if self.is_finish() {
-                break;
-            }
-            let cc = self.get_char()?;
-            if !cc.is_ascii_digit() {
-                self.current -= 1;
-                break;
+    fn parse_offset(&mut self) -> Result<Offset> {
+        let mut offset = Offset::default();
+        match self.get_char()? {
+            ':' => offset.character = Some(self.read_integer()?),
+            '~' => {
+                offset.temporal = Some(self.read_number()?);
+                if self.peek() == Some('@') {
+                    self.current += 1;
+                    offset.spatial = Some(self.read_point()?);
+                }
             }
-            let v = cc.to_digit(10).ok_or(EpubError::CfiError(format!(
-                "char not a number at :{:?}",
-                self.current()
-            )))?;
-            n += n * 10 + v;
+            _ => offset.spatial = Some(self.read_point()?),
+        }
+        if self.peek() == Some('[') {
+            offset.assertion = Some(self.parse_assertion()?);
         }
-        Ok(n)
+        Ok(offset)
     }
 
-    fn parse_range(&mut self) -> Result<Range> {
-        let c = self.get_char()?;
-        if c != &'/' {
-            return Err(EpubError::CfiError(
-                "expected '/' when in range".to_string(),
-            ));
+    fn read_point(&mut self) -> Result<(f64, f64)> {
+        let x = self.read_number()?;
+        self.expect(':')?;
+        let y = self.read_number()?;
+        Ok((x, y))
+    }
+
+    fn read_digits(&mut self) -> String {
+        let mut digits = String::new();
+        while let Some(c) = self.peek().filter(|c| c.is_ascii_digit()) {
+            digits.push(c);
+            self.current += 1;
         }
-        let n = self.read_number()?;
-        let c = self.get_char()?;
-        if c != &':' {
-            return Err(EpubError::CfiError("range need a offset".to_string()));
-        }
-        let offset = self.read_number()?;
-        let start = RangeItem { offset, n };
-        let c = self.get_char()?;
-        if c != &',' {
-            return Err(EpubError::CfiError(String::from(
-                "expected ',' when in range",
-            )));
+        digits
+    }
+
+    fn read_integer(&mut self) -> Result<u32> {
+        let start = self.current;
+        let digits = self.read_digits();
+        if digits.is_empty() {
+            return Err(self.error("expected a number"));
         }
-        let c = self.get_char()?;
-        if c != &'/' {
-            return Err(EpubError::CfiError(String::from(
-                "expected '/' when in range",
-            )));
+        digits.parse::<u32>().map_err(|e| {
+            EpubError::CfiError(format!(
+                "invalid number {:?} at position {} error:{:?}",
+                digits, start, e
+            ))
+        })
+    }
+
+    fn read_number(&mut self) -> Result<f64> {
+        let mut digits = self.read_digits();
+        if digits.is_empty() {
+            return Err(self.error("expected a number"));
         }
-        let n = self.read_number()?;
-        let c = self.get_char()?;
-        if c != &':' {
-            return Err(EpubError::CfiError("range need a offset".to_string()));
-        }
-        let offset = self.read_number()?;
-        let end = RangeItem::new(offset, n);
-        Ok(Range { start, end })
-    }
-
-    fn parse_path_item(&mut self) -> Step {
-        let mut index: u32 = 0;
-        let mut asseration = None;
-        while !self.is_finish() {
-            let c = self.get_char().unwrap();
-            if c.is_ascii_digit() {
-                index = index * 10 + c.to_digit(10).unwrap();
-            } else if *c == '[' {
-                let mut idvalue = String::new();
-                let mut ch = self.get_char().unwrap();
-                while *ch != ']' {
-                    idvalue.push(ch.to_owned());
-                    ch = self.get_char().unwrap();
+        if self.peek() == Some('.') {
+            self.current += 1;
+            let fraction = self.read_digits();
+            if fraction.is_empty() {
+                return Err(self.error("expected a fraction"));
+            }
+            digits.push('.');
+            digits.push_str(&fraction);
+        }
+        digits
+            .parse::<f64>()
+            .map_err(|e| self.error(&format!("invalid number {:?} error:{:?}", digits, e)))
+    }
+
+    // a value ends at an unescaped special character, `^` escapes the next one
+    fn read_value(&mut self) -> Result<String> {
+        let mut value = String::new();
+        loop {
+            match self.peek() {
+                None => return Err(self.error("unterminated assertion")),
+                Some('^') => {
+                    self.current += 1;
+                    value.push(self.get_char()?);
                 }
-                asseration = Some(idvalue);
-                self.current -= 1;
-                break;
-            } else {
-                self.current -= 1;
-                break;
+                Some(',') | Some(';') | Some('=') | Some('[') | Some(']') | Some('(')
+                | Some(')') => break,
+                Some(c) => {
+                    value.push(c);
+                    self.current += 1;
+                }
+            }
+        }
+        Ok(value)
+    }
+
+    fn parse_assertion(&mut self) -> Result<Assertion> {
+        self.expect('[')?;
+        let mut assertion = Assertion::default();
+        let first = self.read_value()?;
+        if self.peek() == Some(',') {
+            self.current += 1;
+            assertion.values.push(first);
+            assertion.values.push(self.read_value()?);
+        } else if !first.is_empty() {
+            assertion.values.push(first);
+        }
+        while self.peek() == Some(';') {
+            self.current += 1;
+            let name = self.read_value()?;
+            if name.is_empty() {
+                return Err(self.error("expected a parameter name"));
+            }
+            self.expect('=')?;
+            let mut values = vec![self.read_value()?];
+            while self.peek() == Some(',') {
+                self.current += 1;
+                values.push(self.read_value()?);
             }
+            assertion.parameters.push((name, values));
         }
-        Step { index, asseration }
+        self.expect(']')?;
+        Ok(assertion)
     }
 }
 
+fn check_paths(paths: &[Path]) -> Result<()> {
+    if paths.is_empty() || paths.iter().any(|path| path.is_empty()) {
+        return Err(EpubError::CfiError("empty path".to_string()));
+    }
+    if paths[0].steps.is_empty() {
+        return Err(EpubError::CfiError(
+            "cfi must start with a step".to_string(),
+        ));
+    }
+    Ok(())
+}
+
 impl Epubcfi {
+    // a point, `paths` are separated by indirections
+    pub fn new(paths: Vec<Path>) -> Result<Self> {
+        check_paths(&paths)?;
+        Ok(Epubcfi { paths, range: None })
+    }
+
+    // a range between two points in document order, normalized to their common parent
+    pub fn new_range(start: &Epubcfi, end: &Epubcfi) -> Result<Self> {
+        if start.is_range() || end.is_range() {
+            return Err(EpubError::CfiError(
+                "range ends must not be ranges".to_string(),
+            ));
+        }
+        check_paths(&start.paths)?;
+        check_paths(&end.paths)?;
+        let (start, end) = if start <= end {
+            (start, end)
+        } else {
+            (end, start)
+        };
+        let split = |cfi: &Epubcfi| {
+            let mut paths = cfi.paths.clone();
+            let first = paths[0].steps.remove(0);
+            (first, paths)
+        };
+        let (first, start) = split(start);
+        let (end_first, end) = split(end);
+        if first.index != end_first.index || start[0].is_empty() || end[0].is_empty() {
+            return Err(EpubError::CfiError(
+                "range ends have no common parent".to_string(),
+            ));
+        }
+        let cfi = Epubcfi {
+            paths: vec![Path {
+                steps: vec![first],
+                offset: None,
+            }],
+            range: Some(Range { start, end }),
+        };
+        Ok(cfi.normalize())
+    }
+
     pub fn try_new(target: &str) -> Result<Self> {
         let chars: Vec<char> = target.chars().collect();
         let mut parser = EpubCfiParser::new(chars.as_slice());
         parser.parse()
     }
 
+    // paths separated by indirections, the first one is inside the package document
+    pub fn paths(&self) -> &[Path] {
+        self.paths.as_slice()
+    }
+
     pub fn chapter(&self) -> Option<&Path> {
         self.paths.first()
     }
+
     pub fn range(&self) -> Option<&Range> {
         self.range.as_ref()
     }
+
+    pub fn is_range(&self) -> bool {
+        self.range.is_some()
+    }
+
+    // the start point of a range, the cfi itself otherwise
+    pub fn start(&self) -> Epubcfi {
+        match &self.range {
+            Some(range) => Epubcfi {
+                paths: join(&self.paths, &range.start),
+                range: None,
+            },
+            None => self.clone(),
+        }
+    }
+
+    pub fn end(&self) -> Epubcfi {
+        match &self.range {
+            Some(range) => Epubcfi {
+                paths: join(&self.paths, &range.end),
+                range: None,
+            },
+            None => self.clone(),
+        }
+    }
+
+    // move the steps shared by the start and the end of a range into the parent path
+    pub fn normalize(&self) -> Epubcfi {
+        if self.range.is_none() {
+            return self.clone();
+        }
+        let (start, start_offset) = flatten(&self.start().paths);
+        let (end, end_offset) = flatten(&self.end().paths);
+        let mut common = start
+            .iter()
+            .zip(end.iter())
+            .take_while(|(a, b)| match (a, b) {
+                (Some(a), Some(b)) => a.index == b.index,
+                (None, None) => true,
+                _ => false,
+            })
+            .count();
+        // the parent ends at an element, and both ends need a step or an offset
+        while common > 0
+            && (!start[common - 1].as_ref().is_some_and(|s| s.is_element())
+                || start.get(common).is_some_and(|s| s.is_none())
+                || end.get(common).is_some_and(|s| s.is_none())
+                || (common == start.len() && start_offset.is_none())
+                || (common == end.len() && end_offset.is_none()))
+        {
+            common -= 1;
+        }
+        if common == 0 {
+            return self.clone();
+        }
+        Epubcfi {
+            paths: unflatten(&start[..common], None),
+            range: Some(Range {
+                start: unflatten(&start[common..], start_offset),
+                end: unflatten(&end[common..], end_offset),
+            }),
+        }
+    }
+
+    // steps in document order, indirections are ignored
+    fn location(&self) -> (Vec<u32>, Option<&Offset>) {
+        let steps = self
+            .paths
+            .iter()
+            .flat_map(|path| path.steps.iter().map(|step| step.index))
+            .collect();
+        (
+            steps,
+            self.paths.last().and_then(|path| path.offset.as_ref()),
+        )
+    }
+}
+
+// the steps of all paths, `None` for an indirection
+fn flatten(paths: &[Path]) -> (Vec<Option<Step>>, Option<Offset>) {
+    let mut steps = Vec::new();
+    for (i, path) in paths.iter().enumerate() {
+        if i > 0 {
+            steps.push(None);
+        }
+        steps.extend(path.steps.iter().cloned().map(Some));
+    }
+    (steps, paths.last().and_then(|path| path.offset.clone()))
+}
+
+fn unflatten(steps: &[Option<Step>], offset: Option<Offset>) -> Vec<Path> {
+    let mut paths = vec![Path::default()];
+    for step in steps {
+        match step {
+            Some(step) => paths.last_mut().unwrap().steps.push(step.clone()),
+            None => paths.push(Path::default()),
+        }
+    }
+    paths.last_mut().unwrap().offset = offset;
+    paths
+}
+
+fn join(parent: &[Path], local: &[Path]) -> Vec<Path> {
+    let mut paths = parent.to_vec();
+    let mut local = local.iter();
+    if let (Some(last), Some(first)) = (paths.last_mut(), local.next()) {
+        last.steps.extend(first.steps.iter().cloned());
+        last.offset = first.offset.clone();
+    }
+    paths.extend(local.cloned());
+    paths
+}
+
+fn compare_offset(a: Option<&Offset>, b: Option<&Offset>) -> Ordering {
+    let (a, b) = match (a, b) {
+        (Some(a), Some(b)) => (a, b),
+        _ => return a.is_some().cmp(&b.is_some()),
+    };
+    let spatial = |o: &Offset| o.spatial.map(|(x, y)| (y, x));
+    a.character
+        .cmp(&b.character)
+        .then_with(|| compare_f64(a.temporal, b.temporal))
+        .then_with(|| compare_f64(spatial(a).map(|p| p.0), spatial(b).map(|p| p.0)))
+        .then_with(|| compare_f64(spatial(a).map(|p| p.1), spatial(b).map(|p| p.1)))
+}
+
+fn compare_f64(a: Option<f64>, b: Option<f64>) -> Ordering {
+    match (a, b) {
+        (Some(a), Some(b)) => a.total_cmp(&b),
+        _ => a.is_some().cmp(&b.is_some()),
+    }
+}
+
+impl FromStr for Epubcfi {
+    type Err = EpubError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Epubcfi::try_new(s)
+    }
+}
+
+impl fmt::Display for Assertion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let values: Vec<String> = self.values.iter().map(|v| escape(v)).collect();
+        write!(f, "[{}", values.join(","))?;
+        for (name, values) in self.parameters.iter() {
+            let values: Vec<String> = values.iter().map(|v| escape(v)).collect();
+            write!(f, ";{}={}", escape(name), values.join(","))?;
+        }
+        write!(f, "]")
+    }
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "/{}", self.index)?;
+        if let Some(assertion) = &self.assertion {
+            write!(f, "{}", assertion)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Offset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(character) = self.character {
+            write!(f, ":{}", character)?;
+        }
+        if let Some(temporal) = self.temporal {
+            write!(f, "~{}", temporal)?;
+        }
+        if let Some((x, y)) = self.spatial {
+            write!(f, "@{}:{}", x, y)?;
+        }
+        if let Some(assertion) = &self.assertion {
+            write!(f, "{}", assertion)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for step in self.steps.iter() {
+            write!(f, "{}", step)?;
+        }
+        if let Some(offset) = &self.offset {
+            write!(f, "{}", offset)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_paths(f: &mut fmt::Formatter<'_>, paths: &[Path]) -> fmt::Result {
+    for (i, path) in paths.iter().enumerate() {
+        if i > 0 {
+            write!(f, "!")?;
+        }
+        write!(f, "{}", path)?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for Epubcfi {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "epubcfi(")?;
+        write_paths(f, &self.paths)?;
+        if let Some(range) = &self.range {
+            write!(f, ",")?;
+            write_paths(f, &range.start)?;
+            write!(f, ",")?;
+            write_paths(f, &range.end)?;
+        }
+        write!(f, ")")
+    }
+}
+
+// offsets are parsed from digits only, there is no NaN
+impl Eq for Epubcfi {}
+
+impl PartialOrd for Epubcfi {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// document order of the start points, then of the end points, the string form only
+// separates cfis at the same location with different assertions
+impl Ord for Epubcfi {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let compare = |a: &Epubcfi, b: &Epubcfi| {
+            let (a_steps, a_offset) = a.location();
+            let (b_steps, b_offset) = b.location();
+            a_steps
+                .cmp(&b_steps)
+                .then_with(|| compare_offset(a_offset, b_offset))
+        };
+        compare(&self.start(), &other.start())
+            .then_with(|| compare(&self.end(), &other.end()))
+            .then_with(|| self.is_range().cmp(&other.is_range()))
+            .then_with(|| self.to_string().cmp(&other.to_string()))
+    }
+}
+
+// where a cfi points to inside a book
+pub struct CfiLocation {
+    pub(crate) spine_index: usize,
+    pub(crate) path: String,
+    pub(crate) document: XMLDocument,
+    pub(crate) node: Rc<Node>,
+    pub(crate) offset: Option<u32>,
+}
+
+impl CfiLocation {
+    pub fn spine_index(&self) -> usize {
+        self.spine_index
+    }
+
+    // path of the content document in the container
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn document(&self) -> &XMLDocument {
+        &self.document
+    }
+
+    // an element, or the text node holding the offset
+    pub fn node(&self) -> &Rc<Node> {
+        &self.node
+    }
+
+    // characters into the text node
+    pub fn offset(&self) -> Option<u32> {
+        self.offset
+    }
+}
+
+// a child selected by a step, odd steps select the text between two elements
+enum Child {
+    Element(Handle),
+    Text(Vec<Handle>),
+}
+
+fn child_at(node: &Handle, index: u32) -> Option<Child> {
+    let target = index / 2;
+    let is_text = !index.is_multiple_of(2);
+    let mut elements = 0;
+    let mut texts = Vec::new();
+    for child in node.children.borrow().iter() {
+        match child.data {
+            NodeData::Element { .. } => {
+                elements += 1;
+                if !is_text && elements == target {
+                    return Some(Child::Element(child.clone()));
+                }
+                if is_text && elements > target {
+                    return Some(Child::Text(texts));
+                }
+            }
+            NodeData::Text { .. } if is_text && elements == target => texts.push(child.clone()),
+            _ => {}
+        }
+    }
+    if is_text && elements == target {
+        Some(Child::Text(texts))
+    } else {
+        None
+    }
+}
+
+fn text_len(node: &Handle) -> u32 {
+    match node.data {
+        NodeData::Text { ref contents } => contents.borrow().chars().count() as u32,
+        _ => 0,
+    }
+}
+
+// walk the steps of one path from the document element, id assertions win over indexes
+pub(crate) fn resolve_path(doc: &XMLDocument, path: &Path) -> Result<(Rc<Node>, Option<u32>)> {
+    let mut node = doc.root().ok_or(EpubError::CfiError(
+        "document has no root element".to_string(),
+    ))?;
+    let mut text = None;
+    for step in path.steps.iter() {
+        if text.is_some() {
+            return Err(EpubError::CfiError(format!(
+                "step {} after a text step",
+                step
+            )));
+        }
+        let child = child_at(&node, step.index);
+        let by_id = |id: &str| {
+            doc.find_by_id(id).ok_or(EpubError::CfiError(format!(
+                "id assertion {:?} of step {} failed",
+                id, step
+            )))
+        };
+        node = match (child, step.id()) {
+            (Some(Child::Element(e)), Some(id))
+                if xml::parse_attribute(&e, "id").as_deref() != Some(id) =>
+            {
+                by_id(id)?
+            }
+            (Some(Child::Element(e)), _) => e,
+            (Some(Child::Text(nodes)), _) => {
+                text = Some(nodes);
+                node
+            }
+            (None, Some(id)) => by_id(id)?,
+            (None, None) => return Err(EpubError::CfiError(format!("step {} not found", step))),
+        };
+    }
+    let offset = path.offset.as_ref().and_then(|o| o.character);
+    let nodes = match text {
+        Some(nodes) if !nodes.is_empty() => nodes,
+        // an element, or an empty text between two elements
+        _ => return Ok((node, offset)),
+    };
+    let mut remaining = offset.unwrap_or(0);
+    for text in nodes {
+        let len = text_len(&text);
+        if remaining <= len {
+            return Ok((text, Some(remaining)));
+        }
+        remaining -= len;
+    }
+    Err(EpubError::CfiError(format!(
+        "offset {} is out of range",
+        offset.unwrap_or(0)
+    )))
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::epubcfi::{Epubcfi, RangeItem};
+    use crate::epubcfi::{Epubcfi, Path, SideBias};
+    use crate::xml::XMLDocument;
 
     #[test]
     fn test_parse() {
         let content = "/6/8!/4/4/20,/1:4,/1:21";
         let epubcfi = Epubcfi::try_new(content).unwrap();
         let chapter = epubcfi.chapter().unwrap();
-        assert_eq!(chapter.steps.len(), 2);
+        assert_eq!(chapter.steps().len(), 2);
         let range = epubcfi.range().unwrap();
-        assert_eq!(range.start, RangeItem::new(4, 1));
+        let start = &range.start()[0];
+        assert_eq!(start.steps()[0].index(), 1);
+        assert_eq!(start.offset().unwrap().character(), Some(4));
+        assert_eq!(range.end()[0].offset().unwrap().character(), Some(21));
+    }
+
+    #[test]
+    fn test_parse_assertions() {
+        let cfi = Epubcfi::try_new("epubcfi(/6/14[chap05ref]!/4[body01]/10/2/1:3[2^[1^],xx;s=b])")
+            .unwrap();
+        assert_eq!(cfi.paths().len(), 2);
+        let package = &cfi.paths()[0];
+        assert_eq!(package.steps()[1].index(), 14);
+        assert_eq!(package.steps()[1].id(), Some("chap05ref"));
+        let content = &cfi.paths()[1];
+        assert_eq!(content.steps()[0].id(), Some("body01"));
+        assert!(!content.steps()[3].is_element());
+        let offset = content.offset().unwrap();
+        assert_eq!(offset.character(), Some(3));
+        let assertion = offset.assertion().unwrap();
+        assert_eq!(assertion.text_before(), Some("2[1]"));
+        assert_eq!(assertion.text_after(), Some("xx"));
+        assert_eq!(offset.side_bias(), Some(SideBias::Before));
+
+        let cfi = Epubcfi::try_new("epubcfi(/6/4!/4/2[;s=a]/1:0[,after])").unwrap();
+        let step = &cfi.paths()[1].steps()[1];
+        assert_eq!(step.id(), None);
+        assert_eq!(step.assertion().unwrap().side_bias(), Some(SideBias::After));
+        let offset = cfi.paths()[1].offset().unwrap();
+        assert_eq!(offset.assertion().unwrap().text_before(), None);
+        assert_eq!(offset.assertion().unwrap().text_after(), Some("after"));
+    }
+
+    #[test]
+    fn test_parse_offsets() {
+        let cfi = Epubcfi::try_new("epubcfi(/6/4!/4/6~23.5@10:20.5)").unwrap();
+        let offset = cfi.paths()[1].offset().unwrap();
+        assert_eq!(offset.temporal(), Some(23.5));
+        assert_eq!(offset.spatial(), Some((10.0, 20.5)));
+
+        let cfi = Epubcfi::try_new("/6/4!/2@50:50").unwrap();
+        assert_eq!(
+            cfi.paths()[1].offset().unwrap().spatial(),
+            Some((50.0, 50.0))
+        );
+
+        let cfi = Epubcfi::try_new("/6/123!/4/1:1234").unwrap();
+        assert_eq!(cfi.paths()[0].steps()[1].index(), 123);
+        assert_eq!(cfi.paths()[1].offset().unwrap().character(), Some(1234));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for (cfi, position) in [
+            ("epubcfi(/6/4!/4", 15),
+            ("/6/x", 3),
+            ("/6/4[id", 7),
+            ("/6/4:3/2", 6),
+            ("/6/4!,/1:2,/1:3", 5),
+            ("epubcfi(/6/4,/1:2)", 17),
+        ] {
+            let err = Epubcfi::try_new(cfi).unwrap_err().to_string();
+            assert!(
+                err.contains(&format!("at position {}", position)),
+                "{} {}",
+                cfi,
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn test_display() {
+        for cfi in [
+            "epubcfi(/6/4[chap01ref]!/4[body01]/10[para05]/3:10)",
+            "epubcfi(/6/14[chap05ref]!/4[body01]/10/2/1:3[2^[1^],xx;s=b])",
+            "epubcfi(/6/4!/4/2[;s=a]/1:0[,a^,b^;c^=d^(e^)])",
+            "epubcfi(/6/4!/4/6~23.5@10:20.5)",
+            "epubcfi(/6/4!/4/10,/2/1:1,/3:4)",
+        ] {
+            assert_eq!(Epubcfi::try_new(cfi).unwrap().to_string(), cfi);
+        }
+        let cfi: Epubcfi = "/6/8!/4/4/20,/1:4,/1:21".parse().unwrap();
+        assert_eq!(cfi.to_string(), "epubcfi(/6/8!/4/4/20,/1:4,/1:21)");
+        assert_eq!(cfi.start().to_string(), "epubcfi(/6/8!/4/4/20/1:4)");
+    }
+
+    #[test]
+    fn test_order() {
+        let mut cfis: Vec<Epubcfi> = [
+            "/6/4!/4/10/3:5",
+            "/6/6!/2",
+            "/6/4!/4/10/3:5[a]",
+            "/6/4!/4/2/1:100",
+            "/6/4!/4/10",
+            "/6/4!/4/10,/3:5,/3:9",
+            "/6/4[chap]!/4/10/1:0",
+            "/6/4!/4/10,/3:5,/3:7",
+        ]
+        .iter()
+        .map(|c| Epubcfi::try_new(c).unwrap())
+        .collect();
+        cfis.sort();
+        let sorted: Vec<String> = cfis.iter().map(|c| c.to_string()).collect();
+        assert_eq!(
+            sorted,
+            [
+                "epubcfi(/6/4!/4/2/1:100)",
+                "epubcfi(/6/4!/4/10)",
+                "epubcfi(/6/4[chap]!/4/10/1:0)",
+                "epubcfi(/6/4!/4/10/3:5)",
+                "epubcfi(/6/4!/4/10/3:5[a])",
+                "epubcfi(/6/4!/4/10,/3:5,/3:7)",
+                "epubcfi(/6/4!/4/10,/3:5,/3:9)",
+                "epubcfi(/6/6!/2)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_normalize() {
+        let cfi = Epubcfi::try_new("/6/4!/4,/10/2/1:3,/10/4/1:5").unwrap();
+        assert_eq!(
+            cfi.normalize().to_string(),
+            "epubcfi(/6/4!/4/10,/2/1:3,/4/1:5)"
+        );
+        let cfi = Epubcfi::try_new("/6/4!/4,/10/1:3,/10/1:5").unwrap();
+        assert_eq!(cfi.normalize().to_string(), "epubcfi(/6/4!/4/10,/1:3,/1:5)");
+        // only the indirection is shared
+        let cfi = Epubcfi::try_new("/6,/4!/2,/6!/2").unwrap();
+        assert_eq!(cfi.normalize().to_string(), "epubcfi(/6,/4!/2,/6!/2)");
+        assert_eq!(cfi.normalize(), cfi.normalize().normalize());
+
+        let start = Epubcfi::try_new("/6/4!/4/10/1:3").unwrap();
+        let end = Epubcfi::try_new("/6/4!/4/12/1:5").unwrap();
+        let range = Epubcfi::new_range(&end, &start).unwrap();
+        assert_eq!(range.to_string(), "epubcfi(/6/4!/4,/10/1:3,/12/1:5)");
+        assert!(Epubcfi::new_range(&Epubcfi::default(), &end).is_err());
+        assert!(Epubcfi::new_range(&start, &Epubcfi::default()).is_err());
+        assert!(Epubcfi::new_range(&Epubcfi::try_new("/6").unwrap(), &end).is_err());
+    }
+
+    #[test]
+    fn test_path_from_node() {
+        let doc = XMLDocument::try_new(
+            br#"<html><head/><body>a<b id="x">b</b>bc<!--comment-->de<i/></body></html>"#,
+        )
+        .unwrap();
+        let body = doc.find_tag("body").unwrap();
+        let de = body.children.borrow()[4].clone();
+        let path = Path::from_node(&de, Some(1), true).unwrap();
+        assert_eq!(path.to_string(), "/4/3:3[bcd,e]");
+
+        let b = doc.find_tag("b").unwrap();
+        let path = Path::from_node(&b, None, false).unwrap();
+        assert_eq!(path.to_string(), "/4/2[x]");
+
+        let start = Epubcfi::new(vec![
+            Path::from_node(&doc.find_tag("html").unwrap(), None, false).unwrap(),
+            path,
+        ]);
+        assert!(start.is_err());
     }
 }
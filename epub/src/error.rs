@@ -17,8 +17,14 @@ pub enum EpubError {
     FormatError(String),
     #[error("UrlError: `{0}`")]
     UrlError(String),
+    #[error("WriterError: `{0}`")]
+    WriterError(String),
     #[error("CfiError:`{0}`")]
     CfiError(String),
+    #[error("EncodingError: `{0}`")]
+    EncodingError(String),
+    #[error("DrmError: `{0}`")]
+    DrmError(String),
 }
 
 pub type Result<T> = std::result::Result<T, EpubError>;
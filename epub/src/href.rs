@@ -0,0 +1,161 @@
+use std::path::{Component, Path};
+
+use crate::error::{EpubError, Result};
+
+// a file in the container and the fragment an href points at
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Href {
+    path: String,
+    fragment: Option<String>,
+}
+
+impl Href {
+    // the percent-decoded path from the container root
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn fragment(&self) -> Option<&str> {
+        self.fragment.as_deref()
+    }
+}
+
+// hrefs with a scheme, like `https:` or `mailto:`, are not in the container
+pub fn is_remote(href: &str) -> bool {
+    let end = href.find(['/', '?', '#']).unwrap_or(href.len());
+    href[..end].find(':').is_some_and(|i| {
+        i > 0
+            && href[..i]
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'))
+    })
+}
+
+// the directory of a path in the container, empty for the root
+pub fn directory(path: &str) -> &str {
+    path.rfind('/').map(|i| &path[..i]).unwrap_or("")
+}
+
+fn percent_decode(value: &str) -> Result<String> {
+    let bytes = value.as_bytes();
+    let mut res = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .filter(|h| h.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                res.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                res.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(res)
+        .map_err(|e| EpubError::UrlError(format!("decode {:?} error:{:?}", value, e)))
+}
+
+// a relative path that stays below the container root, like the ones `resolve` returns
+pub fn is_contained(path: &str) -> bool {
+    Path::new(path)
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+}
+
+// resolve `href` found in the document at `base`, both relative to the container root.
+// a query is dropped, `..` may not leave the container
+pub fn resolve(base: &str, href: &str) -> Result<Href> {
+    if is_remote(href) {
+        return Err(EpubError::UrlError(format!(
+            "{:?} is not in the container",
+            href
+        )));
+    }
+    let (href, fragment) = match href.split_once('#') {
+        Some((href, fragment)) => (href, Some(percent_decode(fragment)?)),
+        None => (href, None),
+    };
+    let href = href.split('?').next().unwrap_or_default();
+    let mut parts: Vec<String> = Vec::new();
+    if href.is_empty() {
+        // a fragment-only href points into the document itself
+        parts.push(base.to_string());
+    } else if !href.starts_with('/') {
+        parts.extend(
+            directory(base)
+                .split('/')
+                .filter(|p| !p.is_empty())
+                .map(|p| p.to_string()),
+        );
+    }
+    if !href.is_empty() {
+        for part in href.split('/') {
+            // decoded first, `%2E%2E` is `..` too
+            let part = percent_decode(part)?;
+            if part.contains(['/', '\\']) {
+                return Err(EpubError::UrlError(format!(
+                    "{:?} in {:?} has an encoded path separator",
+                    href, base
+                )));
+            }
+            match part.as_str() {
+                "" | "." => {}
+                ".." => {
+                    if parts.pop().is_none() {
+                        return Err(EpubError::UrlError(format!(
+                            "{:?} in {:?} is outside of the container",
+                            href, base
+                        )));
+                    }
+                }
+                _ => parts.push(part),
+            }
+        }
+    }
+    Ok(Href {
+        path: parts.join("/"),
+        fragment,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::href::{is_contained, is_remote, resolve};
+
+    #[test]
+    fn test_resolve() {
+        let base = "OEBPS/Text/chapter1.xhtml";
+        let href = resolve(base, "chapter%201.xhtml#sec%202").unwrap();
+        assert_eq!(href.path(), "OEBPS/Text/chapter 1.xhtml");
+        assert_eq!(href.fragment(), Some("sec 2"));
+        assert_eq!(
+            resolve(base, "../Images/./a.png?v=1").unwrap().path(),
+            "OEBPS/Images/a.png"
+        );
+        assert_eq!(resolve(base, "#note").unwrap().path(), base);
+        assert_eq!(
+            resolve(base, "/META-INF/a.xml").unwrap().path(),
+            "META-INF/a.xml"
+        );
+        assert_eq!(
+            resolve("content.opf", "c1.xhtml").unwrap().path(),
+            "c1.xhtml"
+        );
+        assert!(resolve(base, "../../../etc/passwd").is_err());
+        assert!(resolve(base, "%2E%2E/%2E%2E/%2E%2E/etc/passwd").is_err());
+        assert!(resolve(base, "..%2F..%2F..%2Fetc/passwd").is_err());
+        assert_eq!(resolve(base, "%2E%2E/a.png").unwrap().path(), "OEBPS/a.png");
+        assert!(!is_contained("../etc/passwd"));
+        assert!(!is_contained("/etc/passwd"));
+        assert!(is_contained("OEBPS/./a.png"));
+        assert!(resolve(base, "https://example.com/a.png").is_err());
+        assert!(is_remote("mailto:a@example.com"));
+        assert!(!is_remote("Text/a.xhtml#x:y"));
+    }
+}
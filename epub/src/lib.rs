@@ -1,11 +1,20 @@
 pub mod book;
+pub mod builder;
 pub mod error;
 
 pub mod chapter;
 pub mod container;
+pub mod encoding;
+pub mod encryption;
 pub mod epubcfi;
+pub mod href;
+mod media_type;
 pub mod nav;
 pub mod package;
 
 mod reader;
+pub mod repair;
+pub mod selector;
+pub mod validate;
+pub mod writer;
 pub mod xml;
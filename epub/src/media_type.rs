@@ -0,0 +1,35 @@
+use crate::error::{EpubError, Result};
+
+// the media type of a publication resource, guessed from the extension of `href`
+pub(crate) fn guess(href: &str) -> Result<&'static str> {
+    let ext = href.rsplit('.').next().unwrap_or_default();
+    match ext.to_ascii_lowercase().as_str() {
+        "xhtml" | "html" => Ok("application/xhtml+xml"),
+        "css" => Ok("text/css"),
+        "png" => Ok("image/png"),
+        "jpg" | "jpeg" => Ok("image/jpeg"),
+        "gif" => Ok("image/gif"),
+        "svg" => Ok("image/svg+xml"),
+        "webp" => Ok("image/webp"),
+        "otf" => Ok("font/otf"),
+        "ttf" => Ok("font/ttf"),
+        "woff" => Ok("font/woff"),
+        "woff2" => Ok("font/woff2"),
+        _ => Err(EpubError::FormatError(format!(
+            "unknown media type for {:?}",
+            href
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::media_type::guess;
+
+    #[test]
+    fn test_guess() {
+        assert_eq!(guess("OEBPS/images/Cover.JPG").unwrap(), "image/jpeg");
+        assert_eq!(guess("style.css").unwrap(), "text/css");
+        assert!(guess("chapter").is_err());
+    }
+}
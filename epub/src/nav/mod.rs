@@ -4,13 +4,31 @@ pub mod ncx;
 use crate::error::Result;
 use crate::xml::{self, XMLDocument};
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
 pub struct NavItem {
     href: String,
     text: String,
     children: Vec<NavItem>,
+    epub_type: Option<String>,
+    hidden: bool,
+    play_order: Option<u32>,
 }
 impl NavItem {
+    pub fn new(text: &str, href: &str, children: Vec<NavItem>) -> Self {
+        NavItem {
+            href: href.to_string(),
+            text: text.to_string(),
+            children,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_type(mut self, epub_type: &str) -> Self {
+        self.epub_type = Some(epub_type.to_string());
+        self
+    }
+
+    // empty for headings without a link
     pub fn href(&self) -> &str {
         &self.href
     }
@@ -20,14 +38,82 @@ impl NavItem {
     pub fn children(&self) -> &[NavItem] {
         self.children.as_slice()
     }
+
+    pub fn is_heading(&self) -> bool {
+        self.href.is_empty()
+    }
+
+    // `epub:type` of the link, e.g. `cover` or `bodymatter` in landmarks
+    pub fn epub_type(&self) -> Option<&str> {
+        self.epub_type.as_deref()
+    }
+
+    pub fn is_hidden(&self) -> bool {
+        self.hidden
+    }
+
+    // NCX playOrder
+    pub fn play_order(&self) -> Option<u32> {
+        self.play_order
+    }
 }
 
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct NavList {
+    nav_type: String,
+    title: String,
+    hidden: bool,
+    items: Vec<NavItem>,
+}
+
+impl NavList {
+    pub fn new(nav_type: &str, title: &str, items: Vec<NavItem>) -> Self {
+        NavList {
+            nav_type: nav_type.to_string(),
+            title: title.to_string(),
+            hidden: false,
+            items,
+        }
+    }
+
+    pub fn nav_type(&self) -> &str {
+        &self.nav_type
+    }
+
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+
+    pub fn is_hidden(&self) -> bool {
+        self.hidden
+    }
+
+    pub fn items(&self) -> &[NavItem] {
+        self.items.as_slice()
+    }
+}
+
+#[derive(Default)]
 pub struct Navigation {
     title: String,
     toc: Vec<NavItem>,
+    landmarks: Vec<NavItem>,
+    page_list: Vec<NavItem>,
+    others: Vec<NavList>,
+    authors: Vec<String>,
+    meta: Vec<(String, String)>,
+    warnings: Vec<String>,
 }
 
 impl Navigation {
+    pub fn new(title: &str, toc: Vec<NavItem>) -> Self {
+        Navigation {
+            title: title.to_string(),
+            toc,
+            ..Default::default()
+        }
+    }
+
     pub fn new_from_nav(content: &[u8]) -> Result<Self> {
         navigation::parse(content)
     }
@@ -43,10 +129,58 @@ impl Navigation {
     pub fn toc(&self) -> &[NavItem] {
         self.toc.as_slice()
     }
+
+    pub fn landmarks(&self) -> &[NavItem] {
+        self.landmarks.as_slice()
+    }
+
+    // print page numbers, the item text is the page label
+    pub fn page_list(&self) -> &[NavItem] {
+        self.page_list.as_slice()
+    }
+
+    // nav elements other than toc, landmarks and page-list, or NCX navList
+    pub fn others(&self) -> &[NavList] {
+        self.others.as_slice()
+    }
+
+    // NCX docAuthor
+    pub fn authors(&self) -> &[String] {
+        self.authors.as_slice()
+    }
+
+    // NCX head meta such as `dtb:uid` and `dtb:depth`
+    pub fn meta(&self, name: &str) -> Option<&str> {
+        self.meta
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    // malformed entries that were skipped or repaired while parsing
+    pub fn warnings(&self) -> &[String] {
+        self.warnings.as_slice()
+    }
+
+    pub fn to_nav_xml(&self) -> String {
+        navigation::to_xml(self)
+    }
+
+    pub fn to_ncx_xml(&self, uid: &str) -> String {
+        ncx::to_xml(self, uid)
+    }
+}
+
+fn normalize_text(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<&str>>().join(" ")
 }
 
 fn parse_title(doc: &XMLDocument) -> String {
-    match doc.find_tag("title") {
+    // the XHTML title, not a `dc:title` in some metadata, books without namespaces included
+    let title = doc
+        .find_tag_ns(xml::XHTML_NAMESPACE, "title")
+        .or_else(|| doc.find_tag_ns("", "title"));
+    match title {
         Some(tl) => xml::parse_text(&tl),
         None => String::from(""),
     }
@@ -3,53 +3,232 @@ use std::rc::Rc;
 use markup5ever_rcdom::Node;
 
 use crate::error::Result;
-use crate::nav::{parse_title, NavItem, Navigation};
+use crate::nav::{normalize_text, parse_title, NavItem, NavList, Navigation};
 use crate::xml::{self, XMLDocument};
 
-fn parse_toc(node: &Rc<Node>) -> Vec<NavItem> {
-    let mut items = Vec::new();
-    let ols = xml::find_children(node, "ol");
-    if ols.is_empty() {
-        return items;
-    }
-    for child in ols {
-        items.extend(parse_nav_ol(&child));
-    }
-    items
+fn is_hidden(node: &Rc<Node>) -> bool {
+    xml::parse_attribute(node, "hidden").is_some()
 }
 
-fn parse_nav_ol(node: &Rc<Node>) -> Vec<NavItem> {
+fn parse_ol(node: &Rc<Node>, hidden: bool) -> Vec<NavItem> {
     let mut res = Vec::new();
-    let ls = xml::find_children(node, "li");
-    if ls.is_empty() {
-        return res;
-    }
-    for li in ls {
-        if let Some(an) = xml::find_children(&li, "a").first() {
-            let href = xml::parse_attribute(an, "href").unwrap_or_default();
-            let text = xml::parse_text(an);
-            let children = parse_toc(&li);
-            let item = NavItem {
-                href,
-                text,
+    let hidden = hidden || is_hidden(node);
+    for li in xml::find_children(node, "li") {
+        let hidden = hidden || is_hidden(&li);
+        let children: Vec<NavItem> = xml::find_children(&li, "ol")
+            .iter()
+            .flat_map(|ol| parse_ol(ol, hidden))
+            .collect();
+        // a heading is a span without a link, only allowed to group children
+        let label = xml::first_child(&li, "a").or_else(|| xml::first_child(&li, "span"));
+        let item = match label {
+            Some(label) => NavItem {
+                href: xml::parse_attribute(&label, "href").unwrap_or_default(),
+                text: normalize_text(&xml::parse_all_text(&label)),
                 children,
-            };
-            res.push(item)
-        }
+                epub_type: xml::parse_attribute(&label, "type"),
+                hidden: hidden || is_hidden(&label),
+                play_order: None,
+            },
+            None if !children.is_empty() => NavItem {
+                children,
+                hidden,
+                ..Default::default()
+            },
+            None => continue,
+        };
+        res.push(item)
     }
     res
 }
 
+fn parse_heading(nav: &Rc<Node>) -> String {
+    for tag in ["h1", "h2", "h3", "h4", "h5", "h6"] {
+        if let Some(heading) = xml::first_child(nav, tag) {
+            return normalize_text(&xml::parse_all_text(&heading));
+        }
+    }
+    String::new()
+}
+
 pub fn parse(content: &[u8]) -> Result<Navigation> {
     let doc = XMLDocument::try_new(content)?;
-    let title = parse_title(&doc);
-    let mut toc = Vec::new();
+    let mut navigation = Navigation {
+        title: parse_title(&doc),
+        ..Default::default()
+    };
     for nav in doc.find_all_tag("nav") {
-        if let Some(ty) = xml::parse_attribute(&nav, "type") {
-            if ty.as_str() == "toc" {
-                toc = parse_toc(&nav)
+        let nav_type = xml::parse_attribute(&nav, "type").unwrap_or_default();
+        let hidden = is_hidden(&nav);
+        let items: Vec<NavItem> = xml::find_children(&nav, "ol")
+            .iter()
+            .flat_map(|ol| parse_ol(ol, hidden))
+            .collect();
+        let known = nav_type
+            .split_whitespace()
+            .find(|t| matches!(*t, "toc" | "landmarks" | "page-list"));
+        match known {
+            Some("toc") if navigation.toc.is_empty() => navigation.toc = items,
+            Some("landmarks") if navigation.landmarks.is_empty() => navigation.landmarks = items,
+            Some("page-list") if navigation.page_list.is_empty() => navigation.page_list = items,
+            _ => navigation.others.push(NavList {
+                nav_type,
+                title: parse_heading(&nav),
+                hidden,
+                items,
+            }),
+        }
+    }
+    Ok(navigation)
+}
+
+fn write_nav_ol(items: &[NavItem], depth: usize, res: &mut String) {
+    let indent = "  ".repeat(depth);
+    res.push_str(&format!("{}<ol>\n", indent));
+    for item in items {
+        res.push_str(&format!("{}  <li", indent));
+        if item.hidden {
+            res.push_str(" hidden=\"hidden\"");
+        }
+        res.push('>');
+        if item.is_heading() {
+            res.push_str(&format!("<span>{}</span>", xml::escape(&item.text)));
+        } else {
+            res.push_str("<a");
+            if let Some(ref t) = item.epub_type {
+                res.push_str(&format!(" epub:type=\"{}\"", xml::escape(t)));
             }
+            res.push_str(&format!(
+                " href=\"{}\">{}</a>",
+                xml::escape(&item.href),
+                xml::escape(&item.text)
+            ));
+        }
+        if !item.children.is_empty() {
+            res.push('\n');
+            write_nav_ol(&item.children, depth + 2, res);
+            res.push_str(&format!("{}  ", indent));
         }
+        res.push_str("</li>\n");
+    }
+    res.push_str(&format!("{}</ol>\n", indent));
+}
+
+fn write_nav(list: &NavList, res: &mut String) {
+    res.push_str(&format!(
+        "  <nav epub:type=\"{}\"",
+        xml::escape(&list.nav_type)
+    ));
+    if list.hidden {
+        res.push_str(" hidden=\"hidden\"");
+    }
+    res.push_str(">\n");
+    if !list.title.is_empty() {
+        res.push_str(&format!("    <h1>{}</h1>\n", xml::escape(&list.title)));
+    }
+    write_nav_ol(&list.items, 2, res);
+    res.push_str("  </nav>\n");
+}
+
+pub fn to_xml(nav: &Navigation) -> String {
+    let title = xml::escape(&nav.title);
+    let mut res = String::new();
+    res.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<!DOCTYPE html>\n");
+    res.push_str("<html xmlns=\"http://www.w3.org/1999/xhtml\" xmlns:epub=\"http://www.idpf.org/2007/ops\">\n");
+    res.push_str(&format!("<head>\n  <title>{}</title>\n</head>\n<body>\n", title));
+    res.push_str(&format!(
+        "  <nav epub:type=\"toc\" id=\"toc\">\n    <h1>{}</h1>\n",
+        title
+    ));
+    write_nav_ol(&nav.toc, 2, &mut res);
+    res.push_str("  </nav>\n");
+    if !nav.landmarks.is_empty() {
+        let mut list = NavList::new("landmarks", "", nav.landmarks.clone());
+        list.hidden = true;
+        write_nav(&list, &mut res);
+    }
+    if !nav.page_list.is_empty() {
+        let mut list = NavList::new("page-list", "", nav.page_list.clone());
+        list.hidden = true;
+        write_nav(&list, &mut res);
+    }
+    for list in nav.others.iter() {
+        write_nav(list, &mut res);
+    }
+    res.push_str("</body>\n</html>\n");
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::nav::navigation::{parse, to_xml};
+
+    const NAV: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops">
+<head><title>Contents</title></head>
+<body>
+  <nav epub:type="toc" id="toc">
+    <h1>Table of Contents</h1>
+    <ol>
+      <li><a href="c1.xhtml"><span>Chapter</span> 1</a></li>
+      <li><span>Part II</span>
+        <ol>
+          <li><a href="c2.xhtml">Chapter 2</a>
+            <ol hidden="">
+              <li><a href="c2.xhtml#s1">Section 2.1</a></li>
+            </ol>
+          </li>
+        </ol>
+      </li>
+    </ol>
+  </nav>
+  <nav epub:type="landmarks" hidden="">
+    <ol>
+      <li><a epub:type="cover" href="cover.xhtml">Cover</a></li>
+      <li><a epub:type="bodymatter" href="c1.xhtml">Start</a></li>
+    </ol>
+  </nav>
+  <nav epub:type="page-list" hidden="">
+    <ol>
+      <li><a href="c1.xhtml#p1">1</a></li>
+      <li><a href="c2.xhtml#p123">123</a></li>
+    </ol>
+  </nav>
+  <nav epub:type="lot">
+    <h2>Tables</h2>
+    <ol><li><a href="c2.xhtml#t1">Table 1</a></li></ol>
+  </nav>
+</body>
+</html>"#;
+
+    #[test]
+    fn test_parse() {
+        let nav = parse(NAV.as_bytes()).unwrap();
+        assert_eq!(nav.title(), "Contents");
+        let toc = nav.toc();
+        assert_eq!(toc.len(), 2);
+        assert_eq!(toc[0].text(), "Chapter 1");
+        assert!(toc[1].is_heading());
+        assert_eq!(toc[1].text(), "Part II");
+        let section = &toc[1].children()[0].children()[0];
+        assert_eq!(section.href(), "c2.xhtml#s1");
+        assert!(section.is_hidden());
+
+        let landmarks = nav.landmarks();
+        assert_eq!(landmarks[0].epub_type(), Some("cover"));
+        assert_eq!(landmarks[1].epub_type(), Some("bodymatter"));
+        assert_eq!(nav.page_list()[1].text(), "123");
+        assert_eq!(nav.others()[0].nav_type(), "lot");
+        assert_eq!(nav.others()[0].title(), "Tables");
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let nav = parse(NAV.as_bytes()).unwrap();
+        let copy = parse(to_xml(&nav).as_bytes()).unwrap();
+        assert_eq!(copy.toc(), nav.toc());
+        assert_eq!(copy.landmarks(), nav.landmarks());
+        assert_eq!(copy.page_list(), nav.page_list());
+        assert_eq!(copy.others(), nav.others());
     }
-    Ok(Navigation { title, toc })
 }
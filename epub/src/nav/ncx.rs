@@ -2,49 +2,337 @@ use std::rc::Rc;
 
 use markup5ever_rcdom::Node;
 
-use crate::error::{EpubError, Result};
-use crate::nav::{parse_title, NavItem, Navigation};
+use crate::error::Result;
+use crate::nav::{normalize_text, parse_title, NavItem, NavList, Navigation};
 use crate::xml::{self, XMLDocument};
 
-pub fn parse_toc(node: &Rc<Node>) -> Result<Vec<NavItem>> {
-    let mut res = Vec::new();
-    let points = xml::find_children(node, "navPoint");
-    if points.is_empty() {
-        return Ok(res);
-    }
-    for point in points {
-        let item = parse_toc_item(&point)?;
-        res.push(item)
-    }
-    Ok(res)
+fn parse_label(node: &Rc<Node>) -> Option<String> {
+    let label = xml::first_child(node, "navLabel")?;
+    let text = xml::first_child(&label, "text")?;
+    Some(normalize_text(&xml::parse_all_text(&text)))
 }
 
-pub fn parse_toc_item(node: &Rc<Node>) -> Result<NavItem> {
-    let label = xml::first_child(node, "navLabel")
-        .ok_or(EpubError::ParseError("navLabel not exists".to_string()))?;
-
-    let text = match xml::first_child(&label, "text") {
-        Some(t) => xml::parse_text(&t),
-        None => "".to_string(),
-    };
-    let content = xml::first_child(node, "content").ok_or(EpubError::ParseError(
-        "content not exist in NavPoint".to_string(),
-    ))?;
-    let href = xml::parse_attribute_must_exist(&content, "src")?;
-    let children = parse_toc(node)?;
-    Ok(NavItem {
+// navPoint, pageTarget and navTarget share the same structure
+fn parse_target(node: &Rc<Node>, tag: &str, warnings: &mut Vec<String>) -> Option<NavItem> {
+    let id = xml::parse_attribute(node, "id").unwrap_or_default();
+    let children = parse_targets(node, tag, warnings);
+    let text = parse_label(node).unwrap_or_else(|| {
+        warnings.push(format!("{} {:?} has no navLabel", tag, id));
+        String::new()
+    });
+    let href = xml::first_child(node, "content")
+        .and_then(|content| xml::parse_attribute(&content, "src"))
+        .unwrap_or_else(|| {
+            warnings.push(format!("{} {:?} has no content src", tag, id));
+            String::new()
+        });
+    if text.is_empty() && href.is_empty() && children.is_empty() {
+        warnings.push(format!("{} {:?} is empty, skipped", tag, id));
+        return None;
+    }
+    let play_order = xml::parse_attribute(node, "playOrder").and_then(|v| v.trim().parse().ok());
+    Some(NavItem {
         href,
         text,
         children,
+        epub_type: xml::parse_attribute(node, "type")
+            .or_else(|| xml::parse_attribute(node, "class")),
+        play_order,
+        ..Default::default()
     })
 }
 
+fn parse_targets(node: &Rc<Node>, tag: &str, warnings: &mut Vec<String>) -> Vec<NavItem> {
+    let mut items: Vec<NavItem> = xml::find_children(node, tag)
+        .iter()
+        .filter_map(|child| parse_target(child, tag, warnings))
+        .collect();
+    if items.iter().all(|item| item.play_order.is_some()) {
+        items.sort_by_key(|item| item.play_order);
+    }
+    items
+}
+
 pub fn parse(content: &[u8]) -> Result<Navigation> {
     let doc = XMLDocument::try_new(content)?;
-    let title = parse_title(&doc);
-    let mut toc = Vec::new();
+    let mut navigation = Navigation::default();
+    let mut warnings = Vec::new();
+    if let Some(head) = doc.find_tag("head") {
+        for meta in xml::find_children(&head, "meta") {
+            if let Some(name) = xml::parse_attribute(&meta, "name") {
+                let content = xml::parse_attribute(&meta, "content").unwrap_or_default();
+                navigation.meta.push((name, content));
+            }
+        }
+    }
+    navigation.title = match doc.find_tag("docTitle") {
+        Some(title) => parse_label_text(&title),
+        None => parse_title(&doc),
+    };
+    for author in doc.find_all_tag("docAuthor") {
+        navigation.authors.push(parse_label_text(&author));
+    }
     if let Some(root) = doc.find_tag("navMap") {
-        toc = parse_toc(&root)?;
+        navigation.toc = parse_targets(&root, "navPoint", &mut warnings);
+    }
+    if let Some(root) = doc.find_tag("pageList") {
+        navigation.page_list = parse_targets(&root, "pageTarget", &mut warnings);
+    }
+    for root in doc.find_all_tag("navList") {
+        let items = parse_targets(&root, "navTarget", &mut warnings);
+        let list = NavList::new(
+            xml::parse_attribute(&root, "class")
+                .unwrap_or_default()
+                .as_str(),
+            parse_label(&root).unwrap_or_default().as_str(),
+            items,
+        );
+        navigation.others.push(list);
+    }
+    navigation.warnings = warnings;
+    Ok(navigation)
+}
+
+// docTitle and docAuthor hold their text directly
+fn parse_label_text(node: &Rc<Node>) -> String {
+    match xml::first_child(node, "text") {
+        Some(text) => normalize_text(&xml::parse_all_text(&text)),
+        None => String::new(),
+    }
+}
+
+fn depth(items: &[NavItem]) -> usize {
+    items
+        .iter()
+        .map(|item| depth(&item.children) + 1)
+        .max()
+        .unwrap_or(0)
+}
+
+// playOrder follows the first appearance of each target, so points sharing a target share it
+#[derive(Default)]
+struct PlayOrder {
+    targets: Vec<String>,
+    points: usize,
+}
+
+impl PlayOrder {
+    fn get(&mut self, href: &str) -> usize {
+        match self.targets.iter().position(|t| t == href) {
+            Some(i) => i + 1,
+            None => {
+                self.targets.push(href.to_string());
+                self.targets.len()
+            }
+        }
+    }
+}
+
+// a heading without a link points at its first linked descendant
+fn link_target(item: &NavItem) -> Option<&str> {
+    if !item.href.is_empty() {
+        return Some(&item.href);
+    }
+    item.children.iter().find_map(link_target)
+}
+
+fn write_targets(
+    items: &[NavItem],
+    tag: &str,
+    indent: usize,
+    order: &mut PlayOrder,
+    res: &mut String,
+) {
+    let pad = "  ".repeat(indent);
+    for item in items {
+        let target = link_target(item);
+        let play_order = order.get(target.unwrap_or_default());
+        order.points += 1;
+        res.push_str(&format!("{}<{} id=\"{}-{}\"", pad, tag, tag, order.points));
+        if tag == "pageTarget" {
+            // type is required on pageTarget, value only makes sense for numeric pages
+            let t = item.epub_type.as_deref().unwrap_or("normal");
+            res.push_str(&format!(" type=\"{}\"", xml::escape(t)));
+            if item.text.parse::<u32>().is_ok() {
+                res.push_str(&format!(" value=\"{}\"", item.text));
+            }
+        } else if let Some(t) = &item.epub_type {
+            res.push_str(&format!(" class=\"{}\"", xml::escape(t)));
+        }
+        res.push_str(&format!(" playOrder=\"{}\">\n", play_order));
+        res.push_str(&format!(
+            "{}  <navLabel><text>{}</text></navLabel>\n",
+            pad,
+            xml::escape(&item.text)
+        ));
+        if let Some(target) = target {
+            res.push_str(&format!(
+                "{}  <content src=\"{}\"/>\n",
+                pad,
+                xml::escape(target)
+            ));
+        }
+        write_targets(&item.children, tag, indent + 1, order, res);
+        res.push_str(&format!("{}</{}>\n", pad, tag));
+    }
+}
+
+fn max_page_number(items: &[NavItem]) -> u32 {
+    items
+        .iter()
+        .filter_map(|item| item.text.parse::<u32>().ok())
+        .max()
+        .unwrap_or(0)
+}
+
+pub fn to_xml(nav: &Navigation, uid: &str) -> String {
+    let mut res = String::new();
+    res.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    res.push_str("<ncx xmlns=\"http://www.daisy.org/z3986/2005/ncx/\" version=\"2005-1\">\n");
+    res.push_str("  <head>\n");
+    res.push_str(&format!(
+        "    <meta name=\"dtb:uid\" content=\"{}\"/>\n",
+        xml::escape(uid)
+    ));
+    res.push_str(&format!(
+        "    <meta name=\"dtb:depth\" content=\"{}\"/>\n",
+        depth(&nav.toc).max(1)
+    ));
+    res.push_str(&format!(
+        "    <meta name=\"dtb:totalPageCount\" content=\"{}\"/>\n",
+        nav.page_list.len()
+    ));
+    res.push_str(&format!(
+        "    <meta name=\"dtb:maxPageNumber\" content=\"{}\"/>\n",
+        max_page_number(&nav.page_list)
+    ));
+    res.push_str("  </head>\n");
+    res.push_str(&format!(
+        "  <docTitle><text>{}</text></docTitle>\n",
+        xml::escape(&nav.title)
+    ));
+    for author in nav.authors.iter() {
+        res.push_str(&format!(
+            "  <docAuthor><text>{}</text></docAuthor>\n",
+            xml::escape(author)
+        ));
+    }
+    let mut order = PlayOrder::default();
+    res.push_str("  <navMap>\n");
+    write_targets(&nav.toc, "navPoint", 2, &mut order, &mut res);
+    res.push_str("  </navMap>\n");
+    if !nav.page_list.is_empty() {
+        res.push_str("  <pageList>\n");
+        write_targets(&nav.page_list, "pageTarget", 2, &mut order, &mut res);
+        res.push_str("  </pageList>\n");
+    }
+    for list in nav.others.iter() {
+        res.push_str(&format!(
+            "  <navList class=\"{}\">\n",
+            xml::escape(list.nav_type())
+        ));
+        res.push_str(&format!(
+            "    <navLabel><text>{}</text></navLabel>\n",
+            xml::escape(list.title())
+        ));
+        write_targets(list.items(), "navTarget", 2, &mut order, &mut res);
+        res.push_str("  </navList>\n");
+    }
+    res.push_str("</ncx>\n");
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::nav::{NavItem, Navigation};
+
+    const NCX: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<ncx xmlns="http://www.daisy.org/z3986/2005/ncx/" version="2005-1">
+  <head>
+    <meta name="dtb:uid" content="urn:uuid:ncx-test"/>
+    <meta name="dtb:depth" content="2"/>
+  </head>
+  <docTitle><text> NCX   Test </text></docTitle>
+  <docAuthor><text>Jane Doe</text></docAuthor>
+  <navMap>
+    <navPoint id="p2" playOrder="3">
+      <navLabel><text>Two</text></navLabel>
+      <content src="c2.xhtml"/>
+    </navPoint>
+    <navPoint id="p1" playOrder="1">
+      <navLabel><text>One</text></navLabel>
+      <content src="c1.xhtml"/>
+      <navPoint id="p11" playOrder="2">
+        <content src="c1.xhtml#s1"/>
+      </navPoint>
+    </navPoint>
+    <navPoint id="broken" playOrder="4">
+      <navLabel><text>Part</text></navLabel>
+    </navPoint>
+  </navMap>
+  <pageList>
+    <pageTarget id="page1" type="normal" value="1" playOrder="5">
+      <navLabel><text>1</text></navLabel>
+      <content src="c1.xhtml#page1"/>
+    </pageTarget>
+  </pageList>
+  <navList class="lot">
+    <navLabel><text>Tables</text></navLabel>
+    <navTarget id="t1" playOrder="6">
+      <navLabel><text>Table 1</text></navLabel>
+      <content src="c2.xhtml#t1"/>
+    </navTarget>
+  </navList>
+</ncx>"#;
+
+    #[test]
+    fn test_parse() {
+        let nav = Navigation::new_from_ncx(NCX.as_bytes()).unwrap();
+        assert_eq!(nav.title(), "NCX Test");
+        assert_eq!(nav.authors(), ["Jane Doe"]);
+        assert_eq!(nav.meta("dtb:uid"), Some("urn:uuid:ncx-test"));
+
+        let toc = nav.toc();
+        assert_eq!(toc.len(), 3);
+        assert_eq!(toc[0].text(), "One");
+        assert_eq!(toc[0].play_order(), Some(1));
+        assert_eq!(toc[0].children()[0].href(), "c1.xhtml#s1");
+        assert_eq!(toc[0].children()[0].text(), "");
+        assert_eq!(toc[1].text(), "Two");
+        assert!(toc[2].is_heading());
+        assert_eq!(nav.warnings().len(), 2);
+
+        assert_eq!(nav.page_list()[0].text(), "1");
+        assert_eq!(nav.page_list()[0].epub_type(), Some("normal"));
+        assert_eq!(nav.others()[0].nav_type(), "lot");
+        assert_eq!(nav.others()[0].title(), "Tables");
+        assert_eq!(nav.others()[0].items()[0].href(), "c2.xhtml#t1");
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let nav = Navigation::new_from_ncx(NCX.as_bytes()).unwrap();
+        let xml = nav.to_ncx_xml("urn:uuid:ncx-test");
+        let parsed = Navigation::new_from_ncx(xml.as_bytes()).unwrap();
+        assert_eq!(parsed.title(), nav.title());
+        assert_eq!(parsed.authors(), nav.authors());
+        assert_eq!(parsed.toc()[0].children()[0].play_order(), Some(2));
+        assert_eq!(parsed.page_list().len(), 1);
+        assert_eq!(parsed.others()[0].items()[0].text(), "Table 1");
+        assert_eq!(parsed.meta("dtb:totalPageCount"), Some("1"));
+        assert!(!xml.contains("src=\"\""));
+
+        let nav = Navigation::new(
+            "Headings",
+            vec![NavItem::new(
+                "Part",
+                "",
+                vec![NavItem::new("One", "c1.xhtml", Vec::new())],
+            )],
+        );
+        let parsed = Navigation::new_from_ncx(nav.to_ncx_xml("uid").as_bytes()).unwrap();
+        assert_eq!(parsed.toc()[0].href(), "c1.xhtml");
+        assert_eq!(parsed.toc()[0].play_order(), Some(1));
+        assert_eq!(parsed.toc()[0].children()[0].play_order(), Some(1));
     }
-    Ok(Navigation { title, toc })
 }
@@ -1,23 +1,268 @@
-use std::collections::HashMap;
 use std::rc::Rc;
 
 use markup5ever_rcdom::{Node, NodeData};
 
+use crate::encoding;
 use crate::error::{EpubError, Result};
+use crate::media_type;
+use crate::repair::{Repair, WarningKind};
 use crate::xml::{self, XMLDocument};
 
-#[derive(Debug, Default)]
-#[allow(dead_code)]
+pub const DC_NAMESPACE: &str = "http://purl.org/dc/elements/1.1/";
+pub const OPF_NAMESPACE: &str = "http://www.idpf.org/2007/opf";
+pub const NCX_MEDIA_TYPE: &str = "application/x-dtbncx+xml";
+pub const XHTML_MEDIA_TYPE: &str = "application/xhtml+xml";
+pub const SVG_MEDIA_TYPE: &str = "image/svg+xml";
+
+// media types every reading system supports, other resources need a fallback
+pub const CORE_MEDIA_TYPES: &[&str] = &[
+    "image/gif",
+    "image/jpeg",
+    "image/png",
+    "image/svg+xml",
+    "image/webp",
+    "audio/mpeg",
+    "audio/mp4",
+    "audio/ogg",
+    "text/css",
+    "font/ttf",
+    "font/otf",
+    "font/woff",
+    "font/woff2",
+    "application/font-sfnt",
+    "application/font-woff",
+    "application/vnd.ms-opentype",
+    "application/xhtml+xml",
+    "application/javascript",
+    "application/ecmascript",
+    "text/javascript",
+    "application/x-dtbncx+xml",
+    "application/smil+xml",
+    "application/pls+xml",
+];
+
+// video is exempt from fallbacks, reading systems that can not play it show its poster or nothing
+pub fn is_core_media_type(media_type: &str) -> bool {
+    CORE_MEDIA_TYPES.contains(&media_type) || media_type.starts_with("video/")
+}
+
+// media types that may be in the spine without a fallback
+pub fn is_content_document(media_type: &str) -> bool {
+    media_type == XHTML_MEDIA_TYPE || media_type == SVG_MEDIA_TYPE
+}
+
+fn push_attribute(res: &mut String, name: &str, value: Option<&str>) {
+    if let Some(v) = value {
+        res.push_str(&format!(" {}=\"{}\"", name, xml::escape(v)));
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
 pub struct MetaItem {
     ns: String,
+    prefix: Option<String>,
     name: String,
     content: String,
-    attrs: HashMap<String, String>,
+    attrs: Vec<(String, String)>,
+    // the position among the `<metadata>` children of the package it was parsed from
+    source: Option<usize>,
+}
+
+impl MetaItem {
+    pub fn new_dc(name: &str, content: &str) -> Self {
+        MetaItem {
+            ns: DC_NAMESPACE.to_string(),
+            prefix: Some("dc".to_string()),
+            name: name.to_string(),
+            content: content.to_string(),
+            attrs: Vec::new(),
+            source: None,
+        }
+    }
+
+    pub fn new_opf(name: &str, content: &str) -> Self {
+        MetaItem {
+            ns: OPF_NAMESPACE.to_string(),
+            prefix: None,
+            name: name.to_string(),
+            content: content.to_string(),
+            attrs: Vec::new(),
+            source: None,
+        }
+    }
+
+    pub fn new_meta(property: &str, content: &str) -> Self {
+        MetaItem::new_opf("meta", content).with_attribute("property", property)
+    }
+
+    pub fn with_attribute(mut self, name: &str, value: &str) -> Self {
+        self.attrs.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn ns(&self) -> &str {
+        &self.ns
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn content(&self) -> &str {
+        &self.content
+    }
+
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        self.attrs
+            .iter()
+            .find(|(k, _)| k == name || k.rsplit(':').next() == Some(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn id(&self) -> Option<&str> {
+        self.attribute("id")
+    }
+
+    pub fn property(&self) -> Option<&str> {
+        self.attribute("property")
+    }
+
+    // the id this item refines, without the leading `#`
+    pub fn refines(&self) -> Option<&str> {
+        self.attribute("refines")
+            .map(|r| r.strip_prefix('#').unwrap_or(r))
+    }
+
+    pub fn is_dc(&self) -> bool {
+        self.ns == DC_NAMESPACE || self.prefix.as_deref() == Some("dc")
+    }
+
+    pub fn value(&self) -> &str {
+        self.content.trim()
+    }
+
+    fn qualified_name(&self) -> String {
+        match self.prefix {
+            Some(ref prefix) => format!("{}:{}", prefix, self.name),
+            None => self.name.clone(),
+        }
+    }
+
+    pub fn to_xml(&self) -> String {
+        let tag = self.qualified_name();
+        let mut res = format!("<{}", tag);
+        for (k, v) in self.attrs.iter() {
+            push_attribute(&mut res, k, Some(v));
+        }
+        if self.content.is_empty() {
+            res.push_str("/>");
+        } else {
+            res.push_str(&format!(">{}</{}>", xml::escape(&self.content), tag));
+        }
+        res
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Creator {
+    name: String,
+    role: Option<String>,
+    // the `scheme` of the role refinement, usually `marc:relators`
+    role_scheme: Option<String>,
+    file_as: Option<String>,
+    display_seq: Option<u32>,
+}
+
+impl Creator {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn role(&self) -> Option<&str> {
+        self.role.as_deref()
+    }
+
+    pub fn role_scheme(&self) -> Option<&str> {
+        self.role_scheme.as_deref()
+    }
+
+    pub fn file_as(&self) -> Option<&str> {
+        self.file_as.as_deref()
+    }
+
+    pub fn display_seq(&self) -> Option<u32> {
+        self.display_seq
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Identifier {
+    id: Option<String>,
+    value: String,
+    // the `identifier-type` refinement, like `15` for an ISBN-13, or the EPUB 2 `opf:scheme`
+    identifier_type: Option<String>,
+    // the `scheme` of the `identifier-type` refinement, like `onix:codelist5`
+    scheme: Option<String>,
+}
+
+impl Identifier {
+    pub fn id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    pub fn identifier_type(&self) -> Option<&str> {
+        self.identifier_type.as_deref()
+    }
+
+    pub fn scheme(&self) -> Option<&str> {
+        self.scheme.as_deref()
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Title {
+    value: String,
+    title_type: Option<String>,
+    display_seq: Option<u32>,
+}
+
+impl Title {
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    pub fn title_type(&self) -> Option<&str> {
+        self.title_type.as_deref()
+    }
+
+    pub fn display_seq(&self) -> Option<u32> {
+        self.display_seq
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Date {
+    value: String,
+    event: Option<String>,
+}
+
+impl Date {
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    pub fn event(&self) -> Option<&str> {
+        self.event.as_deref()
+    }
 }
 
 #[derive(Debug, Default)]
 pub struct MetaData {
-    items: HashMap<String, MetaItem>,
+    items: Vec<MetaItem>,
 }
 
 impl MetaData {
@@ -27,38 +272,409 @@ impl MetaData {
             "metadata is null in package format".to_string(),
         ))?;
 
-        for child in metadata_node
+        for (i, child) in metadata_node
             .children
             .borrow()
             .iter()
             .filter(|ch| matches!(ch.data, NodeData::Element { .. }))
+            .enumerate()
         {
-            let mut metaitem = MetaItem::default();
+            let mut metaitem = MetaItem {
+                source: Some(i),
+                ..Default::default()
+            };
             if let NodeData::Element {
                 ref name,
                 ref attrs,
                 ..
             } = child.data
             {
-                let tagname = name.local.to_string();
-                metaitem.name = tagname.clone();
+                metaitem.ns = name.ns.to_string();
+                metaitem.prefix = name.prefix.as_ref().map(|p| p.to_string());
+                metaitem.name = name.local.to_string();
                 for attr in attrs.borrow().iter() {
-                    let name = attr.name.local.to_string();
-                    let value = attr.value.to_string();
-                    metaitem.attrs.insert(name, value);
+                    let key = match attr.name.prefix {
+                        Some(ref prefix) => format!("{}:{}", prefix, attr.name.local),
+                        None => attr.name.local.to_string(),
+                    };
+                    metaitem.attrs.push((key, attr.value.to_string()));
                 }
                 metaitem.content = xml::parse_text(child);
-                metadata.items.insert(tagname, metaitem);
+                metadata.items.push(metaitem);
             }
         }
 
         Ok(metadata)
     }
+
+    pub fn items(&self) -> &[MetaItem] {
+        self.items.as_slice()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&MetaItem> {
+        self.items.iter().find(|item| item.name == name)
+    }
+
+    pub fn find(&self, ns: &str, name: &str) -> impl Iterator<Item = &MetaItem> {
+        let ns = ns.to_string();
+        let name = name.to_string();
+        self.items
+            .iter()
+            .filter(move |item| item.ns == ns && item.name == name)
+    }
+
+    pub fn dc_items(&self, name: &str) -> impl Iterator<Item = &MetaItem> {
+        let name = name.to_string();
+        self.items
+            .iter()
+            .filter(move |item| item.is_dc() && item.name == name)
+    }
+
+    fn dc_value(&self, name: &str) -> Option<&str> {
+        self.dc_items(name).next().map(|item| item.value())
+    }
+
+    // primary metadata: EPUB 3 `<meta property>` or EPUB 2 `<meta name content>`
+    pub fn meta(&self, property: &str) -> Option<&str> {
+        self.items
+            .iter()
+            .filter(|item| item.name == "meta" && item.refines().is_none())
+            .find_map(|item| {
+                if item.property() == Some(property) {
+                    Some(item.value())
+                } else if item.attribute("name") == Some(property) {
+                    item.attribute("content")
+                } else {
+                    None
+                }
+            })
+    }
+
+    // every `<meta refines="#id">` of an item, refinements of refinements are reached through their own id
+    pub fn refinements(&self, id: &str) -> Vec<&MetaItem> {
+        self.items
+            .iter()
+            .filter(|item| item.refines() == Some(id))
+            .collect()
+    }
+
+    pub fn refinement(&self, id: &str, property: &str) -> Option<&str> {
+        self.refinement_item(id, property).map(|item| item.value())
+    }
+
+    fn refinement_item(&self, id: &str, property: &str) -> Option<&MetaItem> {
+        self.refinements(id)
+            .into_iter()
+            .find(|item| item.property() == Some(property))
+    }
+
+    // the `scheme` attribute of the refinement, EPUB 2 attributes have none
+    fn refinement_scheme(&self, item: &MetaItem, property: &str) -> Option<String> {
+        item.id()
+            .and_then(|id| self.refinement_item(id, property))
+            .and_then(|refinement| refinement.attribute("scheme"))
+            .map(|v| v.to_string())
+    }
+
+    // EPUB 3 refinement first, then the EPUB 2 `opf:` attribute
+    fn refined(&self, item: &MetaItem, property: &str, attribute: &str) -> Option<String> {
+        item.id()
+            .and_then(|id| self.refinement(id, property))
+            .or_else(|| item.attribute(attribute))
+            .map(|v| v.to_string())
+    }
+
+    fn display_seq(&self, item: &MetaItem) -> Option<u32> {
+        item.id()
+            .and_then(|id| self.refinement(id, "display-seq"))
+            .and_then(|v| v.parse().ok())
+    }
+
+    fn creators(&self, name: &str) -> Vec<Creator> {
+        let mut creators: Vec<Creator> = self
+            .dc_items(name)
+            .map(|item| Creator {
+                name: item.value().to_string(),
+                role: self.refined(item, "role", "role"),
+                role_scheme: self.refinement_scheme(item, "role"),
+                file_as: self.refined(item, "file-as", "file-as"),
+                display_seq: self.display_seq(item),
+            })
+            .collect();
+        creators.sort_by_key(|c| c.display_seq.unwrap_or(u32::MAX));
+        creators
+    }
+
+    pub fn authors(&self) -> Vec<Creator> {
+        self.creators("creator")
+    }
+
+    pub fn contributors(&self) -> Vec<Creator> {
+        self.creators("contributor")
+    }
+
+    pub fn titles(&self) -> Vec<Title> {
+        let mut titles: Vec<Title> = self
+            .dc_items("title")
+            .map(|item| Title {
+                value: item.value().to_string(),
+                title_type: item
+                    .id()
+                    .and_then(|id| self.refinement(id, "title-type"))
+                    .map(|v| v.to_string()),
+                display_seq: self.display_seq(item),
+            })
+            .collect();
+        titles.sort_by_key(|t| t.display_seq.unwrap_or(u32::MAX));
+        titles
+    }
+
+    pub fn identifiers(&self) -> Vec<Identifier> {
+        self.dc_items("identifier")
+            .map(|item| Identifier {
+                id: item.id().map(|v| v.to_string()),
+                value: item.value().to_string(),
+                identifier_type: self.refined(item, "identifier-type", "scheme"),
+                scheme: self.refinement_scheme(item, "identifier-type"),
+            })
+            .collect()
+    }
+
+    pub fn identifier(&self, id: &str) -> Option<&str> {
+        self.dc_items("identifier")
+            .find(|item| item.id() == Some(id))
+            .map(|item| item.value())
+    }
+
+    pub fn languages(&self) -> Vec<&str> {
+        self.dc_items("language").map(|item| item.value()).collect()
+    }
+
+    pub fn subjects(&self) -> Vec<&str> {
+        self.dc_items("subject").map(|item| item.value()).collect()
+    }
+
+    pub fn publisher(&self) -> Option<&str> {
+        self.dc_value("publisher")
+    }
+
+    pub fn dates(&self) -> Vec<Date> {
+        self.dc_items("date")
+            .map(|item| Date {
+                value: item.value().to_string(),
+                event: item.attribute("event").map(|v| v.to_string()),
+            })
+            .collect()
+    }
+
+    pub fn modified(&self) -> Option<&str> {
+        self.meta("dcterms:modified")
+    }
+
+    pub fn rights(&self) -> Option<&str> {
+        self.dc_value("rights")
+    }
+
+    pub fn description(&self) -> Option<&str> {
+        self.dc_value("description")
+    }
+
+    // the position of the title with title-type `main`, otherwise of the first one
+    fn title_position(&self) -> Option<usize> {
+        let titles = || {
+            self.items
+                .iter()
+                .enumerate()
+                .filter(|(_, item)| item.is_dc() && item.name == "title")
+        };
+        let main = titles().find(|(_, item)| {
+            item.id()
+                .and_then(|id| self.refinement(id, "title-type"))
+                == Some("main")
+        });
+        main.or_else(|| titles().next()).map(|(i, _)| i)
+    }
+
     pub fn title(&self) -> &str {
-        if let Some(item) = self.items.get("title") {
-            return &item.content;
+        match self.title_position() {
+            Some(i) => self.items[i].value(),
+            None => "",
+        }
+    }
+
+    // replace the content of the title `title` returns, adds a `dc:title` when missing
+    pub fn set_title(&mut self, title: &str) {
+        match self.title_position() {
+            Some(i) => self.items[i].content = title.to_string(),
+            None => self.items.push(MetaItem::new_dc("title", title)),
+        }
+    }
+
+    // replace the content of the first `dc:` item `name`, adds one when missing
+    pub fn set_item(&mut self, name: &str, content: &str) {
+        match self
+            .items
+            .iter_mut()
+            .find(|item| item.is_dc() && item.name == name)
+        {
+            Some(item) => item.content = content.to_string(),
+            None => self.items.push(MetaItem::new_dc(name, content)),
+        }
+    }
+
+    pub fn add_item(&mut self, item: MetaItem) {
+        self.items.push(item)
+    }
+
+    pub fn remove_items(&mut self, name: &str) {
+        self.items.retain(|item| item.name != name)
+    }
+
+    pub fn to_xml(&self) -> String {
+        let mut res = String::new();
+        for item in self.items.iter() {
+            res.push_str("\n    ");
+            res.push_str(&item.to_xml());
+        }
+        res.push_str("\n  ");
+        res
+    }
+}
+
+// a value of the manifest item `properties` attribute
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ManifestProperty {
+    CoverImage,
+    Nav,
+    Scripted,
+    Mathml,
+    Svg,
+    RemoteResources,
+    Switch,
+    Other(String),
+}
+
+impl ManifestProperty {
+    pub fn new(value: &str) -> Self {
+        match value {
+            "cover-image" => ManifestProperty::CoverImage,
+            "nav" => ManifestProperty::Nav,
+            "scripted" => ManifestProperty::Scripted,
+            "mathml" => ManifestProperty::Mathml,
+            "svg" => ManifestProperty::Svg,
+            "remote-resources" => ManifestProperty::RemoteResources,
+            "switch" => ManifestProperty::Switch,
+            other => ManifestProperty::Other(other.to_string()),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            ManifestProperty::CoverImage => "cover-image",
+            ManifestProperty::Nav => "nav",
+            ManifestProperty::Scripted => "scripted",
+            ManifestProperty::Mathml => "mathml",
+            ManifestProperty::Svg => "svg",
+            ManifestProperty::RemoteResources => "remote-resources",
+            ManifestProperty::Switch => "switch",
+            ManifestProperty::Other(value) => value,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    PrePaginated,
+    Reflowable,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Orientation {
+    Auto,
+    Landscape,
+    Portrait,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Spread {
+    Auto,
+    Both,
+    Landscape,
+    None,
+    Portrait,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flow {
+    Auto,
+    Paginated,
+    ScrolledContinuous,
+    ScrolledDoc,
+}
+
+// a value of the itemref `properties` attribute, `rendition:` ones override the package settings
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SpineProperty {
+    PageSpreadLeft,
+    PageSpreadRight,
+    PageSpreadCenter,
+    Layout(Layout),
+    Orientation(Orientation),
+    Spread(Spread),
+    Flow(Flow),
+    AlignXCenter,
+    Other(String),
+}
+
+impl SpineProperty {
+    pub fn new(value: &str) -> Self {
+        match value {
+            "page-spread-left" | "rendition:page-spread-left" => SpineProperty::PageSpreadLeft,
+            "page-spread-right" | "rendition:page-spread-right" => SpineProperty::PageSpreadRight,
+            "page-spread-center" | "rendition:page-spread-center" => {
+                SpineProperty::PageSpreadCenter
+            }
+            "rendition:layout-pre-paginated" => SpineProperty::Layout(Layout::PrePaginated),
+            "rendition:layout-reflowable" => SpineProperty::Layout(Layout::Reflowable),
+            "rendition:orientation-auto" => SpineProperty::Orientation(Orientation::Auto),
+            "rendition:orientation-landscape" => SpineProperty::Orientation(Orientation::Landscape),
+            "rendition:orientation-portrait" => SpineProperty::Orientation(Orientation::Portrait),
+            "rendition:spread-auto" => SpineProperty::Spread(Spread::Auto),
+            "rendition:spread-both" => SpineProperty::Spread(Spread::Both),
+            "rendition:spread-landscape" => SpineProperty::Spread(Spread::Landscape),
+            "rendition:spread-none" => SpineProperty::Spread(Spread::None),
+            "rendition:spread-portrait" => SpineProperty::Spread(Spread::Portrait),
+            "rendition:flow-auto" => SpineProperty::Flow(Flow::Auto),
+            "rendition:flow-paginated" => SpineProperty::Flow(Flow::Paginated),
+            "rendition:flow-scrolled-continuous" => SpineProperty::Flow(Flow::ScrolledContinuous),
+            "rendition:flow-scrolled-doc" => SpineProperty::Flow(Flow::ScrolledDoc),
+            "rendition:align-x-center" => SpineProperty::AlignXCenter,
+            other => SpineProperty::Other(other.to_string()),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            SpineProperty::PageSpreadLeft => "page-spread-left",
+            SpineProperty::PageSpreadRight => "page-spread-right",
+            SpineProperty::PageSpreadCenter => "rendition:page-spread-center",
+            SpineProperty::Layout(Layout::PrePaginated) => "rendition:layout-pre-paginated",
+            SpineProperty::Layout(Layout::Reflowable) => "rendition:layout-reflowable",
+            SpineProperty::Orientation(Orientation::Auto) => "rendition:orientation-auto",
+            SpineProperty::Orientation(Orientation::Landscape) => "rendition:orientation-landscape",
+            SpineProperty::Orientation(Orientation::Portrait) => "rendition:orientation-portrait",
+            SpineProperty::Spread(Spread::Auto) => "rendition:spread-auto",
+            SpineProperty::Spread(Spread::Both) => "rendition:spread-both",
+            SpineProperty::Spread(Spread::Landscape) => "rendition:spread-landscape",
+            SpineProperty::Spread(Spread::None) => "rendition:spread-none",
+            SpineProperty::Spread(Spread::Portrait) => "rendition:spread-portrait",
+            SpineProperty::Flow(Flow::Auto) => "rendition:flow-auto",
+            SpineProperty::Flow(Flow::Paginated) => "rendition:flow-paginated",
+            SpineProperty::Flow(Flow::ScrolledContinuous) => "rendition:flow-scrolled-continuous",
+            SpineProperty::Flow(Flow::ScrolledDoc) => "rendition:flow-scrolled-doc",
+            SpineProperty::AlignXCenter => "rendition:align-x-center",
+            SpineProperty::Other(value) => value,
         }
-        ""
     }
 }
 
@@ -74,21 +690,85 @@ pub struct ManifestItem {
 }
 
 impl ManifestItem {
-    fn parse(node: &Rc<Node>) -> Result<Self> {
-        let id = xml::parse_attribute_must_exist(node, "id")?;
-        let href = xml::parse_attribute_must_exist(node, "href")?;
-        let media_type = xml::parse_attribute_must_exist(node, "media-type")?;
+    // `None` when a lenient `repair` skipped the item
+    fn parse(node: &Rc<Node>, path: &str, repair: &mut Repair) -> Result<Option<Self>> {
+        let id = xml::parse_attribute(node, "id").unwrap_or_default();
+        let context = format!("manifest item {:?} skipped", id);
+        let (id, href) = match (
+            xml::parse_attribute_must_exist(node, "id"),
+            xml::parse_attribute_must_exist(node, "href"),
+        ) {
+            (Ok(id), Ok(href)) => (id, href),
+            (Err(e), _) | (_, Err(e)) => {
+                repair.recover(WarningKind::InvalidItem, path, &context, e)?;
+                return Ok(None);
+            }
+        };
+        let media_type = match xml::parse_attribute_must_exist(node, "media-type") {
+            Ok(media_type) => media_type,
+            Err(e) => match media_type::guess(&href) {
+                Ok(guess) if repair.is_lenient() => {
+                    let message =
+                        format!("media-type of manifest item {:?} guessed as {}", id, guess);
+                    repair.warn(WarningKind::RepairedItem, path, &message);
+                    guess.to_string()
+                }
+                _ => {
+                    repair.recover(WarningKind::InvalidItem, path, &context, e)?;
+                    return Ok(None);
+                }
+            },
+        };
         let fallback = xml::parse_attribute(node, "fallback");
         let properties = xml::parse_attribute(node, "properties");
         let media_overlay = xml::parse_attribute(node, "media-overlay");
-        Ok(ManifestItem {
+        Ok(Some(ManifestItem {
             id,
             href,
             media_type,
             fallback,
             properties,
             media_overlay,
-        })
+        }))
+    }
+
+    pub fn new(id: &str, href: &str, media_type: &str) -> Self {
+        ManifestItem {
+            id: id.to_string(),
+            href: href.to_string(),
+            media_type: media_type.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_properties(mut self, properties: &str) -> Self {
+        self.properties = Some(properties.to_string());
+        self
+    }
+
+    pub fn with_fallback(mut self, fallback: &str) -> Self {
+        self.fallback = Some(fallback.to_string());
+        self
+    }
+
+    pub fn has_property(&self, property: &str) -> bool {
+        match self.properties {
+            Some(ref p) => p.split_whitespace().any(|v| v == property),
+            None => false,
+        }
+    }
+
+    pub fn properties(&self) -> Vec<ManifestProperty> {
+        self.properties
+            .as_deref()
+            .unwrap_or_default()
+            .split_whitespace()
+            .map(ManifestProperty::new)
+            .collect()
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
     }
 
     pub fn href(&self) -> &str {
@@ -98,6 +778,22 @@ impl ManifestItem {
     pub fn media_type(&self) -> &str {
         &self.media_type
     }
+
+    pub fn fallback(&self) -> Option<&str> {
+        self.fallback.as_deref()
+    }
+
+    pub fn to_xml(&self) -> String {
+        let mut res = String::from("<item");
+        push_attribute(&mut res, "id", Some(&self.id));
+        push_attribute(&mut res, "href", Some(&self.href));
+        push_attribute(&mut res, "media-type", Some(&self.media_type));
+        push_attribute(&mut res, "fallback", self.fallback.as_deref());
+        push_attribute(&mut res, "properties", self.properties.as_deref());
+        push_attribute(&mut res, "media-overlay", self.media_overlay.as_deref());
+        res.push_str("/>");
+        res
+    }
 }
 
 #[derive(Debug, Default)]
@@ -123,12 +819,56 @@ impl ItemRef {
         })
     }
 
+    pub fn new(idref: &str) -> Self {
+        ItemRef {
+            idref: idref.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_linear(mut self, linear: bool) -> Self {
+        self.linear = match linear {
+            true => None,
+            false => Some("no".to_string()),
+        };
+        self
+    }
+
+    pub fn with_properties(mut self, properties: &str) -> Self {
+        self.properties = Some(properties.to_string());
+        self
+    }
+
     pub fn idref(&self) -> &str {
         &self.idref
     }
     pub fn id(&self) -> &Option<String> {
         &self.id
     }
+
+    // `linear="no"` items, like popup footnotes, are not part of the default reading order
+    pub fn is_linear(&self) -> bool {
+        self.linear.as_deref().map(str::trim) != Some("no")
+    }
+
+    pub fn properties(&self) -> Vec<SpineProperty> {
+        self.properties
+            .as_deref()
+            .unwrap_or_default()
+            .split_whitespace()
+            .map(SpineProperty::new)
+            .collect()
+    }
+
+    pub fn to_xml(&self) -> String {
+        let mut res = String::from("<itemref");
+        push_attribute(&mut res, "idref", Some(&self.idref));
+        push_attribute(&mut res, "id", self.id.as_deref());
+        push_attribute(&mut res, "linear", self.linear.as_deref());
+        push_attribute(&mut res, "properties", self.properties.as_deref());
+        res.push_str("/>");
+        res
+    }
 }
 
 #[derive(Debug, Default)]
@@ -146,44 +886,105 @@ impl Reference {
         let href = xml::parse_attribute(node, "href");
         Ok(Reference { t, title, href })
     }
+
+    // the guide `type`, like `cover`, `toc` or `text`
+    pub fn reference_type(&self) -> &str {
+        &self.t
+    }
+
+    pub fn title(&self) -> Option<&str> {
+        self.title.as_deref()
+    }
+
+    pub fn href(&self) -> Option<&str> {
+        self.href.as_deref()
+    }
+
+    pub fn to_xml(&self) -> String {
+        let mut res = String::from("<reference");
+        push_attribute(&mut res, "type", Some(&self.t));
+        push_attribute(&mut res, "title", self.title.as_deref());
+        push_attribute(&mut res, "href", self.href.as_deref());
+        res.push_str("/>");
+        res
+    }
+}
+
+// parts of a parsed package that `to_bytes` writes again after an edit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Section {
+    // the `unique-identifier` of the `<package>` start tag
+    Package,
+    Manifest,
+    // the itemrefs and the `toc` of the `<spine>` start tag
+    Spine,
 }
 
 #[allow(dead_code)]
 pub struct Package {
     path: String,
+    raw: Vec<u8>,
+    modified: Vec<Section>,
+    // the parsed metadata items, edits through `metadata_mut` are found by comparing with them
+    parsed_metadata: Vec<MetaItem>,
+    version: String,
+    unique_identifier: Option<String>,
     metadata: MetaData,
-    manifest: HashMap<String, ManifestItem>,
+    manifest: Vec<ManifestItem>,
     guide: Vec<Reference>,
     spine: Vec<ItemRef>,
+    toc: Option<String>,
 }
-pub fn parse_guide(doc: &XMLDocument) -> Result<Vec<Reference>> {
+pub(crate) fn parse_guide(
+    doc: &XMLDocument,
+    path: &str,
+    repair: &mut Repair,
+) -> Result<Vec<Reference>> {
     let mut guide = Vec::new();
     if let Some(gs) = doc.find_tag("guide") {
         for item in xml::find_children(&gs, "reference") {
-            let r = Reference::parse(&item)?;
-            guide.push(r);
+            match Reference::parse(&item) {
+                Ok(r) => guide.push(r),
+                Err(e) => {
+                    repair.recover(WarningKind::InvalidItem, path, "guide reference skipped", e)?
+                }
+            }
         }
     }
     Ok(guide)
 }
 
-pub fn parse_spine(doc: &XMLDocument) -> Result<Vec<ItemRef>> {
+pub(crate) fn parse_spine(
+    doc: &XMLDocument,
+    path: &str,
+    repair: &mut Repair,
+) -> Result<Vec<ItemRef>> {
     let mut spine = Vec::new();
     if let Some(spine_node) = doc.find_tag("spine") {
         for item in xml::find_children(&spine_node, "itemref") {
-            let s = ItemRef::parse(&item)?;
-            spine.push(s);
+            match ItemRef::parse(&item) {
+                Ok(s) => spine.push(s),
+                Err(e) => {
+                    repair.recover(WarningKind::InvalidItem, path, "spine itemref skipped", e)?
+                }
+            }
         }
     }
     Ok(spine)
 }
-pub fn parse_manifest(doc: &XMLDocument) -> Result<HashMap<String, ManifestItem>> {
-    let mut manifest = HashMap::new();
+
+pub(crate) fn parse_manifest(
+    doc: &XMLDocument,
+    path: &str,
+    repair: &mut Repair,
+) -> Result<Vec<ManifestItem>> {
+    let mut manifest = Vec::new();
     if let Some(mainfest_node) = doc.find_tag("manifest") {
         let items = xml::find_children(&mainfest_node, "item");
         for item in items {
-            let manifest_item = ManifestItem::parse(&item)?;
-            manifest.insert(manifest_item.id.clone(), manifest_item);
+            if let Some(manifest_item) = ManifestItem::parse(&item, path, repair)? {
+                manifest.push(manifest_item);
+            }
         }
     } else {
         return Err(EpubError::FormatError("manifest is null".to_string()));
@@ -193,43 +994,821 @@ pub fn parse_manifest(doc: &XMLDocument) -> Result<HashMap<String, ManifestItem>
 
 impl Package {
     pub fn new(path: &str, content: &[u8]) -> Result<Self> {
-        let doc = XMLDocument::try_new(content)?;
+        Package::parse(path, content, &mut Repair::strict())
+    }
+
+    pub(crate) fn parse(path: &str, content: &[u8], repair: &mut Repair) -> Result<Self> {
+        let doc = XMLDocument::try_new_from_entry(content, path)?;
+        let package = doc.find_tag("package").ok_or(EpubError::FormatError(
+            "package is null in package format".to_string(),
+        ))?;
+        let version = xml::parse_attribute(&package, "version").unwrap_or_default();
+        let unique_identifier = xml::parse_attribute(&package, "unique-identifier");
         let metadata = MetaData::parse(&doc)?;
-        let guide = parse_guide(&doc)?;
-        let spine = parse_spine(&doc)?;
-        let manifest = parse_manifest(&doc)?;
+        let guide = parse_guide(&doc, path, repair)?;
+        let mut spine = parse_spine(&doc, path, repair)?;
+        let toc = doc
+            .find_tag("spine")
+            .and_then(|node| xml::parse_attribute(&node, "toc"));
+        let manifest = parse_manifest(&doc, path, repair)?;
+        if repair.is_lenient() {
+            spine.retain(|item| {
+                let exists = manifest.iter().any(|m| m.id == item.idref);
+                if !exists {
+                    let message = format!(
+                        "spine itemref {:?} is not in the manifest, skipped",
+                        item.idref
+                    );
+                    repair.warn(WarningKind::InvalidItem, path, &message);
+                }
+                exists
+            });
+        }
         Ok(Package {
             path: path.to_string(),
+            raw: content.to_vec(),
+            modified: Vec::new(),
+            parsed_metadata: metadata.items.clone(),
+            version,
+            unique_identifier,
             metadata,
             manifest,
             spine,
             guide,
+            toc,
         })
     }
 
+    // an empty package that is serialized from scratch by `to_bytes`
+    pub fn create(path: &str, version: &str) -> Self {
+        Package {
+            path: path.to_string(),
+            raw: Vec::new(),
+            modified: Vec::new(),
+            parsed_metadata: Vec::new(),
+            version: version.to_string(),
+            unique_identifier: None,
+            metadata: MetaData::default(),
+            manifest: Vec::new(),
+            guide: Vec::new(),
+            spine: Vec::new(),
+            toc: None,
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
     pub fn title(&self) -> &str {
         self.metadata.title()
     }
 
+    pub fn is_modified(&self) -> bool {
+        self.raw.is_empty() || !self.modified.is_empty() || self.metadata_modified()
+    }
+
+    fn metadata_modified(&self) -> bool {
+        self.metadata.items != self.parsed_metadata
+    }
+
+    fn set_modified(&mut self, section: Section) {
+        if !self.modified.contains(&section) {
+            self.modified.push(section);
+        }
+    }
+
+    pub fn metadata_mut(&mut self) -> &mut MetaData {
+        &mut self.metadata
+    }
+
+    // the original document, only the edited sections are regenerated
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        if self.raw.is_empty() {
+            return Ok(self.to_xml().into_bytes());
+        }
+        if !self.is_modified() {
+            return Ok(self.raw.clone());
+        }
+        // other encodings are transcoded, the edited package is written as UTF-8
+        let mut content = encoding::decode(&self.raw, &self.path)?;
+        self.splice(&mut content)?;
+        Ok(content.into_bytes())
+    }
+
+    // replace the edited sections of the original document, everything else is kept as is
+    fn splice(&self, content: &mut String) -> Result<()> {
+        if self.metadata_modified() {
+            self.splice_metadata(content)?;
+        }
+        for section in self.modified.iter() {
+            match section {
+                Section::Package => set_start_attribute(
+                    content,
+                    "package",
+                    "unique-identifier",
+                    self.unique_identifier.as_deref(),
+                )?,
+                Section::Manifest => replace_content(content, "manifest", &self.manifest_xml())?,
+                Section::Spine => {
+                    replace_content(content, "spine", &self.spine_items_xml())?;
+                    set_start_attribute(content, "spine", "toc", self.toc.as_deref())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // edited items replace their element, removed ones are dropped and new ones appended,
+    // untouched elements with their comments and nested content stay as they are
+    fn splice_metadata(&self, content: &mut String) -> Result<()> {
+        let element =
+            find_element(content, "metadata").ok_or_else(|| element_not_found("metadata"))?;
+        let children = match element.close {
+            Some(close) => child_elements(content, element.open_end, close),
+            None => Vec::new(),
+        };
+        if element.close.is_none() || children.len() != self.parsed_metadata.len() {
+            return replace_content(content, "metadata", &self.metadata.to_xml());
+        }
+        let mut kept = vec![false; children.len()];
+        let mut edits = Vec::new();
+        let mut added = String::new();
+        for item in self.metadata.items.iter() {
+            match item.source.filter(|i| !kept[*i]) {
+                Some(i) => {
+                    kept[i] = true;
+                    if *item != self.parsed_metadata[i] {
+                        edits.push((children[i].0, children[i].1, item.to_xml()));
+                    }
+                }
+                None => {
+                    added.push_str("\n    ");
+                    added.push_str(&item.to_xml());
+                }
+            }
+        }
+        for (i, (start, end)) in children.iter().enumerate() {
+            if !kept[i] {
+                // with the indentation before it
+                edits.push((content[..*start].trim_end().len(), *end, String::new()));
+            }
+        }
+        if !added.is_empty() {
+            let end = children.last().map_or(element.open_end, |child| child.1);
+            edits.push((end, end, added));
+        }
+        edits.sort_by_key(|(start, _, _)| *start);
+        for (start, end, text) in edits.into_iter().rev() {
+            content.replace_range(start..end, &text);
+        }
+        Ok(())
+    }
+
+    pub fn to_xml(&self) -> String {
+        let mut res = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<package");
+        push_attribute(&mut res, "xmlns", Some(OPF_NAMESPACE));
+        push_attribute(&mut res, "version", Some(&self.version));
+        push_attribute(&mut res, "unique-identifier", self.unique_identifier.as_deref());
+        res.push_str(">\n");
+        res.push_str(&format!(
+            "  <metadata xmlns:dc=\"{}\">{}</metadata>\n",
+            DC_NAMESPACE,
+            self.metadata.to_xml()
+        ));
+        res.push_str(&format!("  <manifest>{}</manifest>\n", self.manifest_xml()));
+        res.push_str(&format!("  {}</spine>\n", self.spine_xml()));
+        if !self.guide.is_empty() {
+            res.push_str("  <guide>\n");
+            for item in self.guide.iter() {
+                res.push_str(&format!("    {}\n", item.to_xml()));
+            }
+            res.push_str("  </guide>\n");
+        }
+        res.push_str("</package>\n");
+        res
+    }
+
+    fn manifest_xml(&self) -> String {
+        let mut res = String::new();
+        for item in self.manifest.iter() {
+            res.push_str(&format!("\n    {}", item.to_xml()));
+        }
+        res.push_str("\n  ");
+        res
+    }
+
+    // the spine start tag and content, the start tag carries the toc attribute
+    fn spine_xml(&self) -> String {
+        let mut res = String::from("<spine");
+        push_attribute(&mut res, "toc", self.toc.as_deref());
+        res.push('>');
+        res.push_str(&self.spine_items_xml());
+        res
+    }
+
+    fn spine_items_xml(&self) -> String {
+        let mut res = String::new();
+        for item in self.spine.iter() {
+            res.push_str(&format!("\n    {}", item.to_xml()));
+        }
+        res.push_str("\n  ");
+        res
+    }
+
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    pub fn unique_identifier(&self) -> Option<&str> {
+        self.unique_identifier.as_deref()
+    }
+
+    pub fn set_unique_identifier(&mut self, id: &str) {
+        self.set_modified(Section::Package);
+        self.unique_identifier = Some(id.to_string());
+    }
+
+    pub fn toc(&self) -> Option<&str> {
+        self.toc.as_deref()
+    }
+
+    pub fn set_toc(&mut self, id: &str) {
+        self.set_modified(Section::Spine);
+        self.toc = Some(id.to_string());
+    }
+
+    pub fn add_manifest(&mut self, item: ManifestItem) {
+        self.set_modified(Section::Manifest);
+        self.manifest.push(item);
+    }
+
+    pub fn add_spine(&mut self, item: ItemRef) {
+        self.set_modified(Section::Spine);
+        self.spine.push(item);
+    }
+
     pub fn spine(&self) -> &[ItemRef] {
         self.spine.as_slice()
     }
+
+    // spine items in the default reading order, with their spine index
+    pub fn linear_spine(&self) -> impl Iterator<Item = (usize, &ItemRef)> {
+        self.spine
+            .iter()
+            .enumerate()
+            .filter(|(_, item)| item.is_linear())
+    }
+
+    pub fn non_linear_spine(&self) -> impl Iterator<Item = (usize, &ItemRef)> {
+        self.spine
+            .iter()
+            .enumerate()
+            .filter(|(_, item)| !item.is_linear())
+    }
     pub fn metadata(&self) -> &MetaData {
         &self.metadata
     }
 
+    // the EPUB 2 guide
+    pub fn guide(&self) -> &[Reference] {
+        self.guide.as_slice()
+    }
+
     pub fn get_manifest(&self, name: &str) -> Option<&ManifestItem> {
-        self.manifest.get(name)
+        self.manifest.iter().find(|item| item.id == name)
+    }
+
+    pub fn manifest(&self) -> &[ManifestItem] {
+        self.manifest.as_slice()
+    }
+
+    // the item `id` followed by the items its fallback chain references
+    pub fn fallback_chain(&self, id: &str) -> Result<Vec<&ManifestItem>> {
+        let mut chain: Vec<&ManifestItem> = Vec::new();
+        let mut next = Some(id);
+        while let Some(id) = next {
+            if chain.iter().any(|item| item.id == id) {
+                return Err(EpubError::FormatError(format!(
+                    "fallback chain of {:?} loops at {:?}",
+                    chain[0].id, id
+                )));
+            }
+            let item = self.get_manifest(id).ok_or_else(|| {
+                EpubError::FormatError(format!("{:?} is not in the manifest", id))
+            })?;
+            chain.push(item);
+            next = item.fallback();
+        }
+        Ok(chain)
+    }
+
+    // the first item of the fallback chain of `id` with a media type `supported` accepts
+    pub fn resolve_fallback<F: Fn(&str) -> bool>(
+        &self,
+        id: &str,
+        supported: F,
+    ) -> Result<Option<&ManifestItem>> {
+        let chain = self.fallback_chain(id)?;
+        Ok(chain.into_iter().find(|item| supported(item.media_type())))
     }
 
-    pub fn manifest(&self) -> &HashMap<String, ManifestItem> {
-        &self.manifest
+    // the content document shown for spine item `n`, following fallbacks of foreign items
+    pub fn spine_content(&self, n: usize) -> Result<Option<&ManifestItem>> {
+        match self.spine().get(n) {
+            Some(itemref) => self.resolve_fallback(itemref.idref(), is_content_document),
+            None => Ok(None),
+        }
+    }
+
+    // the EPUB 3 navigation document, the manifest item with the `nav` property
+    pub fn nav_item(&self) -> Option<&ManifestItem> {
+        self.manifest.iter().find(|item| item.has_property("nav"))
+    }
+
+    // the NCX referenced by the spine `toc` attribute, or the first item with the NCX media type
+    pub fn ncx_item(&self) -> Option<&ManifestItem> {
+        self.toc
+            .as_deref()
+            .and_then(|id| self.get_manifest(id))
+            .or_else(|| {
+                self.manifest
+                    .iter()
+                    .find(|item| item.media_type == NCX_MEDIA_TYPE)
+            })
     }
 
     pub fn chapter(&self, n: usize) -> Option<ManifestItem> {
         if let Some(sp) = self.spine().get(n) {
-            return self.manifest.get(sp.idref()).map(|v| v.to_owned());
+            return self.get_manifest(sp.idref()).map(|v| v.to_owned());
         }
         None
     }
 }
+
+// an element of the raw package, found without building a DOM so the rest stays untouched
+struct Element {
+    name: String,
+    start: usize,
+    // the end of the start tag
+    open_end: usize,
+    // the start of the end tag, `None` for an empty element tag
+    close: Option<usize>,
+}
+
+// the end of the markup at `pos`, comments, CDATA sections and declarations are skipped whole
+fn markup_end(raw: &str, pos: usize) -> Option<usize> {
+    let rest = &raw[pos..];
+    let terminator = if rest.starts_with("<!--") {
+        "-->"
+    } else if rest.starts_with("<![CDATA[") {
+        "]]>"
+    } else if rest.starts_with("<?") {
+        "?>"
+    } else if rest.starts_with("<!") {
+        ">"
+    } else {
+        // a tag, quoted attribute values may contain `>`
+        let mut quote = None;
+        for (i, c) in rest.char_indices() {
+            match (quote, c) {
+                (None, '"' | '\'') => quote = Some(c),
+                (Some(q), c) if c == q => quote = None,
+                (None, '>') => return Some(pos + i + 1),
+                _ => {}
+            }
+        }
+        return None;
+    };
+    rest.find(terminator).map(|i| pos + i + terminator.len())
+}
+
+// the first element with the local name `local`
+fn find_element(raw: &str, local: &str) -> Option<Element> {
+    let mut pos = 0;
+    let mut open: Option<Element> = None;
+    let mut depth = 0;
+    while let Some(i) = raw[pos..].find('<') {
+        let start = pos + i;
+        pos = markup_end(raw, start)?;
+        let tag = &raw[start..pos];
+        if tag.starts_with("<!") || tag.starts_with("<?") {
+            continue;
+        }
+        let closing = tag.starts_with("</");
+        let empty = tag.ends_with("/>");
+        let name = tag.trim_start_matches("</").trim_start_matches('<');
+        let name = &name[..name
+            .find(|c: char| c.is_whitespace() || c == '>' || c == '/')
+            .unwrap_or(name.len())];
+        match open {
+            None if !closing && name.rsplit(':').next() == Some(local) => {
+                let element = Element {
+                    name: name.to_string(),
+                    start,
+                    open_end: pos,
+                    close: None,
+                };
+                if empty {
+                    return Some(element);
+                }
+                open = Some(element);
+                depth = 1;
+            }
+            Some(ref mut element) if element.name == name && !empty => {
+                if !closing {
+                    depth += 1;
+                    continue;
+                }
+                depth -= 1;
+                if depth == 0 {
+                    element.close = Some(start);
+                    return open;
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+// the ranges of the child elements between `start` and `end`
+fn child_elements(raw: &str, start: usize, end: usize) -> Vec<(usize, usize)> {
+    let mut res = Vec::new();
+    let mut pos = start;
+    let mut open = None;
+    let mut depth = 0;
+    while let Some(i) = raw[pos..end].find('<') {
+        let tag_start = pos + i;
+        let Some(tag_end) = markup_end(raw, tag_start).filter(|e| *e <= end) else {
+            break;
+        };
+        pos = tag_end;
+        let tag = &raw[tag_start..tag_end];
+        if tag.starts_with("<!") || tag.starts_with("<?") {
+            continue;
+        }
+        if tag.starts_with("</") {
+            depth -= 1;
+            if depth == 0 {
+                res.extend(open.take().map(|open| (open, tag_end)));
+            }
+        } else if tag.ends_with("/>") {
+            if depth == 0 {
+                res.push((tag_start, tag_end));
+            }
+        } else {
+            if depth == 0 {
+                open = Some(tag_start);
+            }
+            depth += 1;
+        }
+    }
+    res
+}
+
+fn element_not_found(local: &str) -> EpubError {
+    EpubError::FormatError(format!("{} not found in package", local))
+}
+
+// replace the content of the first `local` element, an empty element tag gets an end tag
+fn replace_content(raw: &mut String, local: &str, content: &str) -> Result<()> {
+    let element = find_element(raw, local).ok_or_else(|| element_not_found(local))?;
+    match element.close {
+        Some(close) => raw.replace_range(element.open_end..close, content),
+        None => {
+            let tag = raw[element.start..element.open_end - 2].trim_end();
+            let replacement = format!("{}>{}</{}>", tag, content, element.name);
+            raw.replace_range(element.start..element.open_end, &replacement);
+        }
+    }
+    Ok(())
+}
+
+// the range of attribute `name` in `tag` including the whitespace before it, and of its value
+fn attribute_range(tag: &str, name: &str) -> Option<(usize, usize, usize)> {
+    let bytes = tag.as_bytes();
+    let mut i = tag.find(|c: char| c.is_ascii_whitespace())?;
+    loop {
+        let before = i;
+        while bytes.get(i).is_some_and(|b| b.is_ascii_whitespace()) {
+            i += 1;
+        }
+        let key_start = i;
+        while bytes
+            .get(i)
+            .is_some_and(|b| !b.is_ascii_whitespace() && !b"=/?>".contains(b))
+        {
+            i += 1;
+        }
+        if i == key_start {
+            return None;
+        }
+        let key = &tag[key_start..i];
+        while bytes.get(i).is_some_and(|b| b.is_ascii_whitespace()) {
+            i += 1;
+        }
+        if bytes.get(i) != Some(&b'=') {
+            return None;
+        }
+        i += 1;
+        while bytes.get(i).is_some_and(|b| b.is_ascii_whitespace()) {
+            i += 1;
+        }
+        let quote = *bytes.get(i).filter(|b| **b == b'"' || **b == b'\'')?;
+        let value_start = i + 1;
+        let value_end = value_start + tag[value_start..].find(quote as char)?;
+        i = value_end + 1;
+        if key == name {
+            return Some((before, value_start, value_end));
+        }
+    }
+}
+
+// `tag` with attribute `name` set to `value`, or removed for `None`
+fn set_attribute(tag: &str, name: &str, value: Option<&str>) -> String {
+    let mut res = tag.to_string();
+    match (attribute_range(tag, name), value) {
+        (Some((_, start, end)), Some(value)) => res.replace_range(start..end, &xml::escape(value)),
+        (Some((before, _, end)), None) => res.replace_range(before..end + 1, ""),
+        (None, Some(value)) => {
+            let suffix = match tag.ends_with("/>") || tag.ends_with("?>") {
+                true => 2,
+                false => 1,
+            };
+            let end = tag[..tag.len() - suffix].trim_end().len();
+            res.insert_str(end, &format!(" {}=\"{}\"", name, xml::escape(value)));
+        }
+        (None, None) => {}
+    }
+    res
+}
+
+fn set_start_attribute(
+    raw: &mut String,
+    local: &str,
+    name: &str,
+    value: Option<&str>,
+) -> Result<()> {
+    let element = find_element(raw, local).ok_or_else(|| element_not_found(local))?;
+    let tag = set_attribute(&raw[element.start..element.open_end], name, value);
+    raw.replace_range(element.start..element.open_end, &tag);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::package::{
+        is_core_media_type, ItemRef, Layout, ManifestItem, ManifestProperty, MetaItem, Package,
+        SpineProperty,
+    };
+
+    const PACKAGE: &str = r##"<?xml version="1.0" encoding="UTF-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" xmlns:opf="http://www.idpf.org/2007/opf" version="3.0" unique-identifier="uid">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+    <dc:identifier id="uid">urn:uuid:a7b3c2d1</dc:identifier>
+    <dc:identifier id="isbn">9780000000000</dc:identifier>
+    <meta refines="#isbn" property="identifier-type" scheme="onix:codelist5">15</meta>
+    <dc:identifier opf:scheme="DOI">10.1000/182</dc:identifier>
+    <dc:title id="sub">A Subtitle</dc:title>
+    <meta refines="#sub" property="title-type">subtitle</meta>
+    <dc:title id="main">  The Main Title </dc:title>
+    <meta refines="#main" property="title-type">main</meta>
+    <dc:creator id="c2">Second Author</dc:creator>
+    <meta refines="#c2" property="display-seq">2</meta>
+    <dc:creator id="c1">First Author</dc:creator>
+    <meta refines="#c1" property="role" scheme="marc:relators">aut</meta>
+    <meta refines="#c1" property="file-as">Author, First</meta>
+    <meta refines="#c1" property="display-seq">1</meta>
+    <dc:contributor opf:role="ill" opf:file-as="Painter, Jane">Jane Painter</dc:contributor>
+    <dc:subject>Fiction</dc:subject>
+    <dc:subject>Adventure</dc:subject>
+    <dc:language>en</dc:language>
+    <dc:publisher>Example Press</dc:publisher>
+    <dc:date opf:event="publication">2020-05-01</dc:date>
+    <dc:rights>All rights reserved</dc:rights>
+    <dc:description>A book.</dc:description>
+    <meta property="dcterms:modified">2024-01-01T00:00:00Z</meta>
+    <meta name="cover" content="cover-image"/>
+  </metadata>
+  <manifest>
+    <item id="c1" href="c1.xhtml" media-type="application/xhtml+xml"/>
+  </manifest>
+  <spine>
+    <itemref idref="c1"/>
+  </spine>
+</package>"##;
+
+    #[test]
+    fn test_metadata() {
+        let package = Package::new("content.opf", PACKAGE.as_bytes()).unwrap();
+        let metadata = package.metadata();
+        assert_eq!(metadata.title(), "The Main Title");
+        let titles = metadata.titles();
+        assert_eq!(titles.len(), 2);
+        assert_eq!(titles[1].value(), "The Main Title");
+        assert_eq!(titles[1].title_type(), Some("main"));
+        assert_eq!(titles[1].display_seq(), None);
+
+        let authors = metadata.authors();
+        assert_eq!(authors.len(), 2);
+        assert_eq!(authors[0].name(), "First Author");
+        assert_eq!(authors[0].role(), Some("aut"));
+        assert_eq!(authors[0].role_scheme(), Some("marc:relators"));
+        assert_eq!(authors[0].file_as(), Some("Author, First"));
+        assert_eq!(authors[0].display_seq(), Some(1));
+        assert_eq!(authors[1].name(), "Second Author");
+        assert_eq!(authors[1].role(), None);
+        assert_eq!(authors[1].role_scheme(), None);
+        assert_eq!(authors[1].file_as(), None);
+        assert_eq!(authors[1].display_seq(), Some(2));
+        let contributor = &metadata.contributors()[0];
+        assert_eq!(contributor.role(), Some("ill"));
+        assert_eq!(contributor.role_scheme(), None);
+        assert_eq!(contributor.file_as(), Some("Painter, Jane"));
+
+        let identifiers = metadata.identifiers();
+        assert_eq!(identifiers.len(), 3);
+        assert_eq!(identifiers[1].id(), Some("isbn"));
+        assert_eq!(identifiers[1].value(), "9780000000000");
+        assert_eq!(identifiers[1].identifier_type(), Some("15"));
+        assert_eq!(identifiers[1].scheme(), Some("onix:codelist5"));
+        assert_eq!(identifiers[2].identifier_type(), Some("DOI"));
+        assert_eq!(identifiers[2].scheme(), None);
+        assert_eq!(metadata.identifier("uid"), Some("urn:uuid:a7b3c2d1"));
+
+        assert_eq!(metadata.subjects(), vec!["Fiction", "Adventure"]);
+        assert_eq!(metadata.languages(), vec!["en"]);
+        assert_eq!(metadata.publisher(), Some("Example Press"));
+        let dates = metadata.dates();
+        assert_eq!(dates.len(), 1);
+        assert_eq!(dates[0].value(), "2020-05-01");
+        assert_eq!(dates[0].event(), Some("publication"));
+        assert_eq!(metadata.rights(), Some("All rights reserved"));
+        assert_eq!(metadata.description(), Some("A book."));
+        assert_eq!(metadata.modified(), Some("2024-01-01T00:00:00Z"));
+        assert_eq!(metadata.meta("cover"), Some("cover-image"));
+        assert_eq!(metadata.refinements("c1").len(), 3);
+    }
+
+    #[test]
+    fn test_splice() {
+        let raw = PACKAGE
+            .replace(
+                "<spine>",
+                r#"<!-- <spine> --><spine id="sp" page-progression-direction="rtl">"#,
+            )
+            .replace(
+                r#"unique-identifier="uid""#,
+                r#"unique-identifier="uid" dir="ltr""#,
+            );
+        let mut package = Package::new("content.opf", raw.as_bytes()).unwrap();
+        let _ = package.metadata_mut().title();
+        assert!(!package.is_modified());
+        assert_eq!(package.to_bytes().unwrap(), raw.as_bytes());
+
+        package.add_spine(ItemRef::new("c2"));
+        package.set_toc("ncx");
+        package.set_unique_identifier("isbn");
+        let content = String::from_utf8(package.to_bytes().unwrap()).unwrap();
+        assert!(content.contains(
+            r#"<!-- <spine> --><spine id="sp" page-progression-direction="rtl" toc="ncx">"#
+        ));
+        assert!(content.contains(r#"<itemref idref="c2"/>"#));
+        assert!(content.contains(r#"unique-identifier="isbn" dir="ltr""#));
+        let copy = Package::new("content.opf", content.as_bytes()).unwrap();
+        assert_eq!(copy.spine().len(), 2);
+        assert_eq!(copy.toc(), Some("ncx"));
+        assert_eq!(copy.unique_identifier(), Some("isbn"));
+    }
+
+    #[test]
+    fn test_splice_metadata() {
+        let raw = PACKAGE
+            .replace(
+                r#"<dc:title id="sub">A Subtitle</dc:title>"#,
+                r#"<!-- titles --><dc:title id="sub" xmlns:x="urn:x" x:a="1">A Subtitle</dc:title>"#,
+            )
+            .replace(
+                "<dc:language>en</dc:language>",
+                "<dc-metadata><dc:language>en</dc:language></dc-metadata>",
+            );
+        let mut package = Package::new("content.opf", raw.as_bytes()).unwrap();
+        let metadata = package.metadata_mut();
+        metadata.set_item("title", "New Title");
+        metadata.remove_items("rights");
+        metadata.add_item(MetaItem::new_dc("source", "urn:isbn:9780000000001"));
+        let content = String::from_utf8(package.to_bytes().unwrap()).unwrap();
+        // the edited item keeps its local prefix declaration
+        assert!(content.contains(
+            r#"<!-- titles --><dc:title id="sub" x:a="1" xmlns:x="urn:x">New Title</dc:title>"#
+        ));
+        assert!(content.contains(r#"<dc:title id="main">  The Main Title </dc:title>"#));
+        assert!(content.contains("<dc-metadata><dc:language>en</dc:language></dc-metadata>"));
+        assert!(!content.contains("rights"));
+        assert!(content.contains(
+            "<meta name=\"cover\" content=\"cover-image\"/>\n    <dc:source>urn:isbn:9780000000001</dc:source>\n  </metadata>"
+        ));
+        let copy = Package::new("content.opf", content.as_bytes()).unwrap();
+        assert_eq!(copy.metadata().titles().len(), 2);
+        assert_eq!(copy.metadata().dc_items("source").count(), 1);
+    }
+
+    #[test]
+    fn test_splice_encoded() {
+        let raw = PACKAGE
+            .replace(r#"encoding="UTF-8""#, r#"encoding="ISO-8859-1""#)
+            .replace("<spine>", r#"<spine page-progression-direction="rtl">"#)
+            .replace(
+                "</package>",
+                r#"<x:ext xmlns:x="urn:x">é</x:ext></package>"#,
+            );
+        let (latin1, _, _) = encoding_rs::WINDOWS_1252.encode(&raw);
+        let mut package = Package::new("content.opf", &latin1).unwrap();
+        package.metadata_mut().set_title("Titré");
+        let content = String::from_utf8(package.to_bytes().unwrap()).unwrap();
+        assert!(content.starts_with(r#"<?xml version="1.0" encoding="UTF-8"?>"#));
+        assert!(content.contains(r#"xmlns:opf="http://www.idpf.org/2007/opf""#));
+        assert!(content.contains(r#"<spine page-progression-direction="rtl">"#));
+        assert!(content.contains(r#"<x:ext xmlns:x="urn:x">é</x:ext>"#));
+        assert!(content.contains(r#"<dc:title id="sub">A Subtitle</dc:title>"#));
+        assert!(content.contains(r#"<dc:title id="main">Titré</dc:title>"#));
+        let copy = Package::new("content.opf", content.as_bytes()).unwrap();
+        assert_eq!(copy.metadata().titles().len(), 2);
+        assert_eq!(copy.title(), "Titré");
+    }
+
+    #[test]
+    fn test_fallback() {
+        let mut package = Package::create("content.opf", "3.0");
+        package.add_manifest(ManifestItem::new("c1", "c1.xhtml", "application/xhtml+xml"));
+        package.add_manifest(ManifestItem::new("jpg", "a.jpg", "image/jpeg"));
+        package.add_manifest(ManifestItem::new("jxl", "a.jxl", "image/jxl").with_fallback("jpg"));
+        package.add_manifest(
+            ManifestItem::new("pdf", "c2.pdf", "application/pdf").with_fallback("c1"),
+        );
+        package.add_manifest(
+            ManifestItem::new("loop1", "a.heic", "image/heic").with_fallback("loop2"),
+        );
+        package.add_manifest(
+            ManifestItem::new("loop2", "b.heic", "image/heic").with_fallback("loop1"),
+        );
+        package.add_spine(ItemRef::new("c1"));
+        package.add_spine(ItemRef::new("pdf"));
+
+        let chain = package.fallback_chain("jxl").unwrap();
+        let ids: Vec<&str> = chain.iter().map(|item| item.id()).collect();
+        assert_eq!(ids, vec!["jxl", "jpg"]);
+        let image = package.resolve_fallback("jxl", is_core_media_type).unwrap();
+        assert_eq!(image.unwrap().href(), "a.jpg");
+        assert!(package
+            .resolve_fallback("jpg", |t| t == "image/png")
+            .unwrap()
+            .is_none());
+        assert_eq!(package.spine_content(1).unwrap().unwrap().id(), "c1");
+        assert!(package.spine_content(2).unwrap().is_none());
+        assert!(package.fallback_chain("loop1").is_err());
+        assert!(package.fallback_chain("missing").is_err());
+    }
+
+    #[test]
+    fn test_properties() {
+        let item = ManifestItem::new("nav", "nav.xhtml", "application/xhtml+xml")
+            .with_properties("nav scripted x:custom");
+        assert_eq!(
+            item.properties(),
+            vec![
+                ManifestProperty::Nav,
+                ManifestProperty::Scripted,
+                ManifestProperty::Other("x:custom".to_string())
+            ]
+        );
+        let itemref = ItemRef::new("c1")
+            .with_linear(false)
+            .with_properties("page-spread-left rendition:layout-pre-paginated");
+        assert!(!itemref.is_linear());
+        assert_eq!(
+            itemref.properties(),
+            vec![
+                SpineProperty::PageSpreadLeft,
+                SpineProperty::Layout(Layout::PrePaginated)
+            ]
+        );
+        assert_eq!(
+            itemref.properties()[1].as_str(),
+            "rendition:layout-pre-paginated"
+        );
+
+        let mut package = Package::create("content.opf", "3.0");
+        package.add_spine(ItemRef::new("c1"));
+        package.add_spine(itemref);
+        package.add_spine(ItemRef::new("c3"));
+        let linear: Vec<usize> = package.linear_spine().map(|(i, _)| i).collect();
+        assert_eq!(linear, vec![0, 2]);
+        let notes: Vec<&str> = package
+            .non_linear_spine()
+            .map(|(_, item)| item.idref())
+            .collect();
+        assert_eq!(notes, vec!["c1"]);
+    }
+}
@@ -1,58 +1,111 @@
-use std::fs::File;
-use std::io::{Cursor, Read};
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufReader, Cursor, Read, Seek};
+use std::path::{Path, PathBuf};
 use zip::read::ZipArchive;
+use zip::CompressionMethod;
 
-use crate::container::Container;
+use crate::container::{Container, CONTAINER_PATH};
+use crate::encoding;
+use crate::encryption::{Encryption, ObfuscationKey, ENCRYPTION_PATH};
 use crate::error::{EpubError, Result};
+use crate::href;
 use crate::nav::Navigation;
 use crate::package::Package;
+use crate::repair::{Repair, WarningKind};
 
 pub trait EpubReader {
     fn container(&mut self) -> Result<Vec<u8>>;
     fn readfile(&mut self, path: &str) -> Result<Vec<u8>>;
+    fn file_names(&mut self) -> Result<Vec<String>>;
+    // read an entry without loading it into memory
+    fn open(&mut self, path: &str) -> Result<Box<dyn Read + '_>>;
+    // the first entry of a zip container and whether it is stored uncompressed
+    fn first_entry(&mut self) -> Result<Option<(String, bool)>> {
+        Ok(None)
+    }
 }
 
-pub struct ZipReader {
-    inner: ZipArchive<Cursor<Vec<u8>>>,
+// entries are only decompressed when they are read
+pub struct ZipReader<R: Read + Seek = BufReader<File>> {
+    inner: ZipArchive<R>,
 }
 
-impl ZipReader {
+impl ZipReader<BufReader<File>> {
     pub fn new_from_path(path: PathBuf) -> Result<Self> {
-        let mut file = File::open(path.clone())
+        let file = File::open(path.clone())
             .map_err(|e| EpubError::ReaderError(format!("open {:?} error;{:?}", path, e)))?;
-        let mut buffer = Vec::new();
-        file.read_to_end(&mut buffer)
-            .map_err(|e| EpubError::ReaderError(format!("read {:?} error:{:?}", path, e)))?;
-        ZipReader::new_from_memory(buffer)
+        ZipReader::new(BufReader::new(file))
     }
+}
 
+impl ZipReader<Cursor<Vec<u8>>> {
     pub fn new_from_memory(buffer: Vec<u8>) -> Result<Self> {
-        let cursor = Cursor::new(buffer);
-        let inner = ZipArchive::new(cursor)?;
+        ZipReader::new(Cursor::new(buffer))
+    }
+}
+
+impl<R: Read + Seek> ZipReader<R> {
+    pub fn new(reader: R) -> Result<Self> {
+        let inner = ZipArchive::new(reader)?;
         Ok(ZipReader { inner })
     }
 
     pub fn readfile(&mut self, filename: &str) -> Result<Vec<u8>> {
-        let mut file = self
-            .inner
-            .by_name(filename)
-            .map_err(|e| EpubError::ReaderError(format!("read{:?} error{:?}", filename, e)))?;
+        let mut file = self.open(filename)?;
         let mut content = Vec::new();
         file.read_to_end(&mut content)
             .map_err(|e| EpubError::ReaderError(format!("read {:?} error{:?}", filename, e)))?;
         Ok(content)
     }
+
+    pub fn open(&mut self, filename: &str) -> Result<Box<dyn Read + '_>> {
+        let file = self
+            .inner
+            .by_name(filename)
+            .map_err(|e| EpubError::ReaderError(format!("read{:?} error{:?}", filename, e)))?;
+        Ok(Box::new(file))
+    }
+
+    pub fn file_names(&mut self) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        for i in 0..self.inner.len() {
+            let file = self.inner.by_index_raw(i)?;
+            if !file.is_dir() {
+                names.push(file.name().to_string());
+            }
+        }
+        Ok(names)
+    }
 }
 
-impl EpubReader for ZipReader {
+impl<R: Read + Seek> EpubReader for ZipReader<R> {
     fn container(&mut self) -> Result<Vec<u8>> {
-        self.readfile("META-INF/container.xml")
+        self.readfile(CONTAINER_PATH)
     }
 
     fn readfile(&mut self, path: &str) -> Result<Vec<u8>> {
         self.readfile(path)
     }
+
+    fn file_names(&mut self) -> Result<Vec<String>> {
+        self.file_names()
+    }
+
+    fn open(&mut self, path: &str) -> Result<Box<dyn Read + '_>> {
+        self.open(path)
+    }
+
+    fn first_entry(&mut self) -> Result<Option<(String, bool)>> {
+        if self.inner.is_empty() {
+            return Ok(None);
+        }
+        let file = self.inner.by_index_raw(0)?;
+        Ok(Some((
+            file.name().to_string(),
+            file.compression() == CompressionMethod::Stored,
+        )))
+    }
 }
 
 pub struct DirReader {
@@ -68,11 +121,23 @@ impl DirReader {
             .map_err(|e| EpubError::ReaderError(format!("read file:{:?} error:{:?}", path, e)))?;
         Ok(content)
     }
+
+    // `path` may not leave the book directory
+    fn contained(&self, path: &str) -> Result<PathBuf> {
+        if !href::is_contained(path) {
+            return Err(EpubError::ReaderError(format!(
+                "{:?} is outside of {:?}",
+                path,
+                self.path.display()
+            )));
+        }
+        Ok(self.path.join(path))
+    }
 }
 
 impl EpubReader for DirReader {
     fn container(&mut self) -> Result<Vec<u8>> {
-        let fp = self.path.clone().join("META-INF/container.xml");
+        let fp = self.path.clone().join(CONTAINER_PATH);
         if !fp.exists() {
             return Err(EpubError::ContainerError(format!(
                 "META-INF/container.xml not in {:?}",
@@ -82,7 +147,7 @@ impl EpubReader for DirReader {
         self.readfile(fp)
     }
     fn readfile(&mut self, path: &str) -> Result<Vec<u8>> {
-        let fp = self.path.clone().join(path);
+        let fp = self.contained(path)?;
         if !fp.exists() {
             return Err(EpubError::ReaderError(format!(
                 "{:?}not in {:?}",
@@ -92,6 +157,39 @@ impl EpubReader for DirReader {
         }
         self.readfile(fp)
     }
+
+    fn file_names(&mut self) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        walk_dir(self.path.as_path(), "", &mut names)?;
+        Ok(names)
+    }
+
+    fn open(&mut self, path: &str) -> Result<Box<dyn Read + '_>> {
+        let fp = self.contained(path)?;
+        let file = File::open(fp.as_path())
+            .map_err(|e| EpubError::ReaderError(format!("open file {:?}, error:{:?}", fp, e)))?;
+        Ok(Box::new(file))
+    }
+}
+
+fn walk_dir(dir: &Path, prefix: &str, names: &mut Vec<String>) -> Result<()> {
+    let entries = fs::read_dir(dir)
+        .map_err(|e| EpubError::ReaderError(format!("read dir {:?} error:{:?}", dir, e)))?;
+    let mut entries = entries
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<std::io::Result<Vec<PathBuf>>>()
+        .map_err(|e| EpubError::ReaderError(format!("read dir {:?} error:{:?}", dir, e)))?;
+    entries.sort();
+    for path in entries {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let name = format!("{}{}", prefix, name);
+        if path.is_dir() {
+            walk_dir(path.as_path(), format!("{}/", name).as_str(), names)?;
+        } else {
+            names.push(name);
+        }
+    }
+    Ok(())
 }
 
 impl DirReader {
@@ -102,6 +200,10 @@ impl DirReader {
 
 pub struct Reader {
     inner: Box<dyn EpubReader>,
+    encryption: Option<Encryption>,
+    key: ObfuscationKey,
+    // hrefs repaired to the entry name that only differs in case
+    aliases: BTreeMap<String, String>,
 }
 
 impl Reader {
@@ -113,41 +215,188 @@ impl Reader {
                 Box::new(ZipReader::new_from_path(path)?)
             }
         };
-        Ok(Reader { inner })
+        Ok(Reader::new(inner))
     }
 
     pub fn new_from_memory(buffer: Vec<u8>) -> Result<Self> {
         let inner = Box::new(ZipReader::new_from_memory(buffer)?);
-        Ok(Reader { inner })
+        Ok(Reader::new(inner))
+    }
+
+    // any seekable source, like a file, a memory map in a `Cursor` or a network range reader
+    pub fn new_from_reader<R: Read + Seek + 'static>(reader: R) -> Result<Self> {
+        let inner = Box::new(ZipReader::new(reader)?);
+        Ok(Reader::new(inner))
+    }
+
+    fn new(inner: Box<dyn EpubReader>) -> Self {
+        Reader {
+            inner,
+            encryption: None,
+            key: ObfuscationKey::default(),
+            aliases: BTreeMap::new(),
+        }
     }
 
-    pub fn read_meta_container(&mut self) -> Result<Container> {
-        let content = self.inner.container()?;
-        Container::new(content.as_slice())
+    fn entry(&self, href: &str) -> String {
+        match self.aliases.get(href) {
+            Some(name) => name.clone(),
+            None => href.to_string(),
+        }
     }
 
-    pub fn read_package(&mut self, path: &str) -> Result<Package> {
-        let content = self.inner.readfile(path)?;
-        Package::new(path, content.as_slice())
+    // `META-INF/encryption.xml` is optional
+    pub fn read_encryption(&mut self) -> Result<Option<&Encryption>> {
+        if !self
+            .file_names()?
+            .iter()
+            .any(|name| name == ENCRYPTION_PATH)
+        {
+            return Ok(None);
+        }
+        let content = self.inner.readfile(ENCRYPTION_PATH)?;
+        self.encryption = Some(Encryption::new(content.as_slice())?);
+        Ok(self.encryption.as_ref())
+    }
+
+    pub fn encryption(&self) -> Option<&Encryption> {
+        self.encryption.as_ref()
+    }
+
+    pub fn set_obfuscation_key(&mut self, key: ObfuscationKey) {
+        self.key = key;
+    }
+
+    // a lenient `repair` falls back to the first `.opf` file when the container is unusable
+    pub(crate) fn read_meta_container(&mut self, repair: &mut Repair) -> Result<Container> {
+        let container = self
+            .inner
+            .container()
+            .and_then(|content| Container::new(content.as_slice(), repair));
+        let error = match container {
+            Ok(container) if container.full_path().is_some() || !repair.is_lenient() => {
+                return Ok(container)
+            }
+            Ok(_) => EpubError::ContainerError("no rootfile in container".to_string()),
+            Err(e) => e,
+        };
+        let mut names = self.file_names()?;
+        names.sort();
+        let package = names
+            .iter()
+            .find(|name| name.to_ascii_lowercase().ends_with(".opf"));
+        match package {
+            Some(package) => {
+                let context = format!("package guessed as {:?}", package);
+                repair.recover(
+                    WarningKind::MissingContainer,
+                    CONTAINER_PATH,
+                    &context,
+                    error,
+                )?;
+                Ok(Container::new_with_package(package))
+            }
+            None => Err(error),
+        }
+    }
+
+    pub(crate) fn read_package(&mut self, path: &str, repair: &mut Repair) -> Result<Package> {
+        let content = self.inner.readfile(self.entry(path).as_str())?;
+        Package::parse(path, content.as_slice(), repair)
+    }
+
+    // a lenient `repair` reads a missing `href` from the entry whose name only differs in case
+    pub(crate) fn repair_case(
+        &mut self,
+        href: &str,
+        names: &[String],
+        path: &str,
+        repair: &mut Repair,
+    ) {
+        if !repair.is_lenient() || names.iter().any(|name| name == href) {
+            return;
+        }
+        if let Some(name) = names.iter().find(|name| name.eq_ignore_ascii_case(href)) {
+            let message = format!("{:?} is read from {:?}", href, name);
+            repair.warn(WarningKind::PathCase, path, &message);
+            self.aliases.insert(href.to_string(), name.clone());
+        }
     }
 
     pub fn read_nav(&mut self, href: &str) -> Result<Navigation> {
-        let content = self.inner.readfile(href)?;
+        let content = self.read_binary(href)?;
         Navigation::new_from_nav(content.as_slice())
     }
 
     pub fn read_ncx(&mut self, href: &str) -> Result<Navigation> {
-        let content = self.inner.readfile(href)?;
+        let content = self.read_binary(href)?;
         Navigation::new_from_ncx(content.as_slice())
     }
 
     pub fn read_content(&mut self, href: &str) -> Result<String> {
-        let buf = self.inner.readfile(href)?;
-        // TODO fix unwrap
-        Ok(String::from_utf8(buf).unwrap())
+        let buf = self.read_binary(href)?;
+        encoding::decode(&buf, href)
     }
 
+    // obfuscated fonts are returned de-obfuscated, encrypted resources are an error
     pub fn read_binary(&mut self, href: &str) -> Result<Vec<u8>> {
-        self.inner.readfile(href)
+        let mut content = self.inner.readfile(self.entry(href).as_str())?;
+        if let Some(data) = self.encryption.as_ref().and_then(|e| e.get(href)) {
+            self.key.apply(data, content.as_mut_slice())?;
+        }
+        Ok(content)
+    }
+
+    // a stream for large entries like audio and video, de-obfuscated like `read_binary`
+    pub fn open(&mut self, href: &str) -> Result<Box<dyn Read + '_>> {
+        let data = self.encryption.as_ref().and_then(|e| e.get(href)).cloned();
+        let entry = self.entry(href);
+        let stream = self.inner.open(entry.as_str())?;
+        match data {
+            Some(data) => self.key.reader(&data, stream),
+            None => Ok(stream),
+        }
+    }
+
+    // the bytes stored in the container
+    pub fn read_raw(&mut self, href: &str) -> Result<Vec<u8>> {
+        self.inner.readfile(self.entry(href).as_str())
+    }
+
+    pub fn file_names(&mut self) -> Result<Vec<String>> {
+        self.inner.file_names()
+    }
+
+    // also true for an href repaired to an entry that only differs in case
+    pub fn contains(&mut self, href: &str) -> Result<bool> {
+        let entry = self.entry(href);
+        Ok(self.inner.file_names()?.contains(&entry))
+    }
+
+    pub fn first_entry(&mut self) -> Result<Option<(String, bool)>> {
+        self.inner.first_entry()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::reader::{DirReader, EpubReader};
+
+    #[test]
+    fn test_dir_reader_escape() {
+        let root = std::env::temp_dir().join(format!("epub-dir-reader-{}", std::process::id()));
+        let dir = root.join("book");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(root.join("secret.txt"), b"secret").unwrap();
+        fs::write(dir.join("a.txt"), b"a").unwrap();
+        let mut reader = DirReader::try_new(dir).unwrap();
+        assert_eq!(EpubReader::readfile(&mut reader, "a.txt").unwrap(), b"a");
+        assert!(EpubReader::readfile(&mut reader, "../secret.txt").is_err());
+        assert!(reader.open("../secret.txt").is_err());
+        let absolute = root.join("secret.txt");
+        assert!(reader.open(absolute.to_str().unwrap()).is_err());
+        fs::remove_dir_all(root).unwrap();
     }
 }
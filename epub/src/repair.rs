@@ -0,0 +1,104 @@
+use std::fmt;
+
+use crate::error::{EpubError, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WarningKind {
+    // `META-INF/container.xml` is missing or broken, the package was found by scanning the container
+    MissingContainer,
+    // the container lists a rootfile that is not an OPF package
+    InvalidContainer,
+    // a manifest, spine or guide entry could not be parsed and was skipped
+    InvalidItem,
+    // a missing value was guessed, like the media type of a manifest item
+    RepairedItem,
+    // an href only matches a file in the container when ignoring case
+    PathCase,
+    // the navigation document or the NCX is broken
+    Navigation,
+    // `META-INF/encryption.xml` is broken, the book is read without it
+    Encryption,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warning {
+    kind: WarningKind,
+    path: String,
+    message: String,
+}
+
+impl Warning {
+    pub fn new(kind: WarningKind, path: &str, message: &str) -> Self {
+        Warning {
+            kind,
+            path: path.to_string(),
+            message: message.to_string(),
+        }
+    }
+
+    pub fn kind(&self) -> WarningKind {
+        self.kind
+    }
+
+    // the file in the container the problem was found in
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} {}: {}", self.kind, self.path, self.message)
+    }
+}
+
+// in strict mode every problem is an error, in lenient mode it is recorded and parsing goes on
+#[derive(Debug, Default)]
+pub(crate) struct Repair {
+    lenient: bool,
+    warnings: Vec<Warning>,
+}
+
+impl Repair {
+    pub fn strict() -> Self {
+        Repair::default()
+    }
+
+    pub fn lenient() -> Self {
+        Repair {
+            lenient: true,
+            warnings: Vec::new(),
+        }
+    }
+
+    pub fn is_lenient(&self) -> bool {
+        self.lenient
+    }
+
+    // `Ok` when the caller may skip or repair what caused `error`
+    pub fn recover(
+        &mut self,
+        kind: WarningKind,
+        path: &str,
+        context: &str,
+        error: EpubError,
+    ) -> Result<()> {
+        if !self.lenient {
+            return Err(error);
+        }
+        self.warn(kind, path, format!("{}: {}", context, error).as_str());
+        Ok(())
+    }
+
+    pub fn warn(&mut self, kind: WarningKind, path: &str, message: &str) {
+        self.warnings.push(Warning::new(kind, path, message));
+    }
+
+    pub fn into_warnings(self) -> Vec<Warning> {
+        self.warnings
+    }
+}
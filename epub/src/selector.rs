@@ -0,0 +1,576 @@
+/**
+a subset of https://www.w3.org/TR/selectors-4/: type, class, id and attribute selectors,
+the descendant and child combinators and `:nth-child`, `:first-child` and `:last-child`
+**/
+use std::rc::Rc;
+use std::str::FromStr;
+
+use markup5ever_rcdom::{Handle, Node, NodeData};
+
+use crate::error::{EpubError, Result};
+use crate::package::{DC_NAMESPACE, OPF_NAMESPACE};
+use crate::xml;
+
+// prefixes usable in `prefix|name`, CSS has no other way to declare them here
+const NAMESPACES: [(&str, &str); 8] = [
+    ("html", xml::XHTML_NAMESPACE),
+    ("xhtml", xml::XHTML_NAMESPACE),
+    ("epub", xml::EPUB_NAMESPACE),
+    ("svg", xml::SVG_NAMESPACE),
+    ("math", xml::MATHML_NAMESPACE),
+    ("ncx", xml::NCX_NAMESPACE),
+    ("opf", OPF_NAMESPACE),
+    ("dc", DC_NAMESPACE),
+];
+
+// `None` matches every namespace, `*|name` or a name without a prefix
+#[derive(Debug, Clone, PartialEq)]
+struct Name {
+    ns: Option<String>,
+    local: Option<String>,
+}
+
+impl Name {
+    fn matches(&self, ns: &str, local: &str) -> bool {
+        self.ns.as_ref().is_none_or(|n| n == ns) && self.local.as_ref().is_none_or(|l| l == local)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Operator {
+    // `[attr]`
+    Exists,
+    // `[attr=value]`
+    Equal(String),
+    // `[attr~=value]`, one of the whitespace separated words
+    Includes(String),
+    // `[attr|=value]`, `value` or starting with `value-`
+    DashMatch(String),
+    // `[attr^=value]`
+    Prefix(String),
+    // `[attr$=value]`
+    Suffix(String),
+    // `[attr*=value]`
+    Substring(String),
+}
+
+impl Operator {
+    fn matches(&self, value: &str) -> bool {
+        match self {
+            Operator::Exists => true,
+            Operator::Equal(v) => value == v,
+            Operator::Includes(v) => value.split_whitespace().any(|word| word == v),
+            Operator::DashMatch(v) => {
+                value == v
+                    || value
+                        .strip_prefix(v.as_str())
+                        .is_some_and(|r| r.starts_with('-'))
+            }
+            Operator::Prefix(v) => !v.is_empty() && value.starts_with(v.as_str()),
+            Operator::Suffix(v) => !v.is_empty() && value.ends_with(v.as_str()),
+            Operator::Substring(v) => !v.is_empty() && value.contains(v.as_str()),
+        }
+    }
+}
+
+// `an+b`, the 1-based position among the element siblings
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Nth {
+    a: i64,
+    b: i64,
+}
+
+impl Nth {
+    fn matches(&self, position: i64) -> bool {
+        match self.a {
+            0 => position == self.b,
+            a => (position - self.b) % a == 0 && (position - self.b) / a >= 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Condition {
+    Attribute(Name, Operator),
+    NthChild(Nth),
+    LastChild,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Compound {
+    name: Name,
+    conditions: Vec<Condition>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Combinator {
+    Descendant,
+    Child,
+}
+
+// one selector of a list, ancestors go right to left, each with the combinator to its right
+#[derive(Debug, Clone, PartialEq)]
+struct Complex {
+    subject: Compound,
+    ancestors: Vec<(Combinator, Compound)>,
+}
+
+fn siblings(node: &Handle) -> Vec<Rc<Node>> {
+    match xml::parent(node) {
+        Some(parent) => parent
+            .children
+            .borrow()
+            .iter()
+            .filter(|child| matches!(child.data, NodeData::Element { .. }))
+            .cloned()
+            .collect(),
+        None => vec![node.clone()],
+    }
+}
+
+// unprefixed attribute names match in every namespace, like `xml::parse_attribute`
+fn match_compound(compound: &Compound, node: &Handle) -> bool {
+    let NodeData::Element {
+        ref name,
+        ref attrs,
+        ..
+    } = node.data
+    else {
+        return false;
+    };
+    if !compound.name.matches(&name.ns, &name.local) {
+        return false;
+    }
+    compound.conditions.iter().all(|condition| match condition {
+        Condition::Attribute(attr_name, operator) => attrs.borrow().iter().any(|attr| {
+            attr_name.matches(&attr.name.ns, &attr.name.local) && operator.matches(&attr.value)
+        }),
+        Condition::NthChild(nth) => {
+            let position = siblings(node).iter().position(|s| Rc::ptr_eq(s, node));
+            position.is_some_and(|p| nth.matches(p as i64 + 1))
+        }
+        Condition::LastChild => siblings(node).last().is_some_and(|s| Rc::ptr_eq(s, node)),
+    })
+}
+
+fn match_ancestors(ancestors: &[(Combinator, Compound)], node: &Handle) -> bool {
+    let Some(((combinator, compound), rest)) = ancestors.split_first() else {
+        return true;
+    };
+    let mut parent = xml::parent(node);
+    while let Some(current) = parent {
+        if !matches!(current.data, NodeData::Element { .. }) {
+            return false;
+        }
+        if match_compound(compound, &current) && match_ancestors(rest, &current) {
+            return true;
+        }
+        if *combinator == Combinator::Child {
+            return false;
+        }
+        parent = xml::parent(&current);
+    }
+    false
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Selector {
+    list: Vec<Complex>,
+}
+
+impl Selector {
+    pub fn parse(selector: &str) -> Result<Self> {
+        let content: Vec<char> = selector.chars().collect();
+        SelectorParser::new(&content).parse()
+    }
+
+    pub fn matches(&self, node: &Handle) -> bool {
+        self.list.iter().any(|complex| {
+            match_compound(&complex.subject, node) && match_ancestors(&complex.ancestors, node)
+        })
+    }
+
+    // matching elements below `node`, in document order
+    pub fn select(&self, node: &Handle) -> Vec<Rc<Node>> {
+        let mut res = Vec::new();
+        self.collect(node, &mut res);
+        res
+    }
+
+    fn collect(&self, node: &Handle, res: &mut Vec<Rc<Node>>) {
+        for child in node
+            .children
+            .borrow()
+            .iter()
+            .filter(|child| matches!(child.data, NodeData::Element { .. }))
+        {
+            if self.matches(child) {
+                res.push(child.clone());
+            }
+            self.collect(child, res);
+        }
+    }
+
+    pub fn select_first(&self, node: &Handle) -> Option<Rc<Node>> {
+        node.children
+            .borrow()
+            .iter()
+            .filter(|child| matches!(child.data, NodeData::Element { .. }))
+            .find_map(|child| match self.matches(child) {
+                true => Some(child.clone()),
+                false => self.select_first(child),
+            })
+    }
+}
+
+impl FromStr for Selector {
+    type Err = EpubError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Selector::parse(s)
+    }
+}
+
+struct SelectorParser<'a> {
+    content: &'a [char],
+    current: usize,
+}
+
+impl<'a> SelectorParser<'a> {
+    fn new(content: &'a [char]) -> Self {
+        SelectorParser {
+            content,
+            current: 0,
+        }
+    }
+
+    fn error(&self, msg: &str) -> EpubError {
+        EpubError::ParseError(format!("selector {} at position {}", msg, self.current))
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.content.get(self.current).copied()
+    }
+
+    fn expect(&mut self, expected: char) -> Result<()> {
+        match self.peek() {
+            Some(c) if c == expected => {
+                self.current += 1;
+                Ok(())
+            }
+            Some(c) => Err(self.error(&format!("expected '{}' found '{}'", expected, c))),
+            None => Err(self.error(&format!("expected '{}'", expected))),
+        }
+    }
+
+    fn skip_whitespace(&mut self) -> bool {
+        let start = self.current;
+        while self.peek().is_some_and(|c| c.is_whitespace()) {
+            self.current += 1;
+        }
+        self.current > start
+    }
+
+    fn parse(&mut self) -> Result<Selector> {
+        let mut list = vec![self.parse_complex()?];
+        while self.peek() == Some(',') {
+            self.current += 1;
+            list.push(self.parse_complex()?);
+        }
+        if self.peek().is_some() {
+            return Err(self.error("unexpected character"));
+        }
+        Ok(Selector { list })
+    }
+
+    fn parse_complex(&mut self) -> Result<Complex> {
+        self.skip_whitespace();
+        let mut subject = self.parse_compound()?;
+        let mut ancestors = Vec::new();
+        loop {
+            let whitespace = self.skip_whitespace();
+            let combinator = match self.peek() {
+                Some('>') => {
+                    self.current += 1;
+                    self.skip_whitespace();
+                    Combinator::Child
+                }
+                Some(',') | None => break,
+                Some(_) if whitespace => Combinator::Descendant,
+                Some(c) => return Err(self.error(&format!("unsupported combinator '{}'", c))),
+            };
+            let next = self.parse_compound()?;
+            ancestors.push((combinator, std::mem::replace(&mut subject, next)));
+        }
+        ancestors.reverse();
+        Ok(Complex { subject, ancestors })
+    }
+
+    fn parse_compound(&mut self) -> Result<Compound> {
+        let start = self.current;
+        let name = match self.peek() {
+            Some(c) if c == '*' || c == '|' || is_name_char(c) => self.parse_name()?,
+            _ => Name {
+                ns: None,
+                local: None,
+            },
+        };
+        let mut conditions = Vec::new();
+        loop {
+            match self.peek() {
+                Some('#') => {
+                    self.current += 1;
+                    let id = self.read_identifier()?;
+                    conditions.push(Condition::Attribute(
+                        any_namespace("id"),
+                        Operator::Equal(id),
+                    ));
+                }
+                Some('.') => {
+                    self.current += 1;
+                    let class = self.read_identifier()?;
+                    conditions.push(Condition::Attribute(
+                        any_namespace("class"),
+                        Operator::Includes(class),
+                    ));
+                }
+                Some('[') => conditions.push(self.parse_attribute()?),
+                Some(':') => conditions.push(self.parse_pseudo_class()?),
+                _ => break,
+            }
+        }
+        if self.current == start {
+            return Err(self.error("expected a selector"));
+        }
+        Ok(Compound { name, conditions })
+    }
+
+    // `name`, `*`, `prefix|name`, `*|name` or `|name` for no namespace
+    fn parse_name(&mut self) -> Result<Name> {
+        let first = match self.peek() {
+            Some('*') => {
+                self.current += 1;
+                None
+            }
+            Some('|') => Some(String::new()),
+            _ => Some(self.read_identifier()?),
+        };
+        if self.peek() != Some('|') || self.content.get(self.current + 1) == Some(&'=') {
+            return Ok(Name {
+                ns: None,
+                local: first,
+            });
+        }
+        self.current += 1;
+        let ns = match first {
+            None => None,
+            Some(prefix) if prefix.is_empty() => Some(String::new()),
+            Some(prefix) => match NAMESPACES.iter().find(|(p, _)| *p == prefix) {
+                Some((_, ns)) => Some(ns.to_string()),
+                None => return Err(self.error(&format!("unknown namespace prefix {:?}", prefix))),
+            },
+        };
+        let local = match self.peek() {
+            Some('*') => {
+                self.current += 1;
+                None
+            }
+            _ => Some(self.read_identifier()?),
+        };
+        Ok(Name { ns, local })
+    }
+
+    fn parse_attribute(&mut self) -> Result<Condition> {
+        self.expect('[')?;
+        self.skip_whitespace();
+        let name = self.parse_name()?;
+        if name.local.is_none() {
+            return Err(self.error("expected an attribute name"));
+        }
+        self.skip_whitespace();
+        let operator = match self.peek() {
+            Some(']') => None,
+            Some('=') => {
+                self.current += 1;
+                Some('=')
+            }
+            Some(c) if "~|^$*".contains(c) => {
+                self.current += 1;
+                self.expect('=')?;
+                Some(c)
+            }
+            _ => return Err(self.error("expected an attribute operator")),
+        };
+        let operator = match operator {
+            None => Operator::Exists,
+            Some(c) => {
+                self.skip_whitespace();
+                let value = self.read_value()?;
+                self.skip_whitespace();
+                match c {
+                    '=' => Operator::Equal(value),
+                    '~' => Operator::Includes(value),
+                    '|' => Operator::DashMatch(value),
+                    '^' => Operator::Prefix(value),
+                    '$' => Operator::Suffix(value),
+                    _ => Operator::Substring(value),
+                }
+            }
+        };
+        self.expect(']')?;
+        Ok(Condition::Attribute(name, operator))
+    }
+
+    fn parse_pseudo_class(&mut self) -> Result<Condition> {
+        self.expect(':')?;
+        let name = self.read_identifier()?.to_ascii_lowercase();
+        match name.as_str() {
+            "first-child" => Ok(Condition::NthChild(Nth { a: 0, b: 1 })),
+            "last-child" => Ok(Condition::LastChild),
+            "nth-child" => {
+                self.expect('(')?;
+                let start = self.current;
+                while self.peek().is_some_and(|c| c != ')') {
+                    self.current += 1;
+                }
+                let argument: String = self.content[start..self.current].iter().collect();
+                self.expect(')')?;
+                let nth = parse_nth(&argument)
+                    .ok_or_else(|| self.error(&format!("invalid :nth-child({})", argument)))?;
+                Ok(Condition::NthChild(nth))
+            }
+            _ => Err(self.error(&format!("unsupported pseudo-class :{}", name))),
+        }
+    }
+
+    fn read_identifier(&mut self) -> Result<String> {
+        let start = self.current;
+        while self.peek().is_some_and(is_name_char) {
+            self.current += 1;
+        }
+        if self.current == start {
+            return Err(self.error("expected an identifier"));
+        }
+        Ok(self.content[start..self.current].iter().collect())
+    }
+
+    fn read_value(&mut self) -> Result<String> {
+        let quote = match self.peek() {
+            Some(c @ ('"' | '\'')) => c,
+            _ => return self.read_identifier(),
+        };
+        self.current += 1;
+        let start = self.current;
+        while self.peek().is_some_and(|c| c != quote) {
+            self.current += 1;
+        }
+        let value = self.content[start..self.current].iter().collect();
+        self.expect(quote)?;
+        Ok(value)
+    }
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '-' || c == '_' || !c.is_ascii()
+}
+
+fn any_namespace(local: &str) -> Name {
+    Name {
+        ns: None,
+        local: Some(local.to_string()),
+    }
+}
+
+// `odd`, `even`, `b`, `an` or `an+b`
+fn parse_nth(argument: &str) -> Option<Nth> {
+    let argument: String = argument
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>()
+        .to_ascii_lowercase();
+    match argument.as_str() {
+        "odd" => return Some(Nth { a: 2, b: 1 }),
+        "even" => return Some(Nth { a: 2, b: 0 }),
+        _ => {}
+    }
+    let Some((a, b)) = argument.split_once('n') else {
+        return Some(Nth {
+            a: 0,
+            b: argument.parse().ok()?,
+        });
+    };
+    let a = match a {
+        "" | "+" => 1,
+        "-" => -1,
+        a => a.parse().ok()?,
+    };
+    let b = match b {
+        "" => 0,
+        b if b.starts_with(['+', '-']) => b.trim_start_matches('+').parse().ok()?,
+        _ => return None,
+    };
+    Some(Nth { a, b })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::selector::Selector;
+    use crate::xml::{self, XMLDocument};
+
+    const DOCUMENT: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops">
+<head><title>Doc</title><link rel="stylesheet" href="a.css"/><link rel="icon" href="i.png"/></head>
+<body>
+  <section id="s1" class="chapter intro">
+    <p>one</p><p class="note">two</p><p>three</p>
+  </section>
+  <nav epub:type="toc"><ol><li><a href="c1.xhtml">c1</a></li></ol></nav>
+  <img src="a.png" alt="a"/>
+</body>
+</html>"#;
+
+    fn texts(doc: &XMLDocument, selector: &str) -> Vec<String> {
+        doc.select(selector)
+            .unwrap()
+            .iter()
+            .map(xml::parse_all_text)
+            .collect()
+    }
+
+    #[test]
+    fn test_select() {
+        let doc = XMLDocument::try_new(DOCUMENT.as_bytes()).unwrap();
+        assert_eq!(texts(&doc, "section > p"), ["one", "two", "three"]);
+        assert_eq!(texts(&doc, "body .note"), ["two"]);
+        assert_eq!(texts(&doc, "#s1 p:nth-child(odd)"), ["one", "three"]);
+        assert_eq!(
+            texts(&doc, "p:nth-child(-n+2):last-child"),
+            Vec::<String>::new()
+        );
+        assert_eq!(texts(&doc, "section.intro p:first-child"), ["one"]);
+        assert_eq!(texts(&doc, "nav[epub|type~=toc] a"), ["c1"]);
+        assert_eq!(texts(&doc, "html|title"), ["Doc"]);
+        assert!(doc.select("dc|title").unwrap().is_empty());
+        assert!(doc.select("body > p").unwrap().is_empty());
+
+        let resources = doc.select("img[src], link[rel=stylesheet]").unwrap();
+        let names: Vec<String> = resources
+            .iter()
+            .filter_map(|node| {
+                xml::parse_attribute(node, "src").or_else(|| xml::parse_attribute(node, "href"))
+            })
+            .collect();
+        assert_eq!(names, ["a.css", "a.png"]);
+        assert_eq!(
+            doc.select_first("[href$='.xhtml']")
+                .unwrap()
+                .and_then(|a| xml::parse_attribute(&a, "href")),
+            Some("c1.xhtml".to_string())
+        );
+
+        assert!(Selector::parse("p + p").is_err());
+        assert!(Selector::parse("foo|p").is_err());
+        assert!(Selector::parse("p:nth-child(x)").is_err());
+        assert!(Selector::parse("p,").is_err());
+    }
+}
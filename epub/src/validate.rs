@@ -0,0 +1,632 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use markup5ever_rcdom::{Handle, NodeData};
+
+use crate::book::Book;
+use crate::error::Result;
+use crate::href;
+use crate::nav::{NavItem, Navigation};
+use crate::package::{
+    is_content_document, is_core_media_type, ManifestItem, Package, SVG_MEDIA_TYPE,
+};
+use crate::writer::{MIMETYPE, MIMETYPE_PATH};
+use crate::xml::{self, XMLDocument};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Code {
+    // `mimetype` is not the first entry of the zip container
+    MimetypeNotFirst,
+    // `mimetype` is compressed
+    MimetypeCompressed,
+    // `mimetype` is not `application/epub+zip`
+    MimetypeContent,
+    // a manifest item is not in the container
+    MissingResource,
+    // a spine itemref does not reference a manifest item
+    UnresolvedIdref,
+    DuplicateId,
+    // dc:identifier, dc:title, dc:language or dcterms:modified is missing
+    MissingMetadata,
+    // the package unique-identifier does not reference a dc:identifier
+    UniqueIdentifier,
+    // an EPUB 3 package has no manifest item with the `nav` property
+    MissingNavigation,
+    // a content document uses a feature without declaring its manifest property
+    MissingProperty,
+    // a manifest property is declared for a feature the content document does not use
+    UnusedProperty,
+    // a foreign resource has no fallback chain
+    MissingFallback,
+    // a fallback references an unknown item or loops
+    InvalidFallback,
+    // a content document can not be read or parsed
+    UnreadableResource,
+    // a navigation link points at a file that is not in the container
+    BrokenLink,
+    // a navigation link points at an id that is not in its document
+    BrokenFragment,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    severity: Severity,
+    code: Code,
+    location: String,
+    message: String,
+}
+
+impl Finding {
+    pub fn severity(&self) -> Severity {
+        self.severity
+    }
+
+    pub fn code(&self) -> Code {
+        self.code
+    }
+
+    // the file in the container the finding is about
+    pub fn location(&self) -> &str {
+        &self.location
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?}({:?}) {}: {}",
+            self.severity, self.code, self.location, self.message
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    findings: Vec<Finding>,
+}
+
+impl Report {
+    pub fn findings(&self) -> &[Finding] {
+        self.findings.as_slice()
+    }
+
+    pub fn errors(&self) -> impl Iterator<Item = &Finding> {
+        self.findings
+            .iter()
+            .filter(|f| f.severity == Severity::Error)
+    }
+
+    pub fn warnings(&self) -> impl Iterator<Item = &Finding> {
+        self.findings
+            .iter()
+            .filter(|f| f.severity == Severity::Warning)
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.errors().next().is_none()
+    }
+
+    fn push(&mut self, severity: Severity, code: Code, location: &str, message: String) {
+        self.findings.push(Finding {
+            severity,
+            code,
+            location: location.to_string(),
+            message,
+        });
+    }
+
+    fn error(&mut self, code: Code, location: &str, message: String) {
+        self.push(Severity::Error, code, location, message);
+    }
+
+    fn warning(&mut self, code: Code, location: &str, message: String) {
+        self.push(Severity::Warning, code, location, message);
+    }
+}
+
+// check `book` against the OCF, package and navigation rules
+pub fn validate(book: &mut Book) -> Result<Report> {
+    let names = book.file_names()?.into_iter().collect();
+    let mut validator = Validator {
+        names,
+        ids: BTreeMap::new(),
+        report: Report::default(),
+    };
+    validator.check_container(book)?;
+    validator.check_package(book.package())?;
+    validator.check_content(book);
+    validator.check_navigation(book);
+    Ok(validator.report)
+}
+
+// resources fetched from outside the container, `data:` urls are inline
+fn is_remote_resource(value: &str) -> bool {
+    href::is_remote(value) && !value.starts_with("data:")
+}
+
+fn elements(node: &Handle, res: &mut Vec<Handle>) {
+    for child in node.children.borrow().iter() {
+        if matches!(child.data, NodeData::Element { .. }) {
+            res.push(child.clone());
+            elements(child, res);
+        }
+    }
+}
+
+fn local_name(node: &Handle) -> String {
+    match node.data {
+        NodeData::Element { ref name, .. } => name.local.to_string(),
+        _ => String::new(),
+    }
+}
+
+struct Validator {
+    names: BTreeSet<String>,
+    // element ids of every parsed content document
+    ids: BTreeMap<String, BTreeSet<String>>,
+    report: Report,
+}
+
+impl Validator {
+    fn check_container(&mut self, book: &mut Book) -> Result<()> {
+        if let Some((name, stored)) = book.first_entry()? {
+            if name != MIMETYPE_PATH {
+                let message = format!("the first entry is {:?}", name);
+                self.report
+                    .error(Code::MimetypeNotFirst, MIMETYPE_PATH, message);
+            } else if !stored {
+                let message = "mimetype must be stored without compression".to_string();
+                self.report
+                    .error(Code::MimetypeCompressed, MIMETYPE_PATH, message);
+            }
+        }
+        let content = match self.names.contains(MIMETYPE_PATH) {
+            true => book.read_binary_file(MIMETYPE_PATH)?,
+            false => Vec::new(),
+        };
+        if content != MIMETYPE.as_bytes() {
+            let message = format!("mimetype must be {:?}", MIMETYPE);
+            self.report
+                .error(Code::MimetypeContent, MIMETYPE_PATH, message);
+        }
+        Ok(())
+    }
+
+    fn check_package(&mut self, package: &Package) -> Result<()> {
+        let path = package.path();
+        let metadata = package.metadata();
+        let mut missing = Vec::new();
+        if metadata.identifiers().is_empty() {
+            missing.push("dc:identifier");
+        }
+        if metadata.titles().is_empty() {
+            missing.push("dc:title");
+        }
+        if metadata.languages().is_empty() {
+            missing.push("dc:language");
+        }
+        let epub3 = package.version().starts_with('3');
+        if epub3 && metadata.modified().is_none() {
+            missing.push("dcterms:modified");
+        }
+        for name in missing {
+            self.report
+                .error(Code::MissingMetadata, path, format!("{} is missing", name));
+        }
+        match package.unique_identifier() {
+            Some(id) if metadata.identifier(id).is_some() => {}
+            id => {
+                let message = format!("unique-identifier {:?} is not a dc:identifier", id);
+                self.report.error(Code::UniqueIdentifier, path, message);
+            }
+        }
+        if epub3 && package.nav_item().is_none() {
+            let message = "no manifest item has the nav property".to_string();
+            self.report.error(Code::MissingNavigation, path, message);
+        }
+
+        let doc = XMLDocument::try_new(&package.to_bytes()?)?;
+        self.check_ids(&doc, path);
+
+        for item in package.manifest() {
+            if !href::is_remote(item.href()) {
+                match href::resolve(path, item.href()) {
+                    Ok(href) if self.names.contains(href.path()) => {}
+                    Ok(href) => {
+                        let message = format!(
+                            "{:?} of item {:?} is not in the container",
+                            href.path(),
+                            item.id()
+                        );
+                        self.report.error(Code::MissingResource, path, message);
+                    }
+                    Err(e) => {
+                        let message = format!("item {:?}: {}", item.id(), e);
+                        self.report.error(Code::MissingResource, path, message);
+                    }
+                }
+            }
+            match package.resolve_fallback(item.id(), is_core_media_type) {
+                Ok(Some(_)) => {}
+                Ok(None) => {
+                    let message = format!(
+                        "item {:?} has the foreign media type {:?} and no core media type fallback",
+                        item.id(),
+                        item.media_type()
+                    );
+                    match item.fallback() {
+                        Some(_) => self.report.error(Code::MissingFallback, path, message),
+                        None => self.report.warning(Code::MissingFallback, path, message),
+                    }
+                }
+                Err(e) => {
+                    let message = format!("item {:?}: {}", item.id(), e);
+                    self.report.error(Code::InvalidFallback, path, message);
+                }
+            }
+        }
+
+        for itemref in package.spine() {
+            match package.get_manifest(itemref.idref()) {
+                None => {
+                    let message =
+                        format!("spine idref {:?} is not in the manifest", itemref.idref());
+                    self.report.error(Code::UnresolvedIdref, path, message);
+                }
+                Some(item) => match package.resolve_fallback(item.id(), is_content_document) {
+                    Ok(Some(_)) => {}
+                    Ok(None) => {
+                        let message = format!(
+                            "spine item {:?} is not a content document and has no content document fallback",
+                            item.id()
+                        );
+                        self.report.error(Code::MissingFallback, path, message);
+                    }
+                    Err(e) => {
+                        let message = format!("spine item {:?}: {}", item.id(), e);
+                        self.report.error(Code::InvalidFallback, path, message);
+                    }
+                },
+            }
+        }
+        Ok(())
+    }
+
+    fn check_ids(&mut self, doc: &XMLDocument, path: &str) -> BTreeSet<String> {
+        let mut ids = BTreeSet::new();
+        let mut nodes = Vec::new();
+        if let Some(root) = doc.root() {
+            nodes.push(root.clone());
+            elements(&root, &mut nodes);
+        }
+        for node in nodes.iter() {
+            if let Some(id) = xml::parse_attribute(node, "id") {
+                if !ids.insert(id.clone()) {
+                    self.report.error(
+                        Code::DuplicateId,
+                        path,
+                        format!("id {:?} is not unique", id),
+                    );
+                }
+            }
+        }
+        ids
+    }
+
+    fn check_content(&mut self, book: &mut Book) {
+        let package = book.package();
+        let epub3 = package.version().starts_with('3');
+        let items: Vec<ManifestItem> = package
+            .manifest()
+            .iter()
+            .filter(|item| is_content_document(item.media_type()))
+            .map(|item| item.to_owned())
+            .collect();
+        for item in items {
+            let Ok(path) = book.resolve_path(item.href()) else {
+                continue;
+            };
+            if !self.names.contains(&path) {
+                continue;
+            }
+            let doc = match book
+                .read_binary_file(&path)
+                .and_then(|content| XMLDocument::try_new_from_entry(&content, &path))
+            {
+                Ok(doc) => doc,
+                Err(e) => {
+                    let message = format!("item {:?} can not be parsed: {}", item.id(), e);
+                    self.report.error(Code::UnreadableResource, &path, message);
+                    continue;
+                }
+            };
+            let ids = self.check_ids(&doc, &path);
+            self.ids.insert(path.clone(), ids);
+            if epub3 {
+                self.check_properties(&doc, &item, &path);
+            }
+        }
+    }
+
+    fn check_properties(&mut self, doc: &XMLDocument, item: &ManifestItem, path: &str) {
+        // the `svg` property is for XHTML documents with embedded SVG
+        let svg_document = item.media_type() == SVG_MEDIA_TYPE;
+        let mut nodes = Vec::new();
+        if let Some(root) = doc.root() {
+            elements(&root, &mut nodes);
+        }
+        let mut used = BTreeSet::new();
+        for node in nodes.iter() {
+            let name = local_name(node);
+            match name.as_str() {
+                "script" => {
+                    used.insert("scripted");
+                }
+                "svg" => {
+                    used.insert("svg");
+                }
+                "math" => {
+                    used.insert("mathml");
+                }
+                _ => {}
+            }
+            let mut sources = vec!["src", "data"];
+            if name == "link" {
+                sources.push("href");
+            }
+            if sources
+                .iter()
+                .filter_map(|attr| xml::parse_attribute(node, attr))
+                .any(|value| is_remote_resource(&value))
+            {
+                used.insert("remote-resources");
+            }
+        }
+        for property in ["scripted", "svg", "mathml", "remote-resources"] {
+            if svg_document && property == "svg" {
+                continue;
+            }
+            match (used.contains(property), item.has_property(property)) {
+                (true, false) => {
+                    let message = format!("item {:?} needs the {:?} property", item.id(), property);
+                    self.report.error(Code::MissingProperty, path, message);
+                }
+                (false, true) => {
+                    let message = format!(
+                        "item {:?} declares the {:?} property but does not use it",
+                        item.id(),
+                        property
+                    );
+                    self.report.warning(Code::UnusedProperty, path, message);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn check_navigation(&mut self, book: &Book) {
+        let package = book.package();
+        let documents = [
+            (package.nav_item(), book.nav_document()),
+            (package.ncx_item(), book.ncx()),
+        ];
+        for (item, navigation) in documents {
+            if let (Some(item), Some(navigation)) = (item, navigation) {
+                if let Ok(path) = book.resolve_path(item.href()) {
+                    self.check_links(navigation, &path);
+                }
+            }
+        }
+    }
+
+    fn check_links(&mut self, navigation: &Navigation, path: &str) {
+        let mut items: Vec<&NavItem> = Vec::new();
+        let lists = [
+            navigation.toc(),
+            navigation.landmarks(),
+            navigation.page_list(),
+        ];
+        let others = navigation.others().iter().map(|list| list.items());
+        for list in lists.into_iter().chain(others) {
+            collect(list, &mut items);
+        }
+        for item in items {
+            let href = item.href();
+            if href.is_empty() || href::is_remote(href) {
+                continue;
+            }
+            let (target, fragment) = match href::resolve(path, href) {
+                Ok(href) => (
+                    href.path().to_string(),
+                    href.fragment().unwrap_or_default().to_string(),
+                ),
+                Err(e) => {
+                    let message = format!("{:?} links to {:?}: {}", item.text(), href, e);
+                    self.report.error(Code::BrokenLink, path, message);
+                    continue;
+                }
+            };
+            if !self.names.contains(&target) {
+                let message = format!(
+                    "{:?} links to {:?} which is not in the container",
+                    item.text(),
+                    target
+                );
+                self.report.error(Code::BrokenLink, path, message);
+                continue;
+            }
+            if fragment.is_empty() {
+                continue;
+            }
+            if let Some(ids) = self.ids.get(&target) {
+                if !ids.contains(&fragment) {
+                    let message = format!(
+                        "{:?} links to the unknown id {:?} in {:?}",
+                        item.text(),
+                        fragment,
+                        target
+                    );
+                    self.report.error(Code::BrokenFragment, path, message);
+                }
+            }
+        }
+    }
+}
+
+fn collect<'a>(items: &'a [NavItem], res: &mut Vec<&'a NavItem>) {
+    for item in items {
+        res.push(item);
+        collect(item.children(), res);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::book::Book;
+    use crate::validate::{validate, Code, Severity};
+    use crate::writer::{EpubWriter, ZipWriter};
+
+    const PACKAGE: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="3.0" unique-identifier="uid">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+    <dc:identifier id="uid">urn:uuid:validate-test</dc:identifier>
+    <dc:title>Validate Test</dc:title>
+    <dc:language>en</dc:language>
+    <meta property="dcterms:modified">2024-01-01T00:00:00Z</meta>
+  </metadata>
+  <manifest>
+    <item id="nav" href="nav.xhtml" media-type="application/xhtml+xml" properties="nav"/>
+    <item id="c1" href="text/c1.xhtml" media-type="application/xhtml+xml"/>
+    <item id="pdf" href="c2.pdf" media-type="application/pdf" fallback="c1"/>
+    <item id="css" href="style.css" media-type="text/css"/>
+  </manifest>
+  <spine>
+    <itemref idref="c1"/>
+    <itemref idref="pdf"/>
+  </spine>
+</package>"#;
+
+    const NAV: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops">
+<head><title>Validate Test</title></head>
+<body><nav epub:type="toc"><ol>
+<li><a href="text/c1.xhtml#s1">One</a></li>
+<li><a href="text/c1.xhtml#s2">Two</a></li>
+<li><a href="text/c3.xhtml">Three</a></li>
+</ol></nav></body>
+</html>"#;
+
+    const CHAPTER: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<html xmlns="http://www.w3.org/1999/xhtml"><head><title>One</title><script>let a = 1;</script></head>
+<body><p id="s1">one</p><p id="s1">two</p></body></html>"#;
+
+    fn book(package: &str, css: bool) -> Book {
+        let mut writer = ZipWriter::new_to_memory().unwrap();
+        writer.write_container("OEBPS/content.opf").unwrap();
+        let mut files = vec![
+            ("OEBPS/content.opf", package),
+            ("OEBPS/nav.xhtml", NAV),
+            ("OEBPS/text/c1.xhtml", CHAPTER),
+            ("OEBPS/c2.pdf", "%PDF"),
+        ];
+        if css {
+            files.push(("OEBPS/style.css", "p {}"));
+        }
+        for (name, content) in files {
+            writer.write_file(name, content.as_bytes()).unwrap();
+        }
+        Book::open_from_memory(writer.into_inner().unwrap().into_inner()).unwrap()
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut valid = book(
+            &PACKAGE.replace(r#"id="c1" href"#, r#"id="c1" properties="scripted" href"#),
+            true,
+        );
+        let report = validate(&mut valid).unwrap();
+        let codes: Vec<Code> = report.findings().iter().map(|f| f.code()).collect();
+        assert_eq!(
+            codes,
+            vec![Code::DuplicateId, Code::BrokenFragment, Code::BrokenLink]
+        );
+
+        let package = PACKAGE
+            .replace(r#"<dc:language>en</dc:language>"#, "")
+            .replace(r#"fallback="c1""#, r#"fallback="pdf""#)
+            .replace(
+                r#"<itemref idref="pdf"/>"#,
+                r#"<itemref idref="pdf"/><itemref idref="c9"/>"#,
+            );
+        let mut invalid = book(&package, false);
+        let report = validate(&mut invalid).unwrap();
+        assert!(!report.is_valid());
+        let codes: Vec<Code> = report.errors().map(|f| f.code()).collect();
+        assert_eq!(
+            codes,
+            vec![
+                Code::MissingMetadata,
+                Code::InvalidFallback,
+                Code::MissingResource,
+                Code::InvalidFallback,
+                Code::UnresolvedIdref,
+                Code::DuplicateId,
+                Code::MissingProperty,
+                Code::BrokenFragment,
+                Code::BrokenLink,
+            ]
+        );
+        let finding = &report.findings()[2];
+        assert_eq!(finding.severity(), Severity::Error);
+        assert_eq!(finding.location(), "OEBPS/content.opf");
+        assert!(finding.message().contains("OEBPS/style.css"));
+    }
+
+    #[test]
+    fn test_svg_content() {
+        let package = PACKAGE
+            .replace(
+                "</manifest>",
+                r#"<item id="c3" href="text/c3.svg" media-type="image/svg+xml"/></manifest>"#,
+            )
+            .replace("</spine>", r#"<itemref idref="c3"/></spine>"#);
+        let svg = r#"<svg xmlns="http://www.w3.org/2000/svg"><script>let a = 1;</script>
+<rect id="r"/><rect id="r"/></svg>"#;
+        let mut writer = ZipWriter::new_to_memory().unwrap();
+        writer.write_container("OEBPS/content.opf").unwrap();
+        for (name, content) in [
+            ("OEBPS/content.opf", package.as_str()),
+            ("OEBPS/nav.xhtml", NAV),
+            ("OEBPS/text/c1.xhtml", CHAPTER),
+            ("OEBPS/c2.pdf", "%PDF"),
+            ("OEBPS/style.css", "p {}"),
+            ("OEBPS/text/c3.svg", svg),
+        ] {
+            writer.write_file(name, content.as_bytes()).unwrap();
+        }
+        let mut book = Book::open_from_memory(writer.into_inner().unwrap().into_inner()).unwrap();
+        let report = validate(&mut book).unwrap();
+        let codes: Vec<Code> = report
+            .findings()
+            .iter()
+            .filter(|f| f.location() == "OEBPS/text/c3.svg")
+            .map(|f| f.code())
+            .collect();
+        assert_eq!(codes, vec![Code::DuplicateId, Code::MissingProperty]);
+        assert!(report
+            .findings()
+            .iter()
+            .any(|f| f.message().contains("\"scripted\"") && f.location() == "OEBPS/text/c3.svg"));
+    }
+}
@@ -1,7 +1,286 @@
-pub struct ZipWriter {}
-pub struct DirWriter {}
+use std::fs::{self, File};
+use std::io::{Cursor, Seek, Write};
+use std::path::{Path, PathBuf, MAIN_SEPARATOR};
+
+use zip::write::FileOptions;
+use zip::CompressionMethod;
+
+use crate::container::{Container, CONTAINER_PATH};
+use crate::error::{EpubError, Result};
+use crate::href;
+
+pub const MIMETYPE_PATH: &str = "mimetype";
+pub const MIMETYPE: &str = "application/epub+zip";
+
+pub trait EpubWriter {
+    // `mimetype` is written by the writer itself, writing it again is a no-op
+    fn write_file(&mut self, path: &str, content: &[u8]) -> Result<()>;
+    fn finish(&mut self) -> Result<()>;
+
+    fn write_container(&mut self, package_path: &str) -> Result<()> {
+        let container = Container::new_with_package(package_path);
+        self.write_file(CONTAINER_PATH, container.to_xml().as_bytes())
+    }
+}
+
+pub struct ZipWriter<W: Write + Seek = File> {
+    inner: zip::ZipWriter<W>,
+    finished: Option<W>,
+}
+
+impl ZipWriter<File> {
+    pub fn new_from_path(path: PathBuf) -> Result<Self> {
+        let file = File::create(path.clone())
+            .map_err(|e| EpubError::WriterError(format!("create {:?} error:{:?}", path, e)))?;
+        ZipWriter::new(file)
+    }
+}
+
+impl ZipWriter<Cursor<Vec<u8>>> {
+    pub fn new_to_memory() -> Result<Self> {
+        ZipWriter::new(Cursor::new(Vec::new()))
+    }
+}
+
+impl<W: Write + Seek> ZipWriter<W> {
+    pub fn new(inner: W) -> Result<Self> {
+        let mut inner = zip::ZipWriter::new(inner);
+        // OCF requires mimetype to be the first entry, stored without compression
+        let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+        inner.start_file(MIMETYPE_PATH, options)?;
+        inner
+            .write_all(MIMETYPE.as_bytes())
+            .map_err(|e| EpubError::WriterError(format!("write mimetype error:{:?}", e)))?;
+        Ok(ZipWriter {
+            inner,
+            finished: None,
+        })
+    }
+
+    pub fn into_inner(mut self) -> Result<W> {
+        match self.finished.take() {
+            Some(w) => Ok(w),
+            None => Ok(self.inner.finish()?),
+        }
+    }
+}
+
+impl<W: Write + Seek> EpubWriter for ZipWriter<W> {
+    fn write_file(&mut self, path: &str, content: &[u8]) -> Result<()> {
+        if path == MIMETYPE_PATH {
+            return Ok(());
+        }
+        contained(path)?;
+        let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+        self.inner.start_file(path, options)?;
+        self.inner
+            .write_all(content)
+            .map_err(|e| EpubError::WriterError(format!("write {:?} error:{:?}", path, e)))
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        if self.finished.is_none() {
+            self.finished = Some(self.inner.finish()?);
+        }
+        Ok(())
+    }
+}
+
+pub struct DirWriter {
+    path: PathBuf,
+}
+
+impl DirWriter {
+    pub fn try_new(path: PathBuf) -> Result<Self> {
+        fs::create_dir_all(path.as_path())
+            .map_err(|e| EpubError::WriterError(format!("create dir {:?} error:{:?}", path, e)))?;
+        let mut writer = DirWriter { path };
+        writer.writefile(MIMETYPE_PATH, MIMETYPE.as_bytes())?;
+        Ok(writer)
+    }
+
+    fn writefile(&mut self, name: &str, content: &[u8]) -> Result<()> {
+        contained(name)?;
+        let fp = self.path.join(name);
+        if let Some(parent) = fp.parent() {
+            fs::create_dir_all(parent).map_err(|e| {
+                EpubError::WriterError(format!("create dir {:?} error:{:?}", parent, e))
+            })?;
+        }
+        fs::write(fp.as_path(), content)
+            .map_err(|e| EpubError::WriterError(format!("write file {:?} error:{:?}", fp, e)))
+    }
+}
+
+impl EpubWriter for DirWriter {
+    fn write_file(&mut self, path: &str, content: &[u8]) -> Result<()> {
+        if path == MIMETYPE_PATH {
+            return Ok(());
+        }
+        self.writefile(path, content)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+fn contained(path: &str) -> Result<()> {
+    if href::is_contained(path) {
+        Ok(())
+    } else {
+        Err(EpubError::WriterError(format!(
+            "{:?} is outside of the container",
+            path
+        )))
+    }
+}
 
 pub enum Writer {
     Zip(ZipWriter),
     Dir(DirWriter),
 }
+
+impl Writer {
+    // a directory is written for an existing directory or a path ending with a separator,
+    // a zip file otherwise whatever its extension
+    pub fn new_from_path(path: PathBuf) -> Result<Self> {
+        if Writer::is_dir_path(path.as_path()) {
+            Ok(Writer::Dir(DirWriter::try_new(path)?))
+        } else {
+            Ok(Writer::Zip(ZipWriter::new_from_path(path)?))
+        }
+    }
+
+    fn is_dir_path(path: &Path) -> bool {
+        let name = path.to_string_lossy();
+        path.is_dir() || name.ends_with('/') || name.ends_with(MAIN_SEPARATOR)
+    }
+}
+
+impl EpubWriter for Writer {
+    fn write_file(&mut self, path: &str, content: &[u8]) -> Result<()> {
+        match self {
+            Writer::Zip(w) => w.write_file(path, content),
+            Writer::Dir(w) => w.write_file(path, content),
+        }
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        match self {
+            Writer::Zip(w) => w.finish(),
+            Writer::Dir(w) => w.finish(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read};
+
+    use zip::{CompressionMethod, ZipArchive};
+
+    use crate::book::Book;
+    use crate::writer::{DirWriter, EpubWriter, Writer, ZipWriter};
+
+    const PACKAGE: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="3.0" unique-identifier="uid">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+    <dc:identifier id="uid">urn:uuid:writer-test</dc:identifier>
+    <dc:title>Writer Test</dc:title>
+    <dc:language>en</dc:language>
+  </metadata>
+  <manifest>
+    <item id="nav" href="nav.xhtml" media-type="application/xhtml+xml" properties="nav"/>
+    <item id="c1" href="c1.xhtml" media-type="application/xhtml+xml"/>
+  </manifest>
+  <spine>
+    <itemref idref="c1"/>
+  </spine>
+</package>"#;
+
+    const NAV: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops">
+<head><title>Writer Test</title></head>
+<body><nav epub:type="toc"><ol><li><a href="c1.xhtml">One</a></li></ol></nav></body>
+</html>"#;
+
+    const CHAPTER: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<html xmlns="http://www.w3.org/1999/xhtml"><head><title>One</title></head><body><p>one</p></body></html>"#;
+
+    fn write_book(writer: &mut dyn EpubWriter) {
+        writer.write_container("OEBPS/content.opf").unwrap();
+        writer
+            .write_file("OEBPS/content.opf", PACKAGE.as_bytes())
+            .unwrap();
+        writer
+            .write_file("OEBPS/nav.xhtml", NAV.as_bytes())
+            .unwrap();
+        writer
+            .write_file("OEBPS/c1.xhtml", CHAPTER.as_bytes())
+            .unwrap();
+        writer.finish().unwrap();
+    }
+
+    #[test]
+    fn test_zip_writer() {
+        let mut writer = ZipWriter::new_to_memory().unwrap();
+        write_book(&mut writer);
+        let buffer = writer.into_inner().unwrap().into_inner();
+
+        let mut archive = ZipArchive::new(Cursor::new(buffer.clone())).unwrap();
+        let mut mimetype = archive.by_index(0).unwrap();
+        assert_eq!(mimetype.name(), "mimetype");
+        assert_eq!(mimetype.compression(), CompressionMethod::Stored);
+        let mut content = String::new();
+        mimetype.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "application/epub+zip");
+
+        let mut book = Book::open_from_memory(buffer).unwrap();
+        assert_eq!(book.title(), "Writer Test");
+        assert_eq!(book.nav().unwrap().toc().len(), 1);
+        let path = book.resolve_path("c1.xhtml").unwrap();
+        assert_eq!(book.content(path.as_str()).unwrap(), CHAPTER);
+
+        let mut writer = ZipWriter::new_to_memory().unwrap();
+        assert!(writer.write_file("../escape.xhtml", b"").is_err());
+        assert!(writer.write_file("/escape.xhtml", b"").is_err());
+    }
+
+    #[test]
+    fn test_dir_writer() {
+        let dir = std::env::temp_dir().join(format!("epub-dir-writer-{}", std::process::id()));
+        let mut writer = DirWriter::try_new(dir.clone()).unwrap();
+        write_book(&mut writer);
+        assert!(writer.write_file("../escape.xhtml", b"").is_err());
+
+        let book = Book::open_from_file(dir.as_path()).unwrap();
+        assert_eq!(book.title(), "Writer Test");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_writer_kind() {
+        let dir = std::env::temp_dir().join(format!("epub-writer-kind-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("existing")).unwrap();
+        let cases = [
+            ("book.epub", false),
+            ("book.EPUB", false),
+            ("book", false),
+            ("book.kepub", false),
+            ("out.zip", false),
+            ("unpacked/", true),
+            ("unpacked.epub/", true),
+            ("existing", true),
+        ];
+        for (name, is_dir) in cases {
+            let path = format!("{}/{}", dir.display(), name);
+            let mut writer = Writer::new_from_path(path.clone().into()).unwrap();
+            write_book(&mut writer);
+            assert_eq!(std::path::Path::new(&path).is_dir(), is_dir, "{}", name);
+            let book = Book::open_from_file(path.trim_end_matches('/')).unwrap();
+            assert_eq!(book.title(), "Writer Test");
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,38 +1,69 @@
+use std::collections::{BTreeMap, BTreeSet};
 use std::default::Default;
 use std::rc::Rc;
 
-use html5ever::serialize;
-use markup5ever_rcdom::{Handle, Node, NodeData, RcDom, SerializableHandle};
-use xml5ever::driver::parse_document;
-use xml5ever::tendril::TendrilSink;
+use markup5ever_rcdom::{Handle, Node, NodeData, RcDom};
+use xml5ever::buffer_queue::BufferQueue;
+use xml5ever::tendril::StrTendril;
+use xml5ever::tokenizer::states::XmlState;
+use xml5ever::tokenizer::{TagKind, Token, TokenSink, XmlTokenizer};
+use xml5ever::tree_builder::XmlTreeBuilder;
+use xml5ever::{Attribute, LocalName, Namespace, Prefix, QualName};
 
+use crate::encoding;
 use crate::error::{EpubError, Result};
+use crate::selector::Selector;
+
+pub const XHTML_NAMESPACE: &str = "http://www.w3.org/1999/xhtml";
+pub const EPUB_NAMESPACE: &str = "http://www.idpf.org/2007/ops";
+pub const SVG_NAMESPACE: &str = "http://www.w3.org/2000/svg";
+pub const MATHML_NAMESPACE: &str = "http://www.w3.org/1998/Math/MathML";
+pub const NCX_NAMESPACE: &str = "http://www.daisy.org/z3986/2005/ncx/";
+const XML_NAMESPACE: &str = "http://www.w3.org/XML/1998/namespace";
+const XMLNS_NAMESPACE: &str = "http://www.w3.org/2000/xmlns/";
+
+const XML_DECLARATION: &str = r#"<?xml version="1.0" encoding="UTF-8"?>"#;
+// XHTML elements written as `<br/>`, others get an end tag for HTML parsers
+const VOID_ELEMENTS: [&str; 14] = [
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "param", "source",
+    "track", "wbr",
+];
+// XHTML elements whose whitespace is kept when pretty printing
+const PREFORMATTED_ELEMENTS: [&str; 4] = ["pre", "script", "style", "textarea"];
 
 pub struct XMLDocument {
     dom: RcDom,
 }
 
-
 impl XMLDocument {
+    // `content` is transcoded from the encoding its BOM or declaration names
     pub fn try_new(content: &[u8]) -> Result<Self> {
-        let mut reader = std::io::BufReader::new(content);
-        let dom: RcDom = parse_document(RcDom::default(), Default::default())
-            .from_utf8()
-            .read_from(&mut reader)
-            .map_err(|e| EpubError::XmlError(format!("create xml failed {:?}", e)))?;
-        Ok(XMLDocument { dom })
+        XMLDocument::try_new_from_entry(content, "xml document")
     }
 
-    fn find_first_tag(prefix: &str, node: &Handle) -> Option<Rc<Node>> {
-        match node.data {
-            NodeData::Element { ref name, .. } => {
-                if &*name.local == prefix {
-                    return Some(node.clone());
-                }
-            }
-            _ => {
-                // donothing
-            }
+    // like `try_new`, `href` of the container entry names it in errors
+    pub fn try_new_from_entry(content: &[u8], href: &str) -> Result<Self> {
+        let text = encoding::decode(content, href)?;
+        Ok(XMLDocument::new_from_str(&text))
+    }
+
+    // `text` is already decoded, the encoding of its declaration is ignored.
+    // the `xmlns` declarations stay on their elements as attributes in the xmlns namespace
+    pub fn new_from_str(text: &str) -> Self {
+        let builder = XmlTreeBuilder::new(RcDom::default(), Default::default());
+        let mut tokenizer = XmlTokenizer::new(KeepDeclarations(builder), Default::default());
+        let mut input = BufferQueue::default();
+        input.push_back(StrTendril::from_slice(text));
+        tokenizer.feed(&mut input);
+        tokenizer.end();
+        let dom = tokenizer.sink.0.sink;
+        restore_declarations(&dom.document);
+        XMLDocument { dom }
+    }
+
+    fn find_first_tag(matches: &dyn Fn(&Handle) -> bool, node: &Handle) -> Option<Rc<Node>> {
+        if matches(node) {
+            return Some(node.clone());
         }
 
         for child in node
@@ -41,24 +72,17 @@ impl XMLDocument {
             .iter()
             .filter(|child| matches!(child.data, NodeData::Element { .. }))
         {
-            if let Some(v) = XMLDocument::find_first_tag(prefix, child) {
+            if let Some(v) = XMLDocument::find_first_tag(matches, child) {
                 return Some(v);
             }
         }
         None
     }
 
-    fn find_tags(prefix: &str, node: &Handle) -> Vec<Rc<Node>> {
+    fn find_tags(matches: &dyn Fn(&Handle) -> bool, node: &Handle) -> Vec<Rc<Node>> {
         let mut res = Vec::new();
-        match node.data {
-            NodeData::Element { ref name, .. } => {
-                if &*name.local == prefix {
-                    res.push(node.clone());
-                }
-            }
-            _ => {
-                // donothing
-            }
+        if matches(node) {
+            res.push(node.clone());
         }
         for child in node
             .children
@@ -66,29 +90,494 @@ impl XMLDocument {
             .iter()
             .filter(|child| matches!(child.data, NodeData::Element { .. }))
         {
-            let items = XMLDocument::find_tags(prefix, child);
+            let items = XMLDocument::find_tags(matches, child);
             res.extend(items);
         }
         res
     }
 
+    // `tag` is a local name in any namespace, `dc:title` and an XHTML `title` both match "title"
     pub fn find_tag(&self, tag: &str) -> Option<Rc<Node>> {
-        XMLDocument::find_first_tag(tag, &self.dom.document)
+        XMLDocument::find_first_tag(&|node| is_tag(node, None, tag), &self.dom.document)
     }
 
     pub fn find_all_tag(&self, tag: &str) -> Vec<Rc<Node>> {
-        XMLDocument::find_tags(tag, &self.dom.document)
+        XMLDocument::find_tags(&|node| is_tag(node, None, tag), &self.dom.document)
+    }
+
+    // `ns` is a namespace uri, empty for elements without a namespace
+    pub fn find_tag_ns(&self, ns: &str, tag: &str) -> Option<Rc<Node>> {
+        XMLDocument::find_first_tag(&|node| is_tag(node, Some(ns), tag), &self.dom.document)
+    }
+
+    pub fn find_all_tag_ns(&self, ns: &str, tag: &str) -> Vec<Rc<Node>> {
+        XMLDocument::find_tags(&|node| is_tag(node, Some(ns), tag), &self.dom.document)
+    }
+
+    // elements matching a CSS selector in document order, see `selector::Selector`
+    pub fn select(&self, selector: &str) -> Result<Vec<Rc<Node>>> {
+        Ok(Selector::parse(selector)?.select(&self.dom.document))
+    }
+
+    pub fn select_first(&self, selector: &str) -> Result<Option<Rc<Node>>> {
+        Ok(Selector::parse(selector)?.select_first(&self.dom.document))
+    }
+
+    // the document node, parent of the prolog and the document element
+    pub fn document(&self) -> &Handle {
+        &self.dom.document
+    }
+
+    // the document element
+    pub fn root(&self) -> Option<Rc<Node>> {
+        self.dom
+            .document
+            .children
+            .borrow()
+            .iter()
+            .find(|child| matches!(child.data, NodeData::Element { .. }))
+            .cloned()
+    }
+
+    pub fn find_by_id(&self, id: &str) -> Option<Rc<Node>> {
+        fn find(node: &Handle, id: &str) -> Option<Rc<Node>> {
+            if parse_attribute(node, "id").as_deref() == Some(id) {
+                return Some(node.clone());
+            }
+            node.children
+                .borrow()
+                .iter()
+                .filter(|child| matches!(child.data, NodeData::Element { .. }))
+                .find_map(|child| find(child, id))
+        }
+        find(&self.dom.document, id)
+    }
+    // element ids in document order
+    pub fn ids(&self) -> Vec<String> {
+        fn collect(node: &Handle, ids: &mut Vec<String>) {
+            for child in node.children.borrow().iter() {
+                if let Some(id) = parse_attribute(child, "id") {
+                    ids.push(id);
+                }
+                collect(child, ids);
+            }
+        }
+        let mut ids = Vec::new();
+        collect(&self.dom.document, &mut ids);
+        ids
     }
+
+    // well-formed XML with a UTF-8 declaration, the namespace declarations of the source
+    // are kept and missing ones added
     pub fn to_string(&self) -> Result<String> {
-        let document: SerializableHandle = self.dom.document.clone().into();
-        let mut buffer = Vec::new();
-        serialize(&mut buffer, &document, Default::default())
-            .map_err(|e| EpubError::XmlError(format!("faild seraize to xml {:?}", e)))?;
-        String::from_utf8(buffer)
-            .map_err(|e| EpubError::XmlError(format!("faild converto to string:{:?}", e)))
+        Ok(self.serialize(false))
+    }
+
+    // like `to_string`, elements without text content are indented by two spaces
+    pub fn to_string_pretty(&self) -> Result<String> {
+        Ok(self.serialize(true))
+    }
+
+    fn serialize(&self, pretty: bool) -> String {
+        let mut serializer = Serializer::default();
+        serializer.write_node(&self.dom.document, 0, pretty);
+        serializer.out
     }
 }
 
+// the tree builder binds and drops `xmlns` attributes, a copy of each is added that it keeps:
+// unprefixed, in the xmlns namespace, with the declared prefix as local name
+struct KeepDeclarations<S>(S);
+
+impl<S: TokenSink> TokenSink for KeepDeclarations<S> {
+    fn process_token(&mut self, token: Token) {
+        let token = match token {
+            Token::TagToken(mut tag)
+                if matches!(tag.kind, TagKind::StartTag | TagKind::EmptyTag) =>
+            {
+                let copies: Vec<Attribute> = tag
+                    .attrs
+                    .iter()
+                    .filter_map(|attr| {
+                        let prefix = match attr.name.prefix {
+                            Some(ref prefix) if &**prefix == "xmlns" && !attr.value.is_empty() => {
+                                attr.name.local.clone()
+                            }
+                            None if &*attr.name.local == "xmlns" => LocalName::from(""),
+                            _ => return None,
+                        };
+                        Some(Attribute {
+                            name: QualName::new(None, Namespace::from(XMLNS_NAMESPACE), prefix),
+                            value: attr.value.clone(),
+                        })
+                    })
+                    .collect();
+                tag.attrs.extend(copies);
+                Token::TagToken(tag)
+            }
+            token => token,
+        };
+        self.0.process_token(token);
+    }
+
+    fn end(&mut self) {
+        self.0.end();
+    }
+
+    fn query_state_change(&mut self) -> Option<XmlState> {
+        self.0.query_state_change()
+    }
+}
+
+// the copies of `KeepDeclarations` get their source names back, `xmlns:prefix` and `xmlns`
+fn restore_declarations(node: &Handle) {
+    if let NodeData::Element { ref attrs, .. } = node.data {
+        for attr in attrs.borrow_mut().iter_mut() {
+            if &*attr.name.ns == XMLNS_NAMESPACE && attr.name.prefix.is_none() {
+                attr.name = match attr.name.local.is_empty() {
+                    true => QualName::new(
+                        None,
+                        Namespace::from(XMLNS_NAMESPACE),
+                        LocalName::from("xmlns"),
+                    ),
+                    false => QualName::new(
+                        Some(Prefix::from("xmlns")),
+                        Namespace::from(XMLNS_NAMESPACE),
+                        attr.name.local.clone(),
+                    ),
+                };
+            }
+        }
+    }
+    for child in node.children.borrow().iter() {
+        restore_declarations(child);
+    }
+}
+
+// the prefix and namespace an `xmlns` attribute declares, prefix "" is the default namespace
+fn declaration(attr: &Attribute) -> Option<(String, String)> {
+    match attr.name.prefix {
+        Some(ref prefix) if &**prefix == "xmlns" => {
+            Some((attr.name.local.to_string(), attr.value.to_string()))
+        }
+        None if &*attr.name.local == "xmlns" => Some((String::new(), attr.value.to_string())),
+        _ => None,
+    }
+}
+
+// `node` and its descendants as XML, a document gets an XML declaration.
+// the bindings of ancestors are added where needed, on `node` where possible
+pub fn serialize(node: &Handle, pretty: bool) -> String {
+    let mut serializer = Serializer {
+        hoist: true,
+        ..Default::default()
+    };
+    serializer.write_node(node, 0, pretty);
+    serializer.out
+}
+
+#[derive(Default)]
+struct Serializer {
+    out: String,
+    // in scope namespace bindings, innermost last, the prefix "" is the default namespace
+    scopes: Vec<(String, String)>,
+    generated: usize,
+    // prefixes bound to one namespace in the whole tree are declared once, on the root
+    hoist: bool,
+}
+
+impl Serializer {
+    fn lookup(&self, prefix: &str) -> Option<&str> {
+        self.scopes
+            .iter()
+            .rev()
+            .find(|(p, _)| p == prefix)
+            .map(|(_, ns)| ns.as_str())
+    }
+
+    fn bind(&mut self, prefix: &str, ns: &str, declarations: &mut Vec<(String, String)>) {
+        if self.lookup(prefix).unwrap_or_default() != ns {
+            self.scopes.push((prefix.to_string(), ns.to_string()));
+            declarations.push((prefix.to_string(), ns.to_string()));
+        }
+    }
+
+    // the name to write, bindings missing from the tree are added where needed
+    fn qualify(
+        &mut self,
+        prefix: Option<&str>,
+        ns: &str,
+        local: &str,
+        element: bool,
+        declarations: &mut Vec<(String, String)>,
+    ) -> String {
+        if ns == XML_NAMESPACE {
+            return format!("xml:{}", local);
+        }
+        // an undeclared prefix has no namespace
+        let prefix = prefix.filter(|_| !ns.is_empty());
+        match prefix {
+            None if element => {
+                self.bind("", ns, declarations);
+                local.to_string()
+            }
+            None if ns.is_empty() => local.to_string(),
+            None => {
+                let bound = self
+                    .scopes
+                    .iter()
+                    .rev()
+                    .find(|(p, n)| !p.is_empty() && n == ns)
+                    .map(|(p, _)| p.clone());
+                let prefix = match bound {
+                    Some(prefix) if self.lookup(&prefix) == Some(ns) => prefix,
+                    _ => {
+                        self.generated += 1;
+                        let prefix = format!("ns{}", self.generated);
+                        self.bind(&prefix, ns, declarations);
+                        prefix
+                    }
+                };
+                format!("{}:{}", prefix, local)
+            }
+            Some(prefix) => {
+                self.bind(prefix, ns, declarations);
+                format!("{}:{}", prefix, local)
+            }
+        }
+    }
+
+    fn write_node(&mut self, node: &Handle, depth: usize, pretty: bool) {
+        match node.data {
+            NodeData::Document => {
+                self.out.push_str(XML_DECLARATION);
+                self.out.push('\n');
+                for child in node.children.borrow().iter() {
+                    match child.data {
+                        // replaced by our own declaration, the content is UTF-8 now
+                        NodeData::ProcessingInstruction { ref target, .. }
+                            if target.eq_ignore_ascii_case("xml") => {}
+                        NodeData::Text { ref contents } if contents.borrow().trim().is_empty() => {}
+                        _ => {
+                            self.write_node(child, depth, pretty);
+                            self.out.push('\n');
+                        }
+                    }
+                }
+            }
+            NodeData::Doctype {
+                ref name,
+                ref public_id,
+                ref system_id,
+            } => {
+                self.out.push_str(&format!("<!DOCTYPE {}", name));
+                if !public_id.is_empty() {
+                    self.out
+                        .push_str(&format!(" PUBLIC \"{}\" \"{}\"", public_id, system_id));
+                } else if !system_id.is_empty() {
+                    self.out.push_str(&format!(" SYSTEM \"{}\"", system_id));
+                }
+                self.out.push('>');
+            }
+            NodeData::Text { ref contents } => {
+                for c in contents.borrow().chars() {
+                    match c {
+                        '&' => self.out.push_str("&amp;"),
+                        '<' => self.out.push_str("&lt;"),
+                        '>' => self.out.push_str("&gt;"),
+                        _ => self.out.push(c),
+                    }
+                }
+            }
+            NodeData::Comment { ref contents } => {
+                // `--` may not appear in a comment, nor may it end with `-`
+                self.out.push_str("<!--");
+                let mut previous = None;
+                for c in contents.chars() {
+                    if c == '-' && previous == Some('-') {
+                        self.out.push(' ');
+                    }
+                    self.out.push(c);
+                    previous = Some(c);
+                }
+                if previous == Some('-') {
+                    self.out.push(' ');
+                }
+                self.out.push_str("-->");
+            }
+            NodeData::ProcessingInstruction {
+                ref target,
+                ref contents,
+            } => match contents.is_empty() {
+                true => self.out.push_str(&format!("<?{}?>", target)),
+                false => self.out.push_str(&format!("<?{} {}?>", target, contents)),
+            },
+            NodeData::Element { .. } => self.write_element(node, depth, pretty),
+        }
+    }
+
+    fn write_element(&mut self, node: &Handle, depth: usize, pretty: bool) {
+        let NodeData::Element {
+            ref name,
+            ref attrs,
+            ..
+        } = node.data
+        else {
+            return;
+        };
+        let mark = self.scopes.len();
+        let mut declarations = Vec::new();
+        // the declarations of the source are kept, they may bind prefixes used in values only
+        let original: Vec<(String, String)> =
+            attrs.borrow().iter().filter_map(declaration).collect();
+        for (prefix, ns) in original {
+            self.bind(&prefix, &ns, &mut declarations);
+        }
+        if mark == 0 && self.hoist {
+            for (prefix, ns) in prefixes(node) {
+                self.bind(&prefix, &ns, &mut declarations);
+            }
+        }
+        let tag = self.qualify(
+            name.prefix.as_deref(),
+            &name.ns,
+            &name.local,
+            true,
+            &mut declarations,
+        );
+        let mut attributes = Vec::new();
+        for attr in attrs.borrow().iter() {
+            if declaration(attr).is_some() {
+                continue;
+            }
+            let name = self.qualify(
+                attr.name.prefix.as_deref(),
+                &attr.name.ns,
+                &attr.name.local,
+                false,
+                &mut declarations,
+            );
+            attributes.push((name, attr.value.to_string()));
+        }
+
+        self.out.push('<');
+        self.out.push_str(&tag);
+        // the default namespace first
+        declarations.sort_by_key(|(prefix, _)| !prefix.is_empty());
+        for (prefix, ns) in declarations {
+            let name = match prefix.is_empty() {
+                true => "xmlns".to_string(),
+                false => format!("xmlns:{}", prefix),
+            };
+            self.write_attribute(&name, &ns);
+        }
+        for (name, value) in attributes {
+            self.write_attribute(&name, &value);
+        }
+
+        let xhtml = &*name.ns == XHTML_NAMESPACE;
+        let indent = pretty && indentable(node, xhtml);
+        let children: Vec<Rc<Node>> = node
+            .children
+            .borrow()
+            .iter()
+            .filter(|child| !indent || !is_whitespace(child))
+            .cloned()
+            .collect();
+        if children.is_empty() {
+            match xhtml && !VOID_ELEMENTS.contains(&&*name.local) {
+                true => self.out.push_str(&format!("></{}>", tag)),
+                false => self.out.push_str("/>"),
+            }
+        } else {
+            self.out.push('>');
+            for child in children.iter() {
+                if indent {
+                    self.out.push('\n');
+                    self.out.push_str(&"  ".repeat(depth + 1));
+                }
+                self.write_node(child, depth + 1, indent);
+            }
+            if indent {
+                self.out.push('\n');
+                self.out.push_str(&"  ".repeat(depth));
+            }
+            self.out.push_str(&format!("</{}>", tag));
+        }
+        self.scopes.truncate(mark);
+    }
+
+    fn write_attribute(&mut self, name: &str, value: &str) {
+        self.out.push_str(&format!(" {}=\"", name));
+        for c in value.chars() {
+            match c {
+                '&' => self.out.push_str("&amp;"),
+                '<' => self.out.push_str("&lt;"),
+                '"' => self.out.push_str("&quot;"),
+                '\n' => self.out.push_str("&#10;"),
+                '\r' => self.out.push_str("&#13;"),
+                '\t' => self.out.push_str("&#9;"),
+                _ => self.out.push(c),
+            }
+        }
+        self.out.push('"');
+    }
+}
+
+fn is_whitespace(node: &Handle) -> bool {
+    match node.data {
+        NodeData::Text { ref contents } => contents.borrow().trim().is_empty(),
+        _ => false,
+    }
+}
+
+// mixed content and preformatted elements are written as they are
+fn indentable(node: &Handle, xhtml: bool) -> bool {
+    if let NodeData::Element { ref name, .. } = node.data {
+        if xhtml && PREFORMATTED_ELEMENTS.contains(&&*name.local) {
+            return false;
+        }
+    }
+    parse_attribute_ns(node, XML_NAMESPACE, "space").as_deref() != Some("preserve")
+        && node.children.borrow().iter().all(|child| match child.data {
+            NodeData::Text { .. } => is_whitespace(child),
+            _ => true,
+        })
+}
+
+// prefixes of element and attribute names below `node` that are bound to a single namespace
+fn prefixes(node: &Handle) -> Vec<(String, String)> {
+    fn collect(node: &Handle, res: &mut BTreeMap<String, BTreeSet<String>>) {
+        if let NodeData::Element {
+            ref name,
+            ref attrs,
+            ..
+        } = node.data
+        {
+            let mut names = vec![name.clone()];
+            names.extend(attrs.borrow().iter().map(|attr| attr.name.clone()));
+            for name in names {
+                if let Some(prefix) = name.prefix {
+                    if !name.ns.is_empty() && &*name.ns != XML_NAMESPACE && &*prefix != "xmlns" {
+                        res.entry(prefix.to_string())
+                            .or_default()
+                            .insert(name.ns.to_string());
+                    }
+                }
+            }
+        }
+        for child in node.children.borrow().iter() {
+            collect(child, res);
+        }
+    }
+    let mut res = BTreeMap::new();
+    collect(node, &mut res);
+    res.into_iter()
+        .filter(|(_, namespaces)| namespaces.len() == 1)
+        .filter_map(|(prefix, namespaces)| namespaces.into_iter().next().map(|ns| (prefix, ns)))
+        .collect()
+}
+
 pub fn parse_attribute(node: &Rc<Node>, name: &str) -> Option<String> {
     match node.data {
         NodeData::Element { ref attrs, .. } => {
@@ -103,6 +592,26 @@ pub fn parse_attribute(node: &Rc<Node>, name: &str) -> Option<String> {
     }
 }
 
+pub fn is_tag(node: &Rc<Node>, ns: Option<&str>, tag: &str) -> bool {
+    match node.data {
+        NodeData::Element { ref name, .. } => {
+            &*name.local == tag && ns.is_none_or(|ns| &*name.ns == ns)
+        }
+        _ => false,
+    }
+}
+
+pub fn parse_attribute_ns(node: &Rc<Node>, ns: &str, name: &str) -> Option<String> {
+    match node.data {
+        NodeData::Element { ref attrs, .. } => attrs
+            .borrow()
+            .iter()
+            .find(|attr| &*attr.name.local == name && &*attr.name.ns == ns)
+            .map(|attr| attr.value.to_string()),
+        _ => None,
+    }
+}
+
 pub fn parse_attribute_must_exist(node: &Rc<Node>, name: &str) -> Result<String> {
     match node.data {
         NodeData::Element { ref attrs, .. } => {
@@ -117,6 +626,21 @@ pub fn parse_attribute_must_exist(node: &Rc<Node>, name: &str) -> Result<String>
     }
 }
 
+pub fn escape(text: &str) -> String {
+    let mut res = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => res.push_str("&amp;"),
+            '<' => res.push_str("&lt;"),
+            '>' => res.push_str("&gt;"),
+            '"' => res.push_str("&quot;"),
+            '\'' => res.push_str("&apos;"),
+            _ => res.push(c),
+        }
+    }
+    res
+}
+
 pub fn parse_text(node: &Rc<Node>) -> String {
     let mut res = String::new();
     for child in node
@@ -132,6 +656,37 @@ pub fn parse_text(node: &Rc<Node>) -> String {
     res
 }
 
+// text of all descendants in document order
+pub fn parse_all_text(node: &Rc<Node>) -> String {
+    let mut res = String::new();
+    for child in node.children.borrow().iter() {
+        match child.data {
+            NodeData::Text { ref contents } => res.push_str(contents.borrow().to_string().as_str()),
+            NodeData::Element { .. } => res.push_str(parse_all_text(child).as_str()),
+            _ => {}
+        }
+    }
+    res
+}
+
+pub fn parent(node: &Rc<Node>) -> Option<Rc<Node>> {
+    let weak = node.parent.take();
+    node.parent.set(weak.clone());
+    weak.and_then(|parent| parent.upgrade())
+}
+
+// first element below `node` with the local name `tag`
+pub fn find_descendant(node: &Rc<Node>, tag: &str) -> Option<Rc<Node>> {
+    node.children
+        .borrow()
+        .iter()
+        .filter(|child| matches!(child.data, NodeData::Element { .. }))
+        .find_map(|child| match child.data {
+            NodeData::Element { ref name, .. } if &*name.local == tag => Some(child.clone()),
+            _ => find_descendant(child, tag),
+        })
+}
+
 pub fn find_children(node: &Rc<Node>, tag: &str) -> Vec<Rc<Node>> {
     let mut res = Vec::new();
     for child in node
@@ -158,3 +713,63 @@ pub fn first_child(node: &Rc<Node>, tag: &str) -> Option<Rc<Node>> {
         })
         .cloned()
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::xml::{serialize, XMLDocument};
+
+    const DOCUMENT: &str = r#"<?xml version="1.0" encoding="windows-1252"?>
+<!DOCTYPE html>
+<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops" xml:lang="en">
+<head><title>A &amp; B</title><?pi data?><?empty?><!-- a -- b---></head>
+<body xmlns:z3998="http://www.daisy.org/z3998/2012/vocab/structure/#" epub:type="z3998:fiction">
+  <p epub:type="note">x<br/><![CDATA[a<b]]></p>
+  <svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink"><image xlink:href="a.png"/></svg>
+  <div></div>
+</body>
+</html>"#;
+
+    #[test]
+    fn test_serialize() {
+        let doc = XMLDocument::try_new(DOCUMENT.as_bytes()).unwrap();
+        let xml = doc.to_string().unwrap();
+        assert!(xml.starts_with("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<!DOCTYPE html>\n"));
+        assert!(xml.contains(r#"<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops" xml:lang="en">"#));
+        assert!(xml.contains("<title>A &amp; B</title><?pi data?><?empty?><!-- a - - b- -->"));
+        // a prefix only used in an attribute value keeps its declaration
+        assert!(xml.contains(r#"<body xmlns:z3998="http://www.daisy.org/z3998/2012/vocab/structure/#" epub:type="z3998:fiction">"#));
+        assert!(xml.contains(r#"<p epub:type="note">x<br/>a&lt;b</p>"#));
+        assert!(xml.contains(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink"><image xlink:href="a.png"/></svg>"#
+        ));
+        assert!(xml.contains("<div></div>"));
+        // the output parses back to the same document
+        assert_eq!(
+            XMLDocument::try_new(xml.as_bytes())
+                .unwrap()
+                .to_string()
+                .unwrap(),
+            xml
+        );
+
+        let pretty = doc.to_string_pretty().unwrap();
+        assert!(
+            pretty.contains("\n  <head>\n    <title>A &amp; B</title>\n    <?pi data?>\n    <?empty?>\n    <!-- a - - b- -->\n  </head>")
+        );
+        assert!(pretty.contains("\n    <p epub:type=\"note\">x<br/>a&lt;b</p>\n"));
+        assert!(pretty.contains("\n      <image xlink:href=\"a.png\"/>\n    </svg>\n"));
+        assert!(pretty.contains("\n    <div></div>\n  </body>\n</html>\n"));
+
+        // bindings used below the root are added to it where possible
+        let root = serialize(&doc.root().unwrap(), false);
+        assert!(root.starts_with(r#"<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops" xmlns:xlink="http://www.w3.org/1999/xlink" xml:lang="en">"#));
+
+        // only `xml:space` keeps whitespace, not a `space` attribute in another namespace
+        let doc = XMLDocument::new_from_str(
+            r#"<a xmlns:x="urn:x"><b x:space="preserve"> <c/></b><d xml:space="preserve"> <c/></d></a>"#,
+        );
+        assert!(doc.to_string_pretty().unwrap().contains(
+            "<b x:space=\"preserve\">\n    <c/>\n  </b>\n  <d xml:space=\"preserve\"> <c/></d>"
+        ));
+    }
+}
@@ -1,2 +1,3 @@
 pub mod sync_audio;
+pub mod validate;
 
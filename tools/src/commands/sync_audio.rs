@@ -22,6 +22,7 @@ pub struct Config {
 pub fn command(conf: &Config) {
     let mut book = Book::open_from_file(conf.epub_file.as_path()).unwrap();
     for chapter in book.chapters() {
+        let chapter = chapter.unwrap();
         if chapter.index() == 67 {
             println!("chapter:{:?},{:?}", chapter.index(), chapter.href());
             println!("{:?}", chapter.content().to_string());
@@ -0,0 +1,36 @@
+// check an epub file, exit with an error when the book is invalid
+
+use std::path::PathBuf;
+use std::process;
+
+use clap::Parser;
+
+use epub::book::Book;
+use epub::validate;
+
+#[derive(Debug, Parser)]
+pub struct Config {
+    #[arg(short, long, value_name = "epub_file")]
+    epub_file: PathBuf,
+    // fail on warnings too
+    #[arg(short, long)]
+    strict: bool,
+}
+
+pub fn command(conf: &Config) {
+    let report = Book::open_from_file(conf.epub_file.as_path())
+        .and_then(|mut book| validate::validate(&mut book));
+    let report = match report {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("{}: {}", conf.epub_file.display(), e);
+            process::exit(1);
+        }
+    };
+    for finding in report.findings() {
+        println!("{}", finding);
+    }
+    if !report.is_valid() || (conf.strict && report.warnings().next().is_some()) {
+        process::exit(1);
+    }
+}
@@ -12,12 +12,14 @@ struct Cli {
 #[derive(Parser, Debug)]
 enum Commands {
     CreateAudioBook(commands::sync_audio::Config),
+    Validate(commands::validate::Config),
 }
 
 fn main() {
     let cli = Cli::parse();
     match &cli.command {
         Some(Commands::CreateAudioBook(cfg)) => commands::sync_audio::command(cfg),
+        Some(Commands::Validate(cfg)) => commands::validate::command(cfg),
         None => {}
     }
 }